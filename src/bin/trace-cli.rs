@@ -1,23 +1,70 @@
-use std::collections::HashSet;
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::env;
+use std::fs::{
+    read_to_string,
+    OpenOptions,
+    remove_file,
+    write,
+};
+use std::io::{
+    self,
+    IsTerminal,
+    Write,
+};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{
     Path,
     PathBuf,
 };
+use std::process::{
+    self,
+    ExitCode,
+};
+use std::sync::{
+    atomic::{
+        AtomicBool,
+        Ordering,
+    },
+    Arc,
+};
+use std::time::Duration;
 
 use trace::{
+    ClientConfig,
+    ExistingFilePolicy,
+    ExportOptions,
     ExportOutputFormat,
+    LoginError,
+    MediaLinkKind,
+    MediaVariant,
+    ReactionsSummaryFormat,
     RoomWithCachedInfo,
+    RunLog,
+    SenderHasher,
+    Session,
+    SessionVerificationStatus,
     SessionsFile,
+    TokenRefresh,
+    TraceConfig,
+    TraceError,
+    UnmappableCharacterPolicy,
     add_at_to_user_id_if_applicable,
+    default_sync_settings,
     nonfirst_login,
     user_id_to_crypto_store_path,
+    user_id_to_exported_rooms_path,
+    with_timeout,
 };
 
 use argh::FromArgs;
 use directories::ProjectDirs;
+use encoding_rs::Encoding;
 use futures::StreamExt;
 use matrix_sdk::{
-    config::SyncSettings,
     encryption::verification::{
         AcceptSettings,
         SasState,
@@ -26,17 +73,23 @@ use matrix_sdk::{
         VerificationRequestState,
     },
     ruma::{
+        api::client::error::ErrorKind,
         events::key::verification::{
             request::ToDeviceKeyVerificationRequestEvent,
             ShortAuthenticationString,
         },
-        presence::PresenceState,
+        EventId,
+        RoomId,
         UserId,
     },
     Client,
+    ClientBuildError,
+    HttpError,
+    RoomState,
 };
 use rpassword::read_password;
 use serde::Serialize;
+use serde_json::json;
 
 //////////////
 //   Args   //
@@ -45,6 +98,24 @@ use serde::Serialize;
 #[derive(FromArgs)]
 /// Trace Matrix downloader client
 struct Args {
+    #[argh(option, default = "60")]
+    /// seconds to wait on each of login and initial sync before giving up on an unresponsive homeserver; 0 waits forever; defaults to 60
+    timeout: u64,
+    #[argh(option)]
+    /// path of the sessions file to use in place of the default one in trace's data directory, e.g. to keep a separate set of logins per project; created if it doesn't exist
+    sessions_file: Option<PathBuf>,
+    #[argh(switch)]
+    /// encrypt the sessions file at rest under a passphrase, read from the TRACE_SESSIONS_PASSPHRASE environment variable or else prompted for; once encrypted, the file stays encrypted, and the passphrase is asked for whether or not this flag is passed
+    encrypt_sessions_file: bool,
+    #[argh(option)]
+    /// maximum number of requests per second to make when fetching messages and media, across all rooms, to avoid running into the homeserver's rate limits; unlimited if unspecified
+    limit_rate: Option<f64>,
+    #[argh(option)]
+    /// HTTP user agent to identify trace's requests to the homeserver by; defaults to 'trace/<version>'
+    user_agent: Option<String>,
+    #[argh(option)]
+    /// path of a json file mapping server names to homeserver URLs, e.g. '{"example.org": "https://matrix.example.org"}', to use for accounts on those servers in place of .well-known discovery
+    homeserver_map: Option<PathBuf>,
     #[argh(subcommand)]
     subcommand: RootSubcommand,
 }
@@ -52,27 +123,226 @@ struct Args {
 #[derive(FromArgs)]
 #[argh(subcommand)]
 enum RootSubcommand {
+    Doctor(Doctor),
     Export(Export),
     ListRooms(ListRooms),
+    Profile(Profile),
+    RoomInfo(RoomInfo),
+    Serve(Serve),
     Session(SessionCommand),
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "doctor")]
+/// Check the sessions file, each session, and the output directory for problems
+struct Doctor {
+    #[argh(option, short = 'o')]
+    /// output directory to check the writability of; if unspecified, checks the current directory
+    output: Option<PathBuf>,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "export")]
 /// Export logs from rooms
 struct Export {
     #[argh(positional)]
-    /// user_id (of the form @alice:example.com) to export rooms accessible to
-    user_id: String,
-    #[argh(positional)]
-    /// space-separated list of room IDs (of the form !abcdefghijklmnopqr:example.com), aliases (of the form #room:example.com), or display names (e.g. 'Example Room') to export
+    /// user id (of the form @alice:example.com) to export rooms accessible to, which may be omitted if a default account is set, followed by a space-separated list of room IDs (of the form !abcdefghijklmnopqr:example.com), aliases (of the form #room:example.com), or display names (e.g. 'Example Room') to export
     rooms: Vec<String>,
+    #[argh(switch)]
+    /// print the supported export formats, with the names they can be requested by, and exit without exporting anything
+    list_formats: bool,
     #[argh(option, short = 'f')]
-    /// format to export to; valid options are 'json' and 'txt'; flag can be used multiple times to export multiple formats in a single run; if flag is unspecified, default output format is json
+    /// format to export to; valid options are 'html', 'json', 'txt', 'sqlite' (every room into one 'Trace export.db' database in the output directory, updated in place on re-export), and 'all' (html, json, and txt); see --list-formats for details; flag can be used multiple times to export multiple formats in a single run; if flag is unspecified, default output format is json
     formats: Vec<String>,
     #[argh(option, short = 'o')]
-    /// path of directory to output files to; if unspecified, defaults to current directory
+    /// path of directory to output files to; if unspecified, defaults to current directory; if '-', writes a single room's export in a single format to stdout, with informational messages moved to stderr
     output: Option<PathBuf>,
+    #[argh(switch)]
+    /// append the current date to each output filename, so that repeated runs keep dated snapshots rather than overwriting one another
+    datestamp: bool,
+    #[argh(option)]
+    /// strftime-style format for the date appended by --datestamp (implies --datestamp); defaults to '%Y-%m-%d'; characters which aren't filesystem-safe are replaced with '-'
+    datestamp_format: Option<String>,
+    #[argh(switch)]
+    /// only export messages newer than those exported by this option's previous run, writing them to a separate incremental file; rooms with no previous run get a full export; progress is tracked in a sidecar state file in the output directory
+    since_last_export: bool,
+    #[argh(switch)]
+    /// move your read receipt and fully-read marker in each exported room to its latest exported message; by default, exporting leaves your read position untouched
+    mark_read: bool,
+    #[argh(switch)]
+    /// download attachments (images, files, videos, and audio) into a 'media' directory within the output directory
+    download_media: bool,
+    #[argh(switch)]
+    /// with --download-media, embed downloaded images in the html export as base64 data URIs, so that it's a single self-contained file; pair with '--media thumbnail' to keep it small
+    include_thumbnails_inline: bool,
+    #[argh(option)]
+    /// with --include-thumbnails-inline, the size above which images are linked to rather than embedded, e.g. '500k' or '2M'; defaults to 1M
+    inline_max_size: Option<String>,
+    #[argh(option)]
+    /// which variant of attachments to download with --download-media; valid options are 'full' (the original) and 'thumbnail'; if unspecified, defaults to full
+    media: Option<String>,
+    #[argh(option)]
+    /// how exports link to attachments; valid options are 'relative' (paths to the copies downloaded with --download-media), 'mxc' (the original mxc:// URIs), and 'http' (download URLs on your homeserver); if unspecified, defaults to relative
+    media_links: Option<String>,
+    #[argh(option)]
+    /// maximum number of attachments to download at once with --download-media; defaults to 4
+    media_concurrency: Option<usize>,
+    #[argh(switch)]
+    /// export every room the user has joined, in addition to any rooms listed explicitly
+    all: bool,
+    #[argh(option)]
+    /// with --all, only export rooms with at least this many joined members
+    min_members: Option<u64>,
+    #[argh(option)]
+    /// with --all, only export rooms with at most this many joined members
+    max_members: Option<u64>,
+    #[argh(switch)]
+    /// with --all, only export end-to-end encrypted rooms
+    encrypted_only: bool,
+    #[argh(switch)]
+    /// with --all, only export unencrypted rooms
+    unencrypted_only: bool,
+    #[argh(switch)]
+    /// with --all, only export rooms which this account hasn't exported in a previous --all run
+    only_new_rooms: bool,
+    #[argh(option)]
+    /// regex which room names must match in full; every joined room with a matching name is exported, in addition to any rooms listed explicitly
+    name_pattern: Option<String>,
+    #[argh(option)]
+    /// regex which senders' user IDs are matched against in full; matching senders' events are left out of the export, e.g. '@.*bot:.*' or '@telegram_.*' for bots and bridge puppets
+    exclude_sender_pattern: Option<String>,
+    #[argh(switch)]
+    /// accept pending invites to the rooms named for export (never to any other rooms), so that they can be exported
+    accept_invites: bool,
+    #[argh(switch)]
+    /// skip the sync before exporting, using the room list and room state cached from the last sync instead; faster, but room names and membership may be out of date, and room keys received since then won't be available for decryption; if a requested room isn't cached, syncs after all
+    no_sync: bool,
+    #[argh(option)]
+    /// path of a file listing further rooms to export, one room ID, alias, or display name per line; blank lines and '#' comments are ignored
+    rooms_file: Option<PathBuf>,
+    #[argh(option)]
+    /// room ID, alias, or display name to leave out of the export, e.g. when using --all; flag can be used multiple times; a display name shared by several rooms excludes all of them
+    exclude_room: Vec<String>,
+    #[argh(switch)]
+    /// exit with a partial-export error if some messages couldn't be decrypted; either way, the megolm sessions needed to decrypt them are reported at the end of the export
+    fail_on_decrypt_error: bool,
+    #[argh(option)]
+    /// user id of another logged-in account whose view of each exported room should be merged into the main account's, e.g. to cover a stretch of history the main account can't see; flag can be used multiple times
+    also_user: Vec<String>,
+    #[argh(switch)]
+    /// write events to the json export exactly as received from the server, rather than in trace's own stable schema
+    raw_json: bool,
+    #[argh(switch)]
+    /// also write each room's events, verbatim as received from the server, to a '.raw.json' file alongside the chosen formats
+    raw_dump: bool,
+    #[argh(switch)]
+    /// write json exports on a single line rather than pretty-printed, roughly halving their size
+    json_compact: bool,
+    #[argh(switch)]
+    /// write each room's json export message by message as it's formatted, rather than all at once, to keep memory use down for very large rooms; the output is the same either way
+    output_json_array_stream: bool,
+    #[argh(switch)]
+    /// replace user IDs in every format with salted hashes of themselves, so that users can be correlated across rooms without being identified; unless --hash-salt is given, a random salt is used and discarded at the end of the run, so the hashes can't be reversed (or reproduced) afterwards
+    hash_senders: bool,
+    #[argh(option)]
+    /// salt to use for --hash-senders (implies --hash-senders), so that hashes stay consistent across runs; anyone with the salt can test guesses of user IDs against the hashes, so keep it secret
+    hash_salt: Option<String>,
+    #[argh(switch)]
+    /// record in the json export which users have read up to each message; only each user's latest read receipt is available, as of the last sync, so this shows where everyone has read up to now rather than a history of reading
+    include_read_receipts: bool,
+    #[argh(switch)]
+    /// skip message history entirely and export only a summary of each room's metadata (name, topic, aliases, member count, encryption, creation event, and power levels); useful for inventorying many rooms before deciding which to fully archive
+    summary_only: bool,
+    #[argh(switch)]
+    /// in the txt export, mark messages which have since been edited with '(edited)' and the time of their latest edit
+    include_edited_flag_in_txt: bool,
+    #[argh(switch)]
+    /// in the txt export, prefix each message with the start of its event ID, for cross-referencing with the json export
+    show_event_ids: bool,
+    #[argh(switch)]
+    /// in the txt and html exports, render state events (e.g. room name and topic changes, with their previous values) rather than leaving placeholders
+    render_state: bool,
+    #[argh(switch)]
+    /// in the txt and html exports, quote each reply's chain of replied-to messages inline, nested up to 5 levels deep
+    flatten_replies: bool,
+    #[argh(switch)]
+    /// in the txt and html exports, show senders with the display names they had when each message was sent (as far as the export's membership events tell), rather than their current names
+    prefer_display_name_at_event_time: bool,
+    #[argh(switch)]
+    /// give each event in the json export a permalink, and link html timestamps to their permalinks
+    permalinks: bool,
+    #[argh(option)]
+    /// base URL for permalinks, implying --permalinks; if unspecified, defaults to https://matrix.to/#/
+    permalink_base: Option<String>,
+    #[argh(switch)]
+    /// don't write export files for rooms with no messages to export, just a notice; by default such rooms get files saying '[No messages]'
+    skip_empty: bool,
+    #[argh(switch)]
+    /// only export messages with attachments (images, files, videos, and audio) and stickers, e.g. with --download-media to make a media archive
+    media_only: bool,
+    #[argh(option)]
+    /// event id (of the form $abcdefghijklmnopqr) to export only the surrounding conversation of, rather than the room's whole history; requires exactly one room
+    context: Option<String>,
+    #[argh(option, default = "10")]
+    /// number of events to include on either side of the --context event; defaults to 10
+    context_lines: u16,
+    #[argh(switch)]
+    /// write every room's json export into a single 'Trace export.json' document keyed by room ID, rather than a file per room; json-only
+    single_file: bool,
+    #[argh(option)]
+    /// '/messages' pagination token (e.g. from another tool) to start fetching history from, rather than the start of the room; requires exactly one room
+    from_token: Option<String>,
+    #[argh(option)]
+    /// number of events to request per page when fetching history; defaults to 1000; homeservers may cap this lower
+    batch_size: Option<u16>,
+    #[argh(switch)]
+    /// replace existing export files of the same name; this is the default
+    overwrite: bool,
+    #[argh(switch)]
+    /// fail a room's export rather than replace an existing export file of the same name, e.g. to protect a previous archive in the same output directory
+    no_clobber: bool,
+    #[argh(option)]
+    /// character encoding to write the txt export in, e.g. 'windows-1252' or 'latin1', for tools which can't read UTF-8; any WHATWG encoding label other than UTF-16 is accepted; defaults to UTF-8
+    output_encoding: Option<String>,
+    #[argh(option)]
+    /// what to do with characters which --output-encoding can't represent; valid options are 'replace' (with numeric character references, e.g. '&#128512;') and 'error' (failing the room's export); defaults to replace
+    on_unmappable: Option<String>,
+    #[argh(option)]
+    /// split each room's txt and json exports at message boundaries into numbered parts of at most this size, e.g. '500k', '10M', or '1G' (or plain bytes), named like 'room.part1.txt'; html exports aren't split
+    max_file_size: Option<String>,
+    #[argh(option)]
+    /// instead of exporting messages, write a tally of each message's reactions (event ID, message sender, reaction, count) to a ' [reactions]' file, in this format; valid options are 'json' and 'csv'
+    only_reactions_summary: Option<String>,
+    #[argh(switch)]
+    /// instead of exporting messages, write each room's complete current state (every state event, keyed by type and state key) to a ' [state]' json file
+    export_state_only: bool,
+    #[argh(switch)]
+    /// instead of exporting, print the exact number of messages each room's export would contain (after --media-only and --exclude-sender-pattern filtering), and the total, writing nothing; this fetches each room's full history, just as exporting it would
+    count_only: bool,
+    #[argh(switch)]
+    /// also write a ' [threads]' json file per room, listing each thread's root, reply count, and latest reply
+    thread_summary: bool,
+    #[argh(switch)]
+    /// split each room's txt export into a file per day (in UTC, like the timestamps), named with the date, e.g. 'room 2024-01-15.txt'; days without messages get no file
+    split_by_day: bool,
+    #[argh(option)]
+    /// flag consecutive messages further apart than this as a possible gap in the room's history (which homeservers can't always backfill), with a warning and a marker in the txt and html exports; e.g. '12h', '3d', or '90m' (or plain seconds)
+    gap_threshold: Option<String>,
+    #[argh(option)]
+    /// text to put at the top of every txt and html export file, e.g. an archive's title or a retention notice; json exports are unaffected
+    header_text: Option<String>,
+    #[argh(option)]
+    /// path of a file whose contents are used as with --header-text
+    header_file: Option<PathBuf>,
+    #[argh(option)]
+    /// text to put at the bottom of every txt and html export file; json exports are unaffected
+    footer_text: Option<String>,
+    #[argh(option)]
+    /// path of a file whose contents are used as with --footer-text
+    footer_file: Option<PathBuf>,
+    #[argh(option)]
+    /// file to append a log of the export's progress to, as JSON lines (logins, each room's start and finish with event counts, and errors), e.g. for diagnosing overnight jobs
+    log_file: Option<PathBuf>,
 }
 
 #[derive(FromArgs)]
@@ -80,11 +350,50 @@ struct Export {
 /// List rooms accessible from a given user ID's login
 struct ListRooms {
     #[argh(positional)]
-    /// user id (of the form @alice:example.com) to list rooms from
-    user_id: String,
+    /// user id (of the form @alice:example.com) to list rooms from; if unspecified, uses the default account
+    user_id: Option<String>,
     #[argh(switch, short = 'j')]
-    /// display room list as JSON rather than as human-readable text
+    /// display room list as JSON (with each room's ID, name, canonical and alternative aliases, and encryption status) rather than as human-readable text
     json: bool,
+    #[argh(switch)]
+    /// skip the sync before listing, using the room list cached from the last sync instead; faster, but rooms joined or left since then won't be reflected; if no rooms are cached, syncs after all
+    no_sync: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "profile")]
+/// Show a user's global profile and their membership in each room shared with a given user ID's login
+struct Profile {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) to look the profile up from, which may be omitted if a default account is set, followed by the user id (of the form @bob:example.com) whose profile to look up
+    user_ids: Vec<String>,
+    #[argh(option, short = 'f')]
+    /// format to display the profile in; valid options are 'json' and 'txt'; if unspecified, defaults to txt
+    format: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "room-info")]
+/// Show a single room's details: name, topic, aliases, member count, encryption, creation, and power levels
+struct RoomInfo {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) to look the room up from, which may be omitted if a default account is set, followed by the room's ID, alias, or display name
+    arguments: Vec<String>,
+    #[argh(option, short = 'f')]
+    /// format to display the room's details in; valid options are 'json' and 'txt'; if unspecified, defaults to txt
+    format: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "serve")]
+/// Serve a directory of exports over HTTP on localhost, for browsing html exports (with their media) in a web browser
+struct Serve {
+    #[argh(positional)]
+    /// directory to serve, e.g. an export's output directory
+    directory: PathBuf,
+    #[argh(option, short = 'p', default = "trace::serve::DEFAULT_SERVE_PORT")]
+    /// port to listen on (on 127.0.0.1 only); defaults to 8000
+    port: u16,
 }
 
 #[derive(FromArgs)]
@@ -98,13 +407,38 @@ struct SessionCommand {
 #[derive(FromArgs)]
 #[argh(subcommand)]
 enum SessionSubcommand {
+    ExportSession(SessionExport),
+    ImportSession(SessionImport),
     List(SessionList),
     Login(SessionLogin),
     Logout(SessionLogout),
+    Refresh(SessionRefresh),
     Rename(SessionRename),
+    SetDefault(SessionSetDefault),
     Verify(SessionVerify),
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "export-session")]
+/// Export logged-in sessions to a JSON file, for backup or for moving to another machine
+struct SessionExport {
+    #[argh(option, short = 'o')]
+    /// path of file to write sessions to, which mustn't already exist; if unspecified or '-', writes to stdout
+    output: Option<PathBuf>,
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) whose session should be exported; if unspecified, exports every session
+    user_id: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "import-session")]
+/// Import sessions from a file written by export-session
+struct SessionImport {
+    #[argh(positional)]
+    /// path of file to read sessions from
+    input: PathBuf,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "list")]
 /// List currently-logged-in accounts
@@ -122,8 +456,14 @@ struct SessionLogin {
     /// user id (of the form @alice:example.com) to be logged in
     user_id: String,
     #[argh(positional)]
-    /// optional session name for use in place of the default randomized one
-    session_name: Option<String>
+    /// optional session name for use in place of the default ('Trace'); equivalent to --device-name
+    session_name: Option<String>,
+    #[argh(option)]
+    /// display name for the new session's device, as shown in other clients' session lists; defaults to 'Trace'
+    device_name: Option<String>,
+    #[argh(switch)]
+    /// read the password from the first line of stdin (e.g. piped in from a password manager) rather than prompting for it
+    password_stdin: bool,
 }
 
 #[derive(FromArgs)]
@@ -135,16 +475,31 @@ struct SessionLogout {
     user_id: String,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "refresh")]
+/// Refresh a logged-in session's access token using its refresh token, e.g. if it's near expiry
+struct SessionRefresh {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) whose session should be refreshed; if unspecified, uses the default account
+    user_id: Option<String>,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "rename")]
 /// Rename a logged-in session
 struct SessionRename {
     #[argh(positional)]
-    /// user id (of the form @alice:example.com) to be renamed
-    user_id: String,
+    /// user id (of the form @alice:example.com) to be renamed, which may be omitted if a default account is set, followed by the new name for the session
+    arguments: Vec<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "set-default")]
+/// Set the account which commands act as when not given a user ID
+struct SessionSetDefault {
     #[argh(positional)]
-    /// new name for session
-    session_name: String,
+    /// user id (of the form @alice:example.com) of a logged-in account to make the default; if unspecified, clears the default
+    user_id: Option<String>,
 }
 
 #[derive(FromArgs)]
@@ -152,19 +507,25 @@ struct SessionRename {
 /// Verify a logged-in session for purposes of E2E encryption
 struct SessionVerify {
     #[argh(positional)]
-    /// user id (of the form @alice:example.com) to verify your session with
-    user_id: String,
+    /// user id (of the form @alice:example.com) to verify your session with; if unspecified, uses the default account
+    user_id: Option<String>,
+    #[argh(switch)]
+    /// confirm the SAS decimals automatically rather than asking whether they match; INSECURE, since it defeats the check against man-in-the-middle attacks, so only for scripted setups where the other side is known by other means to be your own
+    assume_yes: bool,
 }
 
 ///////////////////////
 //   Non-arg types   //
 ///////////////////////
 
+// Also list-rooms' json schema, which is meant for scripting, e.g. to pick rooms to feed back into export via --rooms-file
 #[derive(Serialize)]
 struct PrintableRoom {
     name: Option<String>,
     alias: Option<String>,
     id: String,
+    alt_aliases: Vec<String>,
+    encrypted: bool,
 }
 
 impl PrintableRoom {
@@ -173,6 +534,8 @@ impl PrintableRoom {
             name: room_info.name,
             alias: room_info.canonical_alias.map(|alias| alias.to_string()),
             id: room_info.id.to_string(),
+            alt_aliases: room_info.alt_aliases.iter().map(|alias| alias.to_string()).collect(),
+            encrypted: room_info.encrypted,
         }
     }
 }
@@ -181,13 +544,211 @@ impl PrintableRoom {
 struct PrintableSession {
     user_id: String,
     name: String,
+    verification_status: SessionVerificationStatus,
 }
 
+////////////////////
+//   Exit codes   //
+////////////////////
+
+// Keep these in sync with the table in README.md
+const EXIT_OTHER: u8 = 1;
+const EXIT_AUTHENTICATION: u8 = 2;
+const EXIT_NETWORK: u8 = 3;
+const EXIT_ROOM_NOT_FOUND: u8 = 4;
+const EXIT_OUTPUT_IO: u8 = 5;
+const EXIT_PARTIAL_EXPORT: u8 = 6;
+const EXIT_INTERRUPTED: u8 = 130; // The shell convention for termination by SIGINT
+
 /////////////////
 //   Helpers   //
 /////////////////
 
-async fn handle_verification_request(verification_request: VerificationRequest) -> anyhow::Result<()> {
+#[derive(Clone, Copy)]
+enum TerminalStyle {
+    Bold,
+    Dim,
+    Green,
+    Red,
+    Yellow,
+}
+
+// Color is only used when stdout is an interactive terminal, and never when NO_COLOR is set (see https://no-color.org)
+fn color_enabled() -> bool {
+    io::stdout().is_terminal() && env::var_os("NO_COLOR").map_or(true, |no_color| no_color.is_empty())
+}
+
+fn styled(text: &str, style: TerminalStyle) -> String {
+    if !color_enabled() {
+        return String::from(text)
+    }
+    let escape_code = match style {
+        TerminalStyle::Bold => "1",
+        TerminalStyle::Dim => "2",
+        TerminalStyle::Green => "32",
+        TerminalStyle::Red => "31",
+        TerminalStyle::Yellow => "33",
+    };
+    format!("\x1b[{}m{}\x1b[0m", escape_code, text)
+}
+
+fn http_error_exit_code(error: &HttpError) -> u8 {
+    match error.client_api_error_kind() {
+        Some(ErrorKind::Forbidden | ErrorKind::UnknownToken { .. } | ErrorKind::MissingToken | ErrorKind::UserDeactivated) => EXIT_AUTHENTICATION,
+        Some(_) => EXIT_OTHER,
+        None => match error {
+            HttpError::Reqwest(_) => EXIT_NETWORK,
+            _ => EXIT_OTHER,
+        },
+    }
+}
+
+fn exit_code_for_error(error: &anyhow::Error) -> u8 {
+    if let Some(trace_error) = error.downcast_ref::<TraceError>() {
+        match trace_error {
+            TraceError::RoomNotFound(_) => EXIT_ROOM_NOT_FOUND,
+            TraceError::OutputIo { .. } => EXIT_OUTPUT_IO,
+            TraceError::PartialExport { .. } => EXIT_PARTIAL_EXPORT,
+            TraceError::Timeout(_) => EXIT_NETWORK,
+            TraceError::SessionExpired(_) => EXIT_AUTHENTICATION,
+            TraceError::MissingDecryptionKeys(_) => EXIT_PARTIAL_EXPORT,
+            TraceError::Interrupted { .. } => EXIT_INTERRUPTED,
+        }
+    } else if let Some(login_error) = error.downcast_ref::<LoginError>() {
+        match login_error {
+            LoginError::InvalidCredentials | LoginError::PasswordLoginUnsupported(_) => EXIT_AUTHENTICATION,
+            LoginError::RateLimited(_) | LoginError::Network(_) => EXIT_NETWORK,
+            LoginError::Other(matrix_sdk::Error::Http(http_error)) => http_error_exit_code(http_error),
+            LoginError::Other(_) => EXIT_OTHER,
+        }
+    } else if let Some(matrix_error) = error.downcast_ref::<matrix_sdk::Error>() {
+        match matrix_error {
+            matrix_sdk::Error::Http(http_error) => http_error_exit_code(http_error),
+            _ => EXIT_OTHER,
+        }
+    } else if let Some(http_error) = error.downcast_ref::<HttpError>() {
+        http_error_exit_code(http_error)
+    } else if error.downcast_ref::<ClientBuildError>().is_some() {
+        EXIT_NETWORK // Almost always a failure to reach the homeserver during server discovery
+    } else {
+        EXIT_OTHER
+    }
+}
+
+async fn login_and_sync(user_id: &str, sessions_file: &SessionsFile, store_path: &Path, timeout: Option<Duration>, client_config: &ClientConfig) -> anyhow::Result<Client> {
+    let client = with_timeout(timeout, nonfirst_login(user_id, sessions_file, store_path, client_config)).await?;
+    with_timeout(timeout, async { Ok(client.sync_once(default_sync_settings()).await?) }).await?;
+    Ok(client)
+}
+
+// Sizes are in binary units, so '1k' is 1024 bytes
+fn parse_byte_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, multiplier) = match size.char_indices().last()? {
+        (index, 'k' | 'K') => (&size[..index], 1 << 10),
+        (index, 'm' | 'M') => (&size[..index], 1 << 20),
+        (index, 'g' | 'G') => (&size[..index], 1 << 30),
+        _ => (size, 1),
+    };
+    number.trim().parse::<u64>().ok().filter(|number| *number > 0)?.checked_mul(multiplier)
+}
+
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = duration.trim();
+    let (number, multiplier) = match duration.char_indices().last()? {
+        (index, 's') => (&duration[..index], 1),
+        (index, 'm') => (&duration[..index], 60),
+        (index, 'h') => (&duration[..index], 60 * 60),
+        (index, 'd') => (&duration[..index], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+    number.trim().parse::<u64>().ok().filter(|number| *number > 0)?.checked_mul(multiplier).map(Duration::from_secs)
+}
+
+// Room aliases also start with '#', but always contain a ':' and never whitespace, so they can be told apart from comments
+fn room_identifiers_from_file_contents(contents: &str) -> Vec<String> {
+    contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .filter(|line| !(line.starts_with('#') && (!line.contains(':') || line.contains(char::is_whitespace))))
+        .map(String::from)
+        .collect()
+}
+
+// The first ctrl-c asks the export to wrap up gracefully, writing out what it has so far; the second quits immediately
+fn interrupt_on_ctrl_c() -> Arc<AtomicBool> {
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let handler_interrupt = interrupt.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("{}", styled("Interrupted; finishing up the current room. Press ctrl-c again to quit immediately.", TerminalStyle::Yellow));
+            handler_interrupt.store(true, Ordering::SeqCst);
+            if tokio::signal::ctrl_c().await.is_ok() {
+                process::exit(EXIT_INTERRUPTED.into());
+            }
+        }
+    });
+    interrupt
+}
+
+// Room IDs are looked up in the store directly; aliases and names can only be checked against each cached room in turn
+fn room_is_cached(client: &Client, identifier: &str) -> bool {
+    match RoomId::parse(identifier) {
+        Ok(room_id) => client.get_room(&room_id).is_some_and(|room| room.state() == RoomState::Joined),
+        Err(_) => client.joined_rooms().iter().any(|room| room.canonical_alias().is_some_and(|alias| alias == identifier) || room.alt_aliases().iter().any(|alias| alias == identifier) || room.name().is_some_and(|name| name == identifier)),
+    }
+}
+
+// Skipping the sync means relying on the store's cache, so an empty cache (e.g. a store that's never been synced) or one missing any of the requested rooms falls back to syncing after all, rather than giving a silently-incomplete result
+async fn login_and_maybe_sync(user_id: &str, sessions_file: &SessionsFile, store_path: &Path, timeout: Option<Duration>, client_config: &ClientConfig, no_sync: bool, requested_rooms: &[String]) -> anyhow::Result<Client> {
+    if !no_sync {
+        return login_and_sync(user_id, sessions_file, store_path, timeout, client_config).await
+    }
+    let client = with_timeout(timeout, nonfirst_login(user_id, sessions_file, store_path, client_config)).await?;
+    let sync_reason = if client.joined_rooms().is_empty() {
+        Some(String::from("no rooms are cached"))
+    } else {
+        requested_rooms.iter().find(|identifier| !room_is_cached(&client, identifier)).map(|identifier| format!("room {} isn't cached", identifier))
+    };
+    if let Some(sync_reason) = sync_reason {
+        eprintln!("Syncing {} despite --no-sync, since {}.", add_at_to_user_id_if_applicable(user_id), sync_reason);
+        with_timeout(timeout, async { Ok(client.sync_once(default_sync_settings()).await?) }).await?;
+    }
+    Ok(client)
+}
+
+fn user_id_or_default(user_id: Option<String>, default_user_id: Option<&str>) -> anyhow::Result<String> {
+    match (user_id, default_user_id) {
+        (Some(user_id), _) => Ok(user_id),
+        (None, Some(default_user_id)) => Ok(String::from(default_user_id)),
+        (None, None) => anyhow::bail!("No user id was given and no default account is set. Pass a user id, or set a default account with 'session set-default'."),
+    }
+}
+
+// For commands taking a user ID followed by a fixed number of further positionals, where the user ID may be left off in favor of the default account
+fn split_optional_user_id(mut positionals: Vec<String>, remaining_count: usize, default_user_id: Option<&str>) -> anyhow::Result<(String, Vec<String>)> {
+    if positionals.len() == remaining_count + 1 {
+        let user_id = positionals.remove(0);
+        Ok((user_id, positionals))
+    } else if positionals.len() == remaining_count {
+        Ok((user_id_or_default(None, default_user_id)?, positionals))
+    } else {
+        anyhow::bail!("Expected {} arguments (or {} if a default account is set), but received {}.", remaining_count + 1, remaining_count, positionals.len())
+    }
+}
+
+// Export takes any number of rooms, so a leading user ID can't be recognized by count; instead it's recognized by the leading '@' (which no room ID, alias, or name specifier starts with) or by being a logged-in account. With no default account set, the first positional is always taken as the user ID, as it was before defaults existed.
+fn split_leading_user_id(mut positionals: Vec<String>, sessions_file: &SessionsFile, default_user_id: Option<&str>) -> anyhow::Result<(String, Vec<String>)> {
+    let has_leading_user_id = positionals.first().is_some_and(|first| first.starts_with('@') || sessions_file.get(&add_at_to_user_id_if_applicable(first)).is_ok());
+    if has_leading_user_id || (default_user_id.is_none() && !positionals.is_empty()) {
+        let user_id = positionals.remove(0);
+        Ok((user_id, positionals))
+    } else {
+        Ok((user_id_or_default(None, default_user_id)?, positionals))
+    }
+}
+
+async fn handle_verification_request(verification_request: VerificationRequest, assume_yes: bool) -> anyhow::Result<()> {
     verification_request.accept().await?;
     let mut verification_state_stream = verification_request.changes();
     while let Some(state) = verification_state_stream.next().await {
@@ -199,25 +760,29 @@ async fn handle_verification_request(verification_request: VerificationRequest)
                     while let Some(state) = sas_verification_state_stream.next().await {
                         match state {
                             SasState::KeysExchanged {decimals, ..} => {
-                                println!("Attempting verification. SAS decimals: {}, {}, {}", decimals.0, decimals.1, decimals.2);
+                                println!("Attempting verification. SAS decimals: {}", styled(&format!("{}, {}, {}", decimals.0, decimals.1, decimals.2), TerminalStyle::Bold));
+                                if assume_yes {
+                                    sas_verification.confirm().await?;
+                                    println!("{}", styled("Confirmed automatically, without comparing the decimals, due to --assume-yes. Waiting for the other side to confirm as well...", TerminalStyle::Yellow));
+                                    continue
+                                }
                                 println!("Do these decimals match those shown on the other side of the verification? (Y)es/(N)o/(C)ancel");
                                 loop {
                                     let input: String = text_io::read!();
                                     match input.trim().to_ascii_lowercase().as_ref() {
                                         "y" | "yes" => {
                                             sas_verification.confirm().await?;
-                                            println!("Verified. Make sure verification has finished on the other end, then ctrl-c out.");
-                                            // Add checking to ensure verification succeeds on the remote end as well before breaking
+                                            println!("Confirmed. Waiting for the other side to confirm as well...");
                                             break
                                         }
                                         "n" | "no" => {
                                             sas_verification.mismatch().await?;
-                                            println!("Verification failed due to string mismatch.");
+                                            println!("{}", styled("Verification failed due to string mismatch.", TerminalStyle::Red));
                                             break
                                         }
                                         "c" | "cancel" => {
                                             sas_verification.cancel().await?;
-                                            println!("Canceled verification attempt.");
+                                            println!("{}", styled("Canceled verification attempt.", TerminalStyle::Yellow));
                                             break
                                         }
                                         _ => println!("Input '{}' not recognized. Please try again.", input),
@@ -225,19 +790,29 @@ async fn handle_verification_request(verification_request: VerificationRequest)
                                 }
 
                             }
+                            // Only reached once both sides have confirmed, so the other side can't still back out
+                            SasState::Done { .. } => {
+                                println!("{} You can now ctrl-c out.", styled("Verified.", TerminalStyle::Green));
+                                return Ok(())
+                            }
+                            SasState::Cancelled(info) if info.cancelled_by_us() => return Ok(()), // Already reported when the user mismatched or cancelled
+                            SasState::Cancelled(info) => {
+                                println!("{} Reason: {}", styled("Verification failed; the other side cancelled it before both sides confirmed.", TerminalStyle::Red), info.reason());
+                                return Ok(())
+                            }
                             _ =>(),
                         }
                     }
                 } else {
-                    println!("Received verification attempt of type other than SAS V1. Trace CLI can't handle QR code verification, and Trace's developers are unaware of any verification types aside from SAS V1 and QR, so this verification attempt has been aborted.");
+                    println!("{}", styled("Received verification attempt of type other than SAS V1. Trace CLI can't handle QR code verification, and Trace's developers are unaware of any verification types aside from SAS V1 and QR, so this verification attempt has been aborted.", TerminalStyle::Yellow));
                 }
             }
             VerificationRequestState::Cancelled(info) => {
-                println!("Verification cancelled. Cancel info: {:?}", info);
+                println!("{} Cancel info: {:?}", styled("Verification cancelled.", TerminalStyle::Yellow), info);
                 break
             }
             VerificationRequestState::Done => {
-                println!("Verification done.");
+                println!("{}", styled("Verification done.", TerminalStyle::Green));
                 break
             }
             _ => (),
@@ -251,40 +826,329 @@ async fn handle_verification_request(verification_request: VerificationRequest)
 //   Main   //
 //////////////
 
-async fn export(config: Export, sessions_file: &SessionsFile, dirs: &ProjectDirs) -> anyhow::Result<()> {
-    let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&config.user_id));
+fn list_formats() {
+    for format in ExportOutputFormat::VARIANTS {
+        let (name, aliases) = format.specifiers().split_first().unwrap();
+        let aliases = if aliases.is_empty() {
+            String::new()
+        } else {
+            format!(" (or {})", aliases.join(", "))
+        };
+        println!("{}{}, .{}: {}", styled(name, TerminalStyle::Bold), aliases, format.extension(), format.description());
+    }
+    println!("{}: {}", styled("all", TerminalStyle::Bold), ExportOutputFormat::ALL.iter().map(|format| format.specifiers()[0]).collect::<Vec<_>>().join(", "));
+}
+
+// Runs before the sessions file is opened (which would otherwise fail the whole command if the file were broken), so that it can diagnose the sessions file itself
+async fn doctor(config: &Doctor, sessions_file_path: &Path, passphrase: Option<String>, dirs: &ProjectDirs, timeout: Option<Duration>, client_config: &ClientConfig) -> anyhow::Result<()> {
+    let mut failure_count = 0;
+    let mut report = |result: Result<String, (String, String)>| match result {
+        Ok(message) => println!("{} {}", styled("[OK]", TerminalStyle::Green), message),
+        Err((message, hint)) => {
+            failure_count += 1;
+            println!("{} {}\n       {}", styled("[FAIL]", TerminalStyle::Red), message, hint);
+        }
+    };
+
+    let sessions_file_existed = sessions_file_path.exists();
+    let sessions_file = match SessionsFile::open_with_passphrase(sessions_file_path.to_path_buf(), passphrase) {
+        Ok(sessions_file) => {
+            report(Ok(if sessions_file_existed {
+                format!("Sessions file {} is readable, with {} sessions.", sessions_file_path.display(), sessions_file.sessions.len())
+            } else {
+                format!("No sessions file existed yet, so an empty one was created at {}.", sessions_file_path.display())
+            }));
+            Some(sessions_file)
+        }
+        Err(e) => {
+            report(Err((format!("Sessions file {} couldn't be read: {}", sessions_file_path.display(), e), String::from("If it's encrypted, check the passphrase (or the TRACE_SESSIONS_PASSPHRASE environment variable); otherwise, restore it from a backup (see 'session export-session'), or move it aside and log in again."))));
+            None
+        }
+    };
+
+    let trace_config_path = dirs.data_local_dir().join(trace::CONFIG_FILENAME);
+    match TraceConfig::open(&trace_config_path) {
+        Ok(trace_config) => match (&trace_config.default_user_id, &sessions_file) {
+            (Some(default_user_id), Some(sessions_file)) if sessions_file.get(default_user_id).is_err() => report(Err((format!("Default account {} has no logged-in session.", default_user_id), String::from("Log it in with 'session login', or change the default with 'session set-default'.")))),
+            _ => report(Ok(format!("Config file {} is valid.", trace_config_path.display()))),
+        },
+        Err(e) => report(Err((format!("Config file {} couldn't be read: {}", trace_config_path.display(), e), String::from("Fix or delete it; it only holds the default account, which can be set again with 'session set-default'.")))),
+    }
+
+    for session in sessions_file.iter().flat_map(|sessions_file| sessions_file.sessions.iter()) {
+        match with_timeout(timeout, trace::validate_session(session, client_config)).await {
+            Ok(()) => report(Ok(format!("Session for {} is valid, and its homeserver is reachable.", session.user_id))),
+            Err(e) => report(Err((format!("Session for {} failed validation: {}", session.user_id, e), format!("If the homeserver is reachable, log in again with 'session logout {}' and then 'session login {}'.", session.user_id, session.user_id)))),
+        }
+        let store_path = dirs.data_local_dir().join(user_id_to_crypto_store_path(&session.user_id));
+        if store_path.is_dir() {
+            report(Ok(format!("Crypto store for {} is present.", session.user_id)));
+        } else {
+            report(Err((format!("Crypto store for {} is missing from {}.", session.user_id, store_path.display()), format!("Encrypted rooms can't be decrypted without it. If the session was imported, copy its crypto store over too; otherwise, log in again with 'session logout {}' and then 'session login {}'.", session.user_id, session.user_id))));
+        }
+    }
+
+    // The output directory is created by export if need be, so it's the nearest existing ancestor which needs to be writable
+    let output_path = config.output.clone().unwrap_or_else(|| PathBuf::from("."));
+    let existing_output_ancestor = output_path.ancestors().find(|ancestor| ancestor.is_dir()).unwrap_or(Path::new("."));
+    let write_test_path = existing_output_ancestor.join(".trace-doctor-write-test");
+    match write(&write_test_path, "").and_then(|_| remove_file(&write_test_path)) {
+        Ok(()) => report(Ok(format!("Output directory {} is writable.", output_path.display()))),
+        Err(e) => report(Err((format!("Output directory {} isn't writable: {}", output_path.display(), e), String::from("Pick a different directory with --output, or fix the directory's permissions.")))),
+    }
+
+    if failure_count > 0 {
+        anyhow::bail!("{} checks failed.", failure_count);
+    }
+    println!("All checks passed.");
+
+    Ok(())
+}
+
+async fn export(config: Export, sessions_file: &SessionsFile, dirs: &ProjectDirs, timeout: Option<Duration>, client_config: &ClientConfig, rate_limit: Option<f64>, default_user_id: Option<&str>) -> anyhow::Result<()> {
+    let (user_id, mut rooms) = split_leading_user_id(config.rooms, sessions_file, default_user_id)?;
+    if let Some(rooms_file) = &config.rooms_file {
+        let rooms_file_contents = read_to_string(rooms_file).map_err(|e| anyhow::anyhow!("Couldn't read rooms file {}: {}", rooms_file.display(), e))?;
+        rooms.extend(room_identifiers_from_file_contents(&rooms_file_contents));
+    }
+    let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&user_id));
     let mut export_formats = HashSet::new();
     for format in config.formats {
-        match format.to_lowercase().as_ref() {
-            "json" | ".json" => export_formats.insert(ExportOutputFormat::Json),
-            "txt" | ".txt" => export_formats.insert(ExportOutputFormat::Txt),
-            _ => panic!("Received invalid format specifier {} on export command. Valid options are 'json' and 'txt'.", format), // Add real error-handling here. (It'd be nice if argh allowed more direct handling of this; track https://github.com/google/argh/issues/138 in case it eventually does.)
+        if format.eq_ignore_ascii_case("all") {
+            export_formats.extend(ExportOutputFormat::ALL.iter().copied());
+            continue;
+        }
+        match ExportOutputFormat::from_specifier(&format) {
+            Some(export_format) => export_formats.insert(export_format),
+            // It'd be nice if argh allowed more direct handling of this; track https://github.com/google/argh/issues/138 in case it eventually does
+            None => anyhow::bail!("Received invalid format specifier {} on export command. Valid options are {}, and 'all'; see --list-formats for details.", format, ExportOutputFormat::VARIANTS.iter().flat_map(|format| format.specifiers()).map(|specifier| format!("'{}'", specifier)).collect::<Vec<_>>().join(", ")),
         };
     }
     if export_formats.is_empty() {
         export_formats.insert(ExportOutputFormat::Json);
     }
+    let media_variant = match config.media {
+        Some(variant) => match MediaVariant::from_specifier(&variant) {
+            Some(variant) => variant,
+            None => anyhow::bail!("Received invalid media variant {} on export command. Valid options are 'full' and 'thumbnail'.", variant),
+        },
+        None => MediaVariant::default(),
+    };
+    let reactions_summary = match &config.only_reactions_summary {
+        Some(format) => match ReactionsSummaryFormat::from_specifier(format) {
+            Some(format) => Some(format),
+            None => anyhow::bail!("Received invalid reactions summary format {}. Valid options are 'json' and 'csv'.", format),
+        },
+        None => None,
+    };
+    let media_link_kind = match &config.media_links {
+        Some(kind) => match MediaLinkKind::from_specifier(kind) {
+            Some(kind) => kind,
+            None => anyhow::bail!("Received invalid --media-links option {}. Valid options are 'relative', 'mxc', and 'http'.", kind),
+        },
+        None => MediaLinkKind::default(),
+    };
+
+    let sender_hasher = match (config.hash_senders, config.hash_salt) {
+        (_, Some(salt)) => Some(SenderHasher::new(salt.into_bytes())),
+        (true, None) => Some(SenderHasher::with_random_salt()),
+        (false, None) => None,
+    };
+    let existing_file_policy = match (config.overwrite, config.no_clobber) {
+        (true, true) => anyhow::bail!("--overwrite and --no-clobber can't be used together."),
+        (_, true) => ExistingFilePolicy::NoClobber,
+        (_, false) => ExistingFilePolicy::Overwrite,
+    };
+    let encrypted = match (config.encrypted_only, config.unencrypted_only) {
+        (true, true) => anyhow::bail!("--encrypted-only and --unencrypted-only can't be used together."),
+        (true, false) => Some(true),
+        (false, true) => Some(false),
+        (false, false) => None,
+    };
+    // encoding_rs can only decode UTF-16, so labels for it have to be turned away rather than silently writing UTF-8
+    let txt_encoding = match &config.output_encoding {
+        Some(label) => match Encoding::for_label(label.trim().as_bytes()).filter(|encoding| encoding.output_encoding() == *encoding) {
+            Some(encoding) => Some(encoding),
+            None => anyhow::bail!("{} isn't an encoding which txt exports can be written in.", label),
+        },
+        None => None,
+    };
+    let unmappable_character_policy = match &config.on_unmappable {
+        Some(policy) => match UnmappableCharacterPolicy::from_specifier(policy) {
+            Some(policy) => policy,
+            None => anyhow::bail!("Received invalid --on-unmappable policy {}. Valid options are 'replace' and 'error'.", policy),
+        },
+        None => UnmappableCharacterPolicy::default(),
+    };
+    let inline_image_max_size = match (config.include_thumbnails_inline, &config.inline_max_size) {
+        (true, Some(size)) => match parse_byte_size(size) {
+            Some(size) => Some(size),
+            None => anyhow::bail!("Received invalid --inline-max-size {}. Sizes are a positive whole number of bytes, optionally followed by k, M, or G.", size),
+        },
+        (true, None) => Some(trace::media::DEFAULT_INLINE_IMAGE_MAX_SIZE),
+        (false, Some(_)) => anyhow::bail!("--inline-max-size only applies with --include-thumbnails-inline, which wasn't given."),
+        (false, None) => None,
+    };
+    let max_file_size = match &config.max_file_size {
+        Some(size) => match parse_byte_size(size) {
+            Some(size) => Some(size),
+            None => anyhow::bail!("Received invalid --max-file-size {}. Sizes are a positive whole number of bytes, optionally followed by k, M, or G.", size),
+        },
+        None => None,
+    };
+    let gap_threshold = match &config.gap_threshold {
+        Some(threshold) => match parse_duration(threshold) {
+            Some(threshold) => Some(threshold),
+            None => anyhow::bail!("Received invalid --gap-threshold {}. Thresholds are a positive whole number, optionally followed by s, m, h, or d; plain numbers are in seconds.", threshold),
+        },
+        None => None,
+    };
+    let header_text = match (config.header_text, &config.header_file) {
+        (Some(_), Some(_)) => anyhow::bail!("--header-text and --header-file can't be used together."),
+        (Some(text), None) => Some(text),
+        (None, Some(path)) => Some(read_to_string(path).map_err(|e| anyhow::anyhow!("Couldn't read header file {}: {}", path.display(), e))?),
+        (None, None) => None,
+    };
+    let footer_text = match (config.footer_text, &config.footer_file) {
+        (Some(_), Some(_)) => anyhow::bail!("--footer-text and --footer-file can't be used together."),
+        (Some(text), None) => Some(text),
+        (None, Some(path)) => Some(read_to_string(path).map_err(|e| anyhow::anyhow!("Couldn't read footer file {}: {}", path.display(), e))?),
+        (None, None) => None,
+    };
+    let permalink_base = match (config.permalinks, config.permalink_base) {
+        (_, Some(permalink_base)) => Some(permalink_base),
+        (true, None) => Some(String::from(trace::export::DEFAULT_PERMALINK_BASE)),
+        (false, None) => None,
+    };
+    let context_event_id = match &config.context {
+        Some(event_id) => Some(EventId::parse(event_id)?),
+        None => None,
+    };
+    let datestamp_format = match (config.datestamp, config.datestamp_format) {
+        (_, Some(format)) => Some(format),
+        (true, None) => Some(String::from(trace::export::DEFAULT_DATESTAMP_FORMAT)),
+        (false, None) => None,
+    };
+
+    // When exporting to stdout, anything informational has to go to stderr instead so as not to corrupt the export stream
+    let to_stdout = config.output.as_deref() == Some(Path::new(trace::export::STDOUT_SENTINEL));
+    let print_info = |message: String| if to_stdout {
+        eprintln!("{}", message)
+    } else {
+        println!("{}", message)
+    };
 
-    let export_room_count = config.rooms.len();
-    if export_room_count == 0 {
-        println!("Successfully exported 0 rooms. (This may not be what you meant to do.)");
+    let count_only = config.count_only;
+    let mut export_options = ExportOptions {
+        output_path: config.output,
+        formats: export_formats,
+        datestamp_format,
+        since_last_export: config.since_last_export,
+        mark_read: config.mark_read,
+        download_media: config.download_media,
+        media_variant,
+        inline_image_max_size,
+        media_link_kind,
+        media_concurrency: config.media_concurrency,
+        all_rooms: config.all,
+        min_members: config.min_members,
+        max_members: config.max_members,
+        encrypted,
+        only_new_rooms: config.only_new_rooms,
+        exported_rooms_path: Some(PathBuf::from(dirs.data_local_dir()).join(user_id_to_exported_rooms_path(&user_id))),
+        name_pattern: config.name_pattern,
+        accept_invites: config.accept_invites,
+        exclude_rooms: config.exclude_room,
+        fail_on_decrypt_error: config.fail_on_decrypt_error,
+        additional_clients: Vec::new(),
+        raw_json: config.raw_json,
+        raw_dump: config.raw_dump,
+        json_compact: config.json_compact,
+        stream_json: config.output_json_array_stream,
+        sender_hasher,
+        rate_limit,
+        include_read_receipts: config.include_read_receipts,
+        summary_only: config.summary_only,
+        reactions_summary,
+        state_only: config.export_state_only,
+        count_only,
+        thread_summary: config.thread_summary,
+        mark_edits_in_txt: config.include_edited_flag_in_txt,
+        show_event_ids_in_txt: config.show_event_ids,
+        render_state: config.render_state,
+        flatten_replies: config.flatten_replies,
+        display_names_at_event_time: config.prefer_display_name_at_event_time,
+        txt_encoding,
+        unmappable_character_policy,
+        permalink_base,
+        media_only: config.media_only,
+        exclude_sender_pattern: config.exclude_sender_pattern,
+        max_file_size,
+        split_txt_by_day: config.split_by_day,
+        gap_threshold,
+        header_text,
+        footer_text,
+        skip_empty: config.skip_empty,
+        context_event_id,
+        context_lines: config.context_lines,
+        from_token: config.from_token,
+        single_file: config.single_file,
+        batch_size: config.batch_size,
+        existing_file_policy,
+        run_log: None,
+        interrupt: None,
+    };
+
+    if rooms.is_empty() && !config.all {
+        print_info(String::from("Successfully exported 0 rooms. (This may not be what you meant to do.)"));
         return Ok(()); // Plausibly replace with an error once I've got real error-handling
     }
 
-    let client = nonfirst_login(&config.user_id, sessions_file, &store_path).await?;
-    client.sync_once(SyncSettings::new().set_presence(PresenceState::Offline)).await?;
-    trace::export(&client, config.rooms, config.output, export_formats).await?;
+    // Checked before logging in, so that a mistyped or conflicting option doesn't cost a login and sync first. Additional accounts aren't logged into yet, so conflicts with them are left to trace::export's own check.
+    export_options.validate(&rooms)?;
 
-    println!("Successfully exported {} rooms.", export_room_count);
+    let run_log = match &config.log_file {
+        Some(path) => Some(RunLog::open(path)?),
+        None => None,
+    };
+    if let Some(run_log) = &run_log {
+        run_log.record("export_start", json!({ "user_id": user_id, "rooms": rooms, "all": config.all }));
+    }
+    let logged_login = |user_id: String, login_result: anyhow::Result<Client>| {
+        if let Some(run_log) = &run_log {
+            match &login_result {
+                Ok(_) => run_log.record("login", json!({ "user_id": user_id })),
+                Err(e) => run_log.record("login_error", json!({ "user_id": user_id, "error": format!("{:#}", e) })),
+            }
+        }
+        login_result
+    };
+
+    let client = logged_login(user_id.clone(), login_and_maybe_sync(&user_id, sessions_file, &store_path, timeout, client_config, config.no_sync, &rooms).await)?;
+    let mut additional_clients = Vec::new();
+    for additional_user_id in &config.also_user {
+        let additional_store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(additional_user_id));
+        additional_clients.push(logged_login(additional_user_id.clone(), login_and_maybe_sync(additional_user_id, sessions_file, &additional_store_path, timeout, client_config, config.no_sync, &[]).await)?);
+    }
+    export_options.additional_clients = additional_clients;
+    export_options.run_log = run_log.clone();
+    export_options.interrupt = Some(interrupt_on_ctrl_c());
+    let export_room_count = trace::export(&client, rooms, export_options).await?;
+
+    if count_only {
+        print_info(format!("Successfully counted messages in {} rooms.", export_room_count));
+    } else {
+        print_info(format!("Successfully exported {} rooms.", export_room_count));
+    }
 
     Ok(())
 }
 
-async fn list_rooms(config: ListRooms, sessions_file: &SessionsFile, dirs: &ProjectDirs) -> anyhow::Result<()> {
-    let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&config.user_id));
-    let normalized_user_id = add_at_to_user_id_if_applicable(&config.user_id);
-    let client = nonfirst_login(&normalized_user_id, sessions_file, &store_path).await?;
-    client.sync_once(SyncSettings::new().set_presence(PresenceState::Offline)).await?;
+async fn list_rooms(config: ListRooms, sessions_file: &SessionsFile, dirs: &ProjectDirs, timeout: Option<Duration>, client_config: &ClientConfig, default_user_id: Option<&str>) -> anyhow::Result<()> {
+    let user_id = user_id_or_default(config.user_id, default_user_id)?;
+    let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&user_id));
+    let normalized_user_id = add_at_to_user_id_if_applicable(&user_id);
+    let client = login_and_maybe_sync(&normalized_user_id, sessions_file, &store_path, timeout, client_config, config.no_sync, &[]).await?;
 
     let printable_rooms = trace::get_rooms_info(&client).await?
         .into_iter()
@@ -296,12 +1160,12 @@ async fn list_rooms(config: ListRooms, sessions_file: &SessionsFile, dirs: &Proj
         println!("Rooms joined by {}:", normalized_user_id);
         for room in printable_rooms {
             let room_name = match room.name {
-                Some(name) => name,
+                Some(name) => styled(&name, TerminalStyle::Bold),
                 None => String::from("[Unnamed]"),
             };
             let room_alias = match room.alias {
-                Some(alias) => alias,
-                None => String::from("[No canonical alias]"),
+                Some(alias) => styled(&alias, TerminalStyle::Dim),
+                None => styled("[No canonical alias]", TerminalStyle::Dim),
             };
             println!("{} | {} | {}", room_name, room_alias, room.id) // Replace with properly-justified table-formatting in the future
         }
@@ -310,12 +1174,120 @@ async fn list_rooms(config: ListRooms, sessions_file: &SessionsFile, dirs: &Proj
     Ok(())
 }
 
-async fn session_list(config: SessionList, sessions_file: &SessionsFile, dirs: &ProjectDirs) -> anyhow::Result<()> {
-    let printable_sessions = trace::list_sessions(sessions_file, dirs).await?
+async fn profile(config: Profile, sessions_file: &SessionsFile, dirs: &ProjectDirs, timeout: Option<Duration>, client_config: &ClientConfig, default_user_id: Option<&str>) -> anyhow::Result<()> {
+    let (user_id, target_user_ids) = split_optional_user_id(config.user_ids, 1, default_user_id)?;
+    let format = match config.format {
+        Some(format) => match ExportOutputFormat::from_specifier(&format) {
+            Some(format @ (ExportOutputFormat::Json | ExportOutputFormat::Txt)) => format,
+            _ => anyhow::bail!("Received invalid format specifier {} on profile command. Valid options are 'json' and 'txt'.", format),
+        },
+        None => ExportOutputFormat::Txt,
+    };
+    let target_user_id = UserId::parse(add_at_to_user_id_if_applicable(&target_user_ids[0]))?;
+
+    let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&user_id));
+    let client = login_and_sync(&user_id, sessions_file, &store_path, timeout, client_config).await?;
+
+    let profile = trace::get_user_profile(&client, &target_user_id).await?;
+    match format {
+        ExportOutputFormat::Json => println!("{}", serde_json::to_string(&profile).unwrap()),
+        ExportOutputFormat::Txt | ExportOutputFormat::Html | ExportOutputFormat::Sqlite => {
+            println!("Profile of {}:", profile.user_id);
+            println!("Display name: {}", profile.display_name.as_deref().unwrap_or("[None]"));
+            println!("Avatar: {}", profile.avatar_url.as_deref().unwrap_or("[None]"));
+            if profile.rooms.is_empty() {
+                println!("No rooms shared with {}.", add_at_to_user_id_if_applicable(&user_id));
+            } else {
+                println!("Rooms shared with {}:", add_at_to_user_id_if_applicable(&user_id));
+                for room in profile.rooms {
+                    println!("{} | {} | {} | {} | power level {}", room.room_name.unwrap_or_else(|| String::from("[Unnamed]")), room.room_id, room.membership, room.display_name.unwrap_or_else(|| String::from("[No room display name]")), room.power_level) // Replace with properly-justified table-formatting in the future
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn room_info(config: RoomInfo, sessions_file: &SessionsFile, dirs: &ProjectDirs, timeout: Option<Duration>, client_config: &ClientConfig, default_user_id: Option<&str>) -> anyhow::Result<()> {
+    let (user_id, room_identifiers) = split_optional_user_id(config.arguments, 1, default_user_id)?;
+    let format = match config.format {
+        Some(format) => match ExportOutputFormat::from_specifier(&format) {
+            Some(format @ (ExportOutputFormat::Json | ExportOutputFormat::Txt)) => format,
+            _ => anyhow::bail!("Received invalid format specifier {} on room-info command. Valid options are 'json' and 'txt'.", format),
+        },
+        None => ExportOutputFormat::Txt,
+    };
+
+    let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&user_id));
+    let client = login_and_sync(&user_id, sessions_file, &store_path, timeout, client_config).await?;
+
+    let summary = trace::get_room_summary(&client, &room_identifiers[0]).await?;
+    match format {
+        ExportOutputFormat::Json => println!("{}", serde_json::to_string(&summary).unwrap()),
+        ExportOutputFormat::Txt | ExportOutputFormat::Html | ExportOutputFormat::Sqlite => print!("{}", trace::room_summary_to_txt(&summary)),
+    }
+
+    Ok(())
+}
+
+async fn session_export(config: SessionExport, sessions_file: &SessionsFile, dirs: &ProjectDirs) -> anyhow::Result<()> {
+    let sessions = match &config.user_id {
+        Some(user_id) => vec![sessions_file.get(&add_at_to_user_id_if_applicable(user_id)).map_err(|e| anyhow::anyhow!(e))?],
+        None => sessions_file.sessions.clone(),
+    };
+    let serialized_sessions = serde_json::to_string_pretty(&sessions)?;
+
+    eprintln!("Warning: exported sessions contain access tokens, which grant full access to their accounts. Keep the export somewhere private, and delete it once it's been imported.");
+    eprintln!("Note that encryption keys aren't included; to decrypt messages with an imported session, also copy its crypto store from {} to the same place on the other machine.", dirs.data_local_dir().display());
+    match config.output.filter(|path| path != Path::new(trace::export::STDOUT_SENTINEL)) {
+        Some(path) => {
+            // Never overwritten, and (on unix) only readable by the current user, since the tokens are as good as a password
+            let mut open_options = OpenOptions::new();
+            open_options.write(true).create_new(true);
+            #[cfg(unix)]
+            open_options.mode(0o600);
+            open_options.open(&path)
+                .and_then(|mut file| file.write_all(serialized_sessions.as_bytes()))
+                .map_err(|e| TraceError::OutputIo { path: path.clone(), source: e })?;
+            eprintln!("Successfully exported {} sessions to {}.", sessions.len(), path.display());
+        },
+        None => println!("{}", serialized_sessions),
+    }
+
+    Ok(())
+}
+
+async fn session_import(config: SessionImport, sessions_file: &mut SessionsFile, timeout: Option<Duration>, client_config: &ClientConfig) -> anyhow::Result<()> {
+    let sessions: Vec<Session> = serde_json::from_str(&read_to_string(&config.input)?)?;
+
+    let mut imported_session_count = 0;
+    for session in sessions {
+        if sessions_file.get(&session.user_id).is_ok() {
+            println!("Skipping session for {}, since you already have a session logged into that account.", session.user_id);
+            continue;
+        }
+        if let Err(e) = with_timeout(timeout, trace::validate_session(&session, client_config)).await {
+            println!("Skipping session for {}, since it couldn't be validated with its homeserver. Error: {}", session.user_id, e);
+            continue;
+        }
+        let user_id = session.user_id.clone();
+        sessions_file.new_session(session).map_err(|e| anyhow::anyhow!(e))?;
+        println!("Imported session for {}.", user_id);
+        imported_session_count += 1;
+    }
+    println!("Successfully imported {} sessions.", imported_session_count);
+
+    Ok(())
+}
+
+async fn session_list(config: SessionList, sessions_file: &SessionsFile, dirs: &ProjectDirs, timeout: Option<Duration>, client_config: &ClientConfig) -> anyhow::Result<()> {
+    let printable_sessions = with_timeout(timeout, trace::list_sessions(sessions_file, dirs, client_config)).await?
         .into_iter()
-        .map(|(user_id, name)| PrintableSession {
-            user_id,
-            name,
+        .map(|session_info| PrintableSession {
+            user_id: session_info.user_id,
+            name: session_info.device_name,
+            verification_status: session_info.verification_status,
         })
         .collect::<Vec<PrintableSession>>();
     if config.json {
@@ -324,7 +1296,7 @@ async fn session_list(config: SessionList, sessions_file: &SessionsFile, dirs: &
         if printable_sessions.len() > 0 {
             println!("Currently-logged-in sessions:");
             for session in printable_sessions {
-                println!("{} | {}", session.user_id, session.name) // Replace with properly-justified table-formatting in the future
+                println!("{} | {} | {}", session.user_id, session.name, session.verification_status) // Replace with properly-justified table-formatting in the future
             }
         } else {
             println!("You have no sessions currently logged in.");
@@ -334,32 +1306,51 @@ async fn session_list(config: SessionList, sessions_file: &SessionsFile, dirs: &
     Ok(())
 }
 
-async fn session_login(config: SessionLogin, sessions_file: &mut SessionsFile, dirs: &ProjectDirs) -> anyhow::Result<()> {
+async fn session_login(config: SessionLogin, sessions_file: &mut SessionsFile, dirs: &ProjectDirs, timeout: Option<Duration>, client_config: &ClientConfig) -> anyhow::Result<()> {
     let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&config.user_id));
     let normalized_user_id = add_at_to_user_id_if_applicable(&config.user_id);
     if let Ok(_) = sessions_file.get(&normalized_user_id) {
         panic!("Tried to log into account {}, but you already have a session logged into this account.", &normalized_user_id); // Replace this with real error-handling.
     }
 
-    println!("Please input password for account {}.", &normalized_user_id);
-    let password = read_password().unwrap();
+    let device_name = match (config.device_name, config.session_name) {
+        (Some(_), Some(_)) => anyhow::bail!("Session name given both positionally and via --device-name; please give only one."),
+        (device_name, session_name) => device_name.or(session_name),
+    };
+
+    let password = if config.password_stdin {
+        // Otherwise this would wait silently for input which was meant to be piped in
+        if io::stdin().is_terminal() {
+            anyhow::bail!("--password-stdin reads the password from stdin, but stdin is a terminal; pipe the password in, or leave out --password-stdin to be prompted for it.");
+        }
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        let password = password.strip_suffix('\n').map(|password| password.strip_suffix('\r').unwrap_or(password)).unwrap_or(&password).to_owned();
+        if password.is_empty() {
+            anyhow::bail!("--password-stdin was given, but stdin didn't contain a password.");
+        }
+        password
+    } else {
+        println!("Please input password for account {}.", &normalized_user_id);
+        read_password().unwrap()
+    };
     println!("Attempting login to account {}.", &normalized_user_id);
 
     let user = UserId::parse(&normalized_user_id)?;
-    let client = Client::builder().server_name(user.server_name()).sqlite_store(store_path, None).build().await?; // Is this doing the store config right?
+    let client = client_config.client_builder(&user).sqlite_store(store_path, None).build().await?; // Is this doing the store config right?
 
-    trace::first_login(&client, sessions_file, &normalized_user_id, &password, config.session_name).await?;
+    with_timeout(timeout, trace::first_login(&client, sessions_file, &normalized_user_id, &password, device_name)).await?;
 
     println!("Successfully logged into account {}.", normalized_user_id);
 
     Ok(())
 }
 
-async fn session_logout(config: SessionLogout, sessions_file: &mut SessionsFile, dirs: &ProjectDirs) -> anyhow::Result<()> {
+async fn session_logout(config: SessionLogout, sessions_file: &mut SessionsFile, dirs: &ProjectDirs, timeout: Option<Duration>, client_config: &ClientConfig, trace_config: &mut TraceConfig, trace_config_path: &Path) -> anyhow::Result<()> {
     let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&config.user_id));
     let normalized_user_id = add_at_to_user_id_if_applicable(&config.user_id);
 
-    let successful_remote_logout = match nonfirst_login(&config.user_id, sessions_file, &store_path).await {
+    let successful_remote_logout = match with_timeout(timeout, nonfirst_login(&config.user_id, sessions_file, &store_path, client_config)).await {
         Ok(client) => match client.matrix_auth().logout().await {
             Ok(_) => true,
             Err(e) => {
@@ -373,6 +1364,10 @@ async fn session_logout(config: SessionLogout, sessions_file: &mut SessionsFile,
         }
     };
     trace::logout_local(&config.user_id, sessions_file, &store_path)?;
+    if trace_config.default_user_id.as_deref() == Some(normalized_user_id.as_str()) {
+        trace_config.default_user_id = None;
+        trace_config.write(trace_config_path)?;
+    }
     if successful_remote_logout {
         println!("Successfully logged out of account {}.", normalized_user_id);
     } else {
@@ -382,55 +1377,166 @@ async fn session_logout(config: SessionLogout, sessions_file: &mut SessionsFile,
     Ok(())
 }
 
-async fn session_rename(config: SessionRename, sessions_file: &SessionsFile, dirs: &ProjectDirs) -> anyhow::Result<()> {
-    let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&config.user_id));
-    let client = nonfirst_login(&config.user_id, sessions_file, &store_path).await?;
-    trace::rename_session(&client, &config.session_name).await?;
+// Enough of the token to tell it apart from the previous one, without printing a usable credential
+fn masked_token(token: &str) -> String {
+    match token.char_indices().rev().nth(3) {
+        Some((last_four_start, _)) if token.len() > 12 => format!("...{}", &token[last_four_start..]),
+        _ => String::from("[hidden]"),
+    }
+}
+
+async fn session_refresh(config: SessionRefresh, sessions_file: &mut SessionsFile, timeout: Option<Duration>, client_config: &ClientConfig, default_user_id: Option<&str>) -> anyhow::Result<()> {
+    let user_id = user_id_or_default(config.user_id, default_user_id)?;
+    let normalized_user_id = add_at_to_user_id_if_applicable(&user_id);
+    match with_timeout(timeout, trace::refresh_session_tokens(sessions_file, &user_id, client_config)).await? {
+        TokenRefresh::NoRefreshToken => println!("Account {}'s session has no refresh token, so its access token can't be refreshed. If it's expired, run 'session logout {}' and then 'session login {}' to log in again.", normalized_user_id, normalized_user_id, normalized_user_id),
+        TokenRefresh::Refreshed { access_token, expires_in } => {
+            let lifetime = match expires_in {
+                Some(expires_in) => format!("expires in {} seconds", expires_in.as_secs()),
+                None => String::from("has no expiry"),
+            };
+            println!("Successfully refreshed account {}'s access token; the new token ({}) {}.", normalized_user_id, masked_token(&access_token), lifetime);
+        }
+    }
+
+    Ok(())
+}
+
+async fn session_rename(config: SessionRename, sessions_file: &SessionsFile, dirs: &ProjectDirs, timeout: Option<Duration>, client_config: &ClientConfig, default_user_id: Option<&str>) -> anyhow::Result<()> {
+    let (user_id, session_names) = split_optional_user_id(config.arguments, 1, default_user_id)?;
+    let session_name = &session_names[0];
+    let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&user_id));
+    let client = with_timeout(timeout, nonfirst_login(&user_id, sessions_file, &store_path, client_config)).await?;
+    trace::rename_session(&client, session_name).await?;
 
-    println!("Successfully renamed account {}'s session to '{}'.", add_at_to_user_id_if_applicable(&config.user_id), config.session_name);
+    println!("Successfully renamed account {}'s session to '{}'.", add_at_to_user_id_if_applicable(&user_id), session_name);
 
     Ok(())
 }
 
-async fn session_verify(config: SessionVerify, sessions_file: &SessionsFile, dirs: &ProjectDirs) -> anyhow::Result<()> {
-    println!("Warning: verification, although technically implemented, is currently a mess. You will need to manually ctrl-c out of the verification flow once finished.");
+fn session_set_default(config: SessionSetDefault, sessions_file: &SessionsFile, trace_config: &mut TraceConfig, trace_config_path: &Path) -> anyhow::Result<()> {
+    match config.user_id {
+        Some(user_id) => {
+            let normalized_user_id = add_at_to_user_id_if_applicable(&user_id);
+            sessions_file.get(&normalized_user_id).map_err(|e| anyhow::anyhow!(e))?;
+            trace_config.default_user_id = Some(normalized_user_id.clone());
+            trace_config.write(trace_config_path)?;
+            println!("Set default account to {}.", normalized_user_id);
+        }
+        None => {
+            trace_config.default_user_id = None;
+            trace_config.write(trace_config_path)?;
+            println!("Cleared default account.");
+        }
+    }
+
+    Ok(())
+}
+
+async fn session_verify(config: SessionVerify, sessions_file: &SessionsFile, dirs: &ProjectDirs, timeout: Option<Duration>, client_config: &ClientConfig, default_user_id: Option<&str>) -> anyhow::Result<()> {
+    let user_id = user_id_or_default(config.user_id, default_user_id)?;
+    println!("{}", styled("Warning: verification, although technically implemented, is currently a mess. You will need to manually ctrl-c out of the verification flow once finished.", TerminalStyle::Yellow));
     // Add a branch for if no incoming verification request is captured in the sync, to produce an outgoing one.
-    let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&config.user_id));
-    let client = nonfirst_login(&config.user_id, sessions_file, &store_path).await?;
+    let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&user_id));
+    let client = with_timeout(timeout, nonfirst_login(&user_id, sessions_file, &store_path, client_config)).await?;
+    if config.assume_yes {
+        println!("{}", styled("Warning: --assume-yes confirms verification without comparing the SAS decimals, so it can't detect a man-in-the-middle attack, and whoever is on the other side of the verification will be trusted. Only use it when you know by other means that the other side is your own session.", TerminalStyle::Red));
+    }
+    let assume_yes = config.assume_yes;
     let encryption = client.encryption();
-    client.add_event_handler(|event: ToDeviceKeyVerificationRequestEvent| async move {
-        let user_id = event.sender;
-        let flow_id = event.content.transaction_id;
-        match encryption.get_verification_request(&user_id, flow_id).await {
-            None => (),
-            Some(verification_request) => {
-                tokio::spawn(handle_verification_request(verification_request)); // Asynchronousness is needed to keep the sync going, which is needed for the verification flow to go through successfully
+    // The handler is called once per request, so each call gets its own handle on the client's encryption
+    client.add_event_handler(move |event: ToDeviceKeyVerificationRequestEvent| {
+        let encryption = encryption.clone();
+        async move {
+            let user_id = event.sender;
+            let flow_id = event.content.transaction_id;
+            match encryption.get_verification_request(&user_id, flow_id).await {
+                None => (),
+                Some(verification_request) => {
+                    tokio::spawn(handle_verification_request(verification_request, assume_yes)); // Asynchronousness is needed to keep the sync going, which is needed for the verification flow to go through successfully
+                }
             }
         }
     });
 
-    client.sync(SyncSettings::new().set_presence(PresenceState::Offline)).await?; // Figure out how to stop syncing once the verification is done
+    client.sync(default_sync_settings()).await?; // Figure out how to stop syncing once the verification is done
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn run() -> anyhow::Result<()> {
     let dirs = ProjectDirs::from("", "", "Trace").unwrap(); // Figure out qualifier and organization
-    let mut sessions_file = SessionsFile::open([dirs.data_local_dir(), Path::new("sessions.json")].iter().collect());
-
     let args: Args = argh::from_env();
+    // Needs no account, so it shouldn't prompt for the sessions file's passphrase
+    if let RootSubcommand::Export(Export { list_formats: true, .. }) = &args.subcommand {
+        list_formats();
+        return Ok(())
+    }
+    if let RootSubcommand::Serve(config) = &args.subcommand {
+        return trace::serve::serve(&config.directory, config.port).await
+    }
+    let sessions_file_path = match args.sessions_file {
+        Some(path) => path,
+        None => [dirs.data_local_dir(), Path::new(trace::SESSIONS_FILENAME)].iter().collect(),
+    };
+    let passphrase = if args.encrypt_sessions_file || SessionsFile::is_encrypted(&sessions_file_path) {
+        match env::var(trace::SESSIONS_PASSPHRASE_ENV_VAR) {
+            Ok(passphrase) => Some(passphrase),
+            Err(_) => {
+                eprintln!("Please input passphrase for sessions file {}.", sessions_file_path.display());
+                Some(read_password()?)
+            }
+        }
+    } else {
+        None
+    };
+    let timeout = Some(Duration::from_secs(args.timeout)).filter(|timeout| !timeout.is_zero());
+    let client_config = ClientConfig {
+        user_agent: args.user_agent,
+        homeserver_map: match &args.homeserver_map {
+            Some(path) => trace::read_homeserver_map(path)?,
+            None => HashMap::new(),
+        },
+    };
+    if let RootSubcommand::Doctor(config) = &args.subcommand {
+        return doctor(config, &sessions_file_path, passphrase, &dirs, timeout, &client_config).await
+    }
+
+    let mut sessions_file = SessionsFile::open_with_passphrase(sessions_file_path, passphrase).map_err(|e| anyhow::anyhow!(e))?;
+    let trace_config_path = dirs.data_local_dir().join(trace::CONFIG_FILENAME);
+    let mut trace_config = TraceConfig::open(&trace_config_path)?;
+    let default_user_id = trace_config.default_user_id.clone();
+
     match args.subcommand {
-        RootSubcommand::Export(config) => export(config, &sessions_file, &dirs).await?,
-        RootSubcommand::ListRooms(config) => list_rooms(config, &sessions_file, &dirs).await?,
+        RootSubcommand::Doctor(_) => unreachable!("Handled before the sessions file is opened"),
+        RootSubcommand::Export(config) => export(config, &sessions_file, &dirs, timeout, &client_config, args.limit_rate, default_user_id.as_deref()).await?,
+        RootSubcommand::ListRooms(config) => list_rooms(config, &sessions_file, &dirs, timeout, &client_config, default_user_id.as_deref()).await?,
+        RootSubcommand::Profile(config) => profile(config, &sessions_file, &dirs, timeout, &client_config, default_user_id.as_deref()).await?,
+        RootSubcommand::RoomInfo(config) => room_info(config, &sessions_file, &dirs, timeout, &client_config, default_user_id.as_deref()).await?,
+        RootSubcommand::Serve(_) => unreachable!("Handled before the sessions file is opened"),
         RootSubcommand::Session(s) => match s.subcommand {
-            SessionSubcommand::List(config) => session_list(config, &sessions_file, &dirs).await?,
-            SessionSubcommand::Login(config) => session_login(config, &mut sessions_file, &dirs).await?,
-            SessionSubcommand::Logout(config) => session_logout(config, &mut sessions_file, &dirs).await?,
-            SessionSubcommand::Rename(config) => session_rename(config, &sessions_file, &dirs).await?,
-            SessionSubcommand::Verify(config) => session_verify(config, &sessions_file, &dirs).await?,
+            SessionSubcommand::ExportSession(config) => session_export(config, &sessions_file, &dirs).await?,
+            SessionSubcommand::ImportSession(config) => session_import(config, &mut sessions_file, timeout, &client_config).await?,
+            SessionSubcommand::List(config) => session_list(config, &sessions_file, &dirs, timeout, &client_config).await?,
+            SessionSubcommand::Login(config) => session_login(config, &mut sessions_file, &dirs, timeout, &client_config).await?,
+            SessionSubcommand::Logout(config) => session_logout(config, &mut sessions_file, &dirs, timeout, &client_config, &mut trace_config, &trace_config_path).await?,
+            SessionSubcommand::Refresh(config) => session_refresh(config, &mut sessions_file, timeout, &client_config, default_user_id.as_deref()).await?,
+            SessionSubcommand::Rename(config) => session_rename(config, &sessions_file, &dirs, timeout, &client_config, default_user_id.as_deref()).await?,
+            SessionSubcommand::SetDefault(config) => session_set_default(config, &sessions_file, &mut trace_config, &trace_config_path)?,
+            SessionSubcommand::Verify(config) => session_verify(config, &sessions_file, &dirs, timeout, &client_config, default_user_id.as_deref()).await?,
         }
     };
 
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(exit_code_for_error(&e))
+        }
+    }
+}
@@ -1,46 +1,861 @@
 use std::collections::{
+    BTreeMap,
+    BTreeSet,
     HashMap,
     HashSet,
 };
 use std::fs::{
     create_dir_all,
+    read_to_string,
     write,
+    File,
+    OpenOptions,
 };
-use std::path::PathBuf;
+use std::future::Future;
+use std::io::{
+    self,
+    BufWriter,
+    Write,
+};
+use std::ops::{
+    Bound,
+    Range,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::{
+    atomic::{
+        AtomicBool,
+        Ordering,
+    },
+    Arc,
+};
+use std::time::Duration;
 
 use crate::{
     get_rooms_info,
+    html::{
+        messages_to_html,
+        text_to_html,
+    },
+    media::{
+        attachment_media_filename,
+        download_media,
+        inline_image_data_uri,
+        media_attachment,
+        remote_media_link,
+        MediaAttachment,
+        MediaLinkKind,
+        MediaVariant,
+        MEDIA_DIRECTORY_NAME,
+    },
+    rate_limit::{
+        self,
+        RateLimiter,
+    },
+    run_log::{
+        self,
+        RunLog,
+    },
+    sqlite::{
+        write_sqlite_export,
+        SQLITE_EXPORT_FILENAME,
+    },
     RoomWithCachedInfo,
+    TraceError,
 };
 
-use chrono::{DateTime, SecondsFormat};
+use chrono::{
+    format::{
+        Item,
+        StrftimeItems,
+    },
+    DateTime,
+    Local,
+    NaiveDate,
+    SecondsFormat,
+    Utc,
+};
 use matrix_sdk::{
-    deserialized_responses::TimelineEvent,
-    room::MessagesOptions,
+    deserialized_responses::{
+        RawAnySyncOrStrippedState,
+        TimelineEvent,
+    },
+    room::{
+        MessagesOptions,
+        Receipts,
+    },
     ruma::{
+        api::client::{
+            context::get_context,
+            error::ErrorKind,
+            state::get_state_events,
+        },
         events::{
-            room::message::MessageType,
+            receipt::{
+                ReceiptThread,
+                ReceiptType,
+            },
+            room::{
+                encrypted::{
+                    EncryptedEventScheme,
+                    OriginalSyncRoomEncryptedEvent,
+                },
+                history_visibility::HistoryVisibility,
+                message::MessageType,
+            },
             AnyMessageLikeEvent,
+            AnyStateEvent,
             AnyTimelineEvent,
+            StateEventType,
         },
+        serde::Raw,
+        EventId,
+        OwnedEventId,
+        RoomAliasId,
+        RoomId,
+        UInt,
         UserId
     },
     Client,
+    Room,
+    RoomMemberships,
+};
+use encoding_rs::Encoding;
+use futures::future::join_all;
+use hmac::{
+    Hmac,
+    Mac,
+};
+use rand::RngCore;
+use regex::Regex;
+use serde::{
+    Deserialize,
+    Serialize,
 };
+use serde_json::json;
+use sha2::Sha256;
+use tokio::sync::Semaphore;
+
+///////////////////
+//   Constants   //
+///////////////////
+
+pub const STDOUT_SENTINEL: &str = "-";
+pub const DEFAULT_BATCH_SIZE: u16 = 1000;
+pub const DEFAULT_MEDIA_CONCURRENCY: usize = 4;
+pub const DEFAULT_DATESTAMP_FORMAT: &str = "%Y-%m-%d";
+pub const DEFAULT_PERMALINK_BASE: &str = "https://matrix.to/#/";
+// Beyond this many levels of quoted ancestry, flattened reply chains are cut off, so that a long back-and-forth doesn't repeat the whole conversation above every message
+const FLATTENED_REPLY_DEPTH_LIMIT: usize = 5;
+// Room for a split export's part label, which is only known once the parts have been counted
+const PART_LABEL_ALLOWANCE: usize = 64;
+// As recommended by the spec's guidance on routing room permalinks
+const PERMALINK_VIA_SERVER_COUNT: usize = 3;
+const ROOM_EXPORT_STATE_EXTENSION: &str = "trace-state.json";
+const SINGLE_FILE_EXPORT_FILENAME: &str = "Trace export";
+// Long enough to be effectively unique within a room, while keeping txt lines readable
+const TXT_SHORT_EVENT_ID_LENGTH: usize = 12;
 
 ///////////////
 //   Types   //
 ///////////////
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ExportOutputFormat {
+    Html,
     Json,
+    /// Rather than a file per room, every room's events are upserted into a single SQLite database (`Trace export.db`) in the output directory, with tables of rooms, senders, events, and reactions.
+    Sqlite,
     Txt,
 }
 
+impl ExportOutputFormat {
+    /// Every format there is, in the order they're listed to users.
+    pub const VARIANTS: &'static [Self] = &[Self::Html, Self::Json, Self::Sqlite, Self::Txt];
+    /// Every file-per-room format, e.g. for exporting to all of them at once. SQLite, which collects every room into one database, has to be requested explicitly.
+    pub const ALL: &'static [Self] = &[Self::Html, Self::Json, Self::Txt];
+
+    /// The names the format can be requested by (case-insensitively, and optionally with a leading `.`), starting with its canonical name.
+    pub fn specifiers(&self) -> &'static [&'static str] {
+        match self {
+            Self::Html => &["html"],
+            Self::Json => &["json"],
+            Self::Sqlite => &["sqlite", "db"],
+            Self::Txt => &["txt"],
+        }
+    }
+
+    /// The extension of the files the format is written to.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Json => "json",
+            Self::Sqlite => "db",
+            Self::Txt => "txt",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Html => "a standalone web page per room, with a table of contents by date",
+            Self::Json => "trace's stable event schema (or events as received, with --raw-json), a file per room",
+            Self::Sqlite => "a single database of every exported room, updated in place on re-export",
+            Self::Txt => "plain text, a line per event",
+        }
+    }
+
+    pub fn from_specifier(specifier: &str) -> Option<Self> {
+        let specifier = specifier.to_lowercase();
+        let specifier = specifier.strip_prefix('.').unwrap_or(&specifier);
+        Self::VARIANTS.iter().copied().find(|format| format.specifiers().contains(&specifier))
+    }
+}
+
+/// What to do with characters which the txt export's output encoding can't represent.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnmappableCharacterPolicy {
+    /// Replace them with HTML-style numeric character references (e.g. `&#128512;`), so that they can still be recovered from the export.
+    #[default]
+    Replace,
+    /// Fail the room's export.
+    Error,
+}
+
+impl UnmappableCharacterPolicy {
+    pub fn from_specifier(specifier: &str) -> Option<Self> {
+        match specifier.to_lowercase().as_ref() {
+            "replace" => Some(Self::Replace),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Format of the reactions summary written in place of a full export, if requested.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ReactionsSummaryFormat {
+    Json,
+    Csv,
+}
+
+impl ReactionsSummaryFormat {
+    pub fn from_specifier(specifier: &str) -> Option<Self> {
+        match specifier.to_lowercase().as_ref() {
+            "json" => Some(Self::Json),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// What to do when an export file would be written over an existing one. Media files are never overwritten either way, since they're stored under their (immutable) mxc URIs, and incremental exports' state files are always updated.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExistingFilePolicy {
+    #[default]
+    Overwrite,
+    /// Fail the room's export with `TraceError::OutputIo` rather than replacing the existing file. SQLite exports are exempt, since their database is meant to be added to across runs.
+    NoClobber,
+}
+
+/// A library consumer's hook for rewriting each event's JSON before it's exported, e.g. to redact personal information from message bodies. See `ExportOptions::event_transform`.
+pub type EventTransform = Box<dyn Fn(&mut serde_json::Value) + Send + Sync>;
+
+#[derive(Default)]
+pub struct ExportOptions {
+    /// Directory to write exports to; `None` means the current directory, and `-` means stdout.
+    pub output_path: Option<PathBuf>,
+    pub formats: HashSet<ExportOutputFormat>,
+    /// strftime-style format of a run date/time to append to every output filename.
+    pub datestamp_format: Option<String>,
+    /// Only fetch messages newer than the previous such run's, tracked in a per-room state file, into a separate file.
+    pub since_last_export: bool,
+    /// Move each room's read receipt and fully-read marker to its latest exported event.
+    pub mark_read: bool,
+    /// Download attachments into a `media` directory within the output directory.
+    pub download_media: bool,
+    pub media_variant: MediaVariant,
+    /// Embed downloaded images of at most this many bytes in the html export as data URIs.
+    pub inline_image_max_size: Option<u64>,
+    /// How attachments are linked to from the exports.
+    pub media_link_kind: MediaLinkKind,
+    /// Maximum number of concurrent attachment downloads; `DEFAULT_MEDIA_CONCURRENCY` if unset.
+    pub media_concurrency: Option<usize>,
+    /// Export every joined room, in addition to the requested ones.
+    pub all_rooms: bool,
+    /// Only add rooms with at least this many joined members to `all_rooms`.
+    pub min_members: Option<u64>,
+    /// Only add rooms with at most this many joined members to `all_rooms`.
+    pub max_members: Option<u64>,
+    /// Only add encrypted (`Some(true)`) or unencrypted (`Some(false)`) rooms to `all_rooms`.
+    pub encrypted: Option<bool>,
+    /// Only add rooms missing from the record at `exported_rooms_path` to `all_rooms`.
+    pub only_new_rooms: bool,
+    /// The account's record of rooms exported by `all_rooms` runs (see `user_id_to_exported_rooms_path`).
+    pub exported_rooms_path: Option<PathBuf>,
+    /// Also export every joined room whose name matches this regex in full.
+    pub name_pattern: Option<String>,
+    /// Accept pending invites to explicitly-requested rooms before exporting.
+    pub accept_invites: bool,
+    /// Identifiers of rooms to leave out; a name matching several rooms excludes all of them.
+    pub exclude_rooms: Vec<String>,
+    /// Fail with `TraceError::MissingDecryptionKeys` if any messages couldn't be decrypted.
+    pub fail_on_decrypt_error: bool,
+    /// Further clients whose view of each room is merged into the main client's, deduplicated by event ID.
+    pub additional_clients: Vec<Client>,
+    /// Export events exactly as received in json, rather than as `ExportedEvent`s.
+    pub raw_json: bool,
+    /// Also write each room's events verbatim to a `.raw.json` file.
+    pub raw_dump: bool,
+    /// Write json on one line rather than pretty-printed.
+    pub json_compact: bool,
+    /// Write each room's json export message by message, rather than formatting it in full first.
+    pub stream_json: bool,
+    /// Replace user IDs with salted hashes of themselves in every format.
+    pub sender_hasher: Option<SenderHasher>,
+    /// Maximum rate of `/messages` and media requests, in requests per second.
+    pub rate_limit: Option<f64>,
+    /// Record each user's latest read receipt in the json export's header.
+    pub include_read_receipts: bool,
+    /// Write a summary of each room's metadata rather than its messages.
+    pub summary_only: bool,
+    /// Write a tally of each room's reactions, in this format, rather than its messages.
+    pub reactions_summary: Option<ReactionsSummaryFormat>,
+    /// Write each room's complete current state to json rather than its messages.
+    pub state_only: bool,
+    /// Print each room's message count rather than writing anything.
+    pub count_only: bool,
+    /// Also write a list of each room's threads to a ` [threads]` json file.
+    pub thread_summary: bool,
+    /// Mark edited messages in the txt export with the time of their latest edit.
+    pub mark_edits_in_txt: bool,
+    /// Describe state events in the txt and html exports, rather than leaving placeholders.
+    pub render_state: bool,
+    /// Give every event a permalink under this base, e.g. `DEFAULT_PERMALINK_BASE`.
+    pub permalink_base: Option<String>,
+    /// Quote each reply's chain of replied-to messages inline in the txt and html exports.
+    pub flatten_replies: bool,
+    /// Show senders with the display names they had when each message was sent.
+    pub display_names_at_event_time: bool,
+    /// Encoding to write txt exports in, rather than UTF-8.
+    pub txt_encoding: Option<&'static Encoding>,
+    pub unmappable_character_policy: UnmappableCharacterPolicy,
+    /// Prefix each message in the txt export with the start of its event ID.
+    pub show_event_ids_in_txt: bool,
+    /// Only export messages with attachments, and stickers.
+    pub media_only: bool,
+    /// Leave out events whose sender's user ID matches this regex in full.
+    pub exclude_sender_pattern: Option<String>,
+    /// Called on each event's JSON after filtering and before anything else sees it.
+    pub event_transform: Option<EventTransform>,
+    /// Split txt and json exports bigger than this many bytes into numbered parts at message boundaries.
+    pub max_file_size: Option<u64>,
+    /// Split each room's txt export into a file per (UTC) day.
+    pub split_txt_by_day: bool,
+    /// Flag consecutive events further apart than this as possible gaps in history.
+    pub gap_threshold: Option<Duration>,
+    /// Text placed at the top of every txt and html export file.
+    pub header_text: Option<String>,
+    /// Text placed at the bottom of every txt and html export file.
+    pub footer_text: Option<String>,
+    /// Write no files for rooms with no messages to export.
+    pub skip_empty: bool,
+    /// Only export this event and up to `context_lines` events either side of it.
+    pub context_event_id: Option<OwnedEventId>,
+    pub context_lines: u16,
+    /// Collect every room's json export into a single `Trace export.json`.
+    pub single_file: bool,
+    /// `/messages` token to start paginating from, rather than the start of the room.
+    pub from_token: Option<String>,
+    /// Number of events to request per `/messages` page; `DEFAULT_BATCH_SIZE` if unset.
+    pub batch_size: Option<u16>,
+    pub existing_file_policy: ExistingFilePolicy,
+    /// Log to record the export's progress to as it happens.
+    pub run_log: Option<RunLog>,
+    /// Storing `true` here stops the export gracefully, failing with `TraceError::Interrupted`.
+    pub interrupt: Option<Arc<AtomicBool>>,
+}
+
+impl ExportOptions {
+    /// Checks for invalid values and incompatible combinations of options, which `export` would otherwise only report once it's been called, e.g. after logging in. The number of rooms requested is checked too, since some options require exactly one.
+    pub fn validate(&self, rooms: &[String]) -> anyhow::Result<()> {
+        let to_stdout = self.to_stdout();
+        let download_media = self.download_media;
+        let reactions_summary = self.reactions_summary.is_some();
+        let context_export = self.context_event_id.is_some();
+        let additional_clients = !self.additional_clients.is_empty();
+        let one_room = !self.all_rooms && rooms.len() == 1;
+        if self.single_file && (self.formats.len() != 1 || !self.formats.contains(&ExportOutputFormat::Json) || self.summary_only) {
+            anyhow::bail!("Single-file exports are json-only, so they require json to be the only format, and can't be combined with summary-only exports.");
+        }
+        if to_stdout && !self.single_file && (!one_room || self.formats.len() != 1) {
+            anyhow::bail!("Exporting to stdout requires exactly one room and one format, but got {} rooms and {} formats.", rooms.len(), self.formats.len());
+        }
+        if self.split_txt_by_day && to_stdout {
+            anyhow::bail!("Splitting txt exports by day writes several files, so it can't be combined with exporting to stdout.");
+        }
+        if self.max_file_size.is_some() && (to_stdout || self.single_file) {
+            anyhow::bail!("Exports can only be split into several files when they're written to an output directory one room at a time, so --max-file-size can't be combined with stdout or single-file exports.");
+        }
+        if self.stream_json && (self.max_file_size.is_some() || self.single_file) {
+            anyhow::bail!("Split and single-file json exports need every message formatted before they can be written, so they can't be streamed.");
+        }
+        if self.formats.contains(&ExportOutputFormat::Sqlite) && (to_stdout || self.summary_only) {
+            anyhow::bail!("SQLite exports need an output directory to keep their database in, so they can't be written to stdout, and can't be combined with summary-only exports.");
+        }
+        if self.inline_image_max_size.is_some() && !(download_media && self.formats.contains(&ExportOutputFormat::Html)) {
+            anyhow::bail!("Images can only be embedded in html exports, and only once they've been downloaded, so inlining them requires the html format and media downloads.");
+        }
+        if to_stdout && download_media {
+            anyhow::bail!("Media can't be downloaded when exporting to stdout, since it needs an output directory to be saved to.");
+        }
+        if self.since_last_export && additional_clients {
+            anyhow::bail!("Incremental exports can't be combined with additional accounts, since pagination state can't be shared between accounts.");
+        }
+        if to_stdout && self.since_last_export {
+            anyhow::bail!("Incremental exports can't be written to stdout, since they need an output directory to keep their state in.");
+        }
+        if context_export && (!one_room || self.since_last_export || additional_clients || self.summary_only) {
+            anyhow::bail!("Context exports require exactly one room, and can't be combined with --all, incremental exports, additional accounts, or summary-only exports.");
+        }
+        if let Some(from_token) = &self.from_token {
+            if from_token.trim().is_empty() {
+                anyhow::bail!("Pagination token can't be empty.");
+            }
+            if !one_room || self.since_last_export || context_export || additional_clients || self.summary_only {
+                anyhow::bail!("Pagination tokens are specific to one room, so exporting from one requires exactly one room, and can't be combined with --all, incremental exports, context exports, additional accounts, or summary-only exports.");
+            }
+        }
+        if self.raw_dump && (to_stdout || self.summary_only || self.sender_hasher.is_some() || self.event_transform.is_some()) {
+            anyhow::bail!("Raw dumps are written verbatim to their own file, so they can't be written to stdout, combined with summary-only exports, or anonymized or otherwise transformed.");
+        }
+        if reactions_summary && (self.summary_only || self.since_last_export || self.single_file || download_media) {
+            anyhow::bail!("Reactions summaries replace the usual export, so they can't be combined with summary-only, incremental, or single-file exports, or media downloads.");
+        }
+        if self.summary_only && (self.since_last_export || download_media || self.mark_read) {
+            anyhow::bail!("Summary-only exports don't fetch messages, so they can't be combined with incremental exports, media downloads, or marking rooms read.");
+        }
+        if self.state_only && (self.summary_only || reactions_summary || self.since_last_export || self.single_file || download_media || self.mark_read || self.raw_dump || context_export) {
+            anyhow::bail!("State-only exports don't fetch messages, so they can't be combined with other kinds of summary, incremental, context, single-file, or raw exports, media downloads, or marking rooms read.");
+        }
+        if self.count_only && (self.summary_only || self.state_only || reactions_summary || self.single_file || download_media || self.mark_read || self.raw_dump) {
+            anyhow::bail!("Message counts don't write anything, so they can't be combined with other kinds of summary, single-file, or raw exports, media downloads, or marking rooms read.");
+        }
+        if self.thread_summary && (to_stdout || self.summary_only || self.state_only || self.count_only || reactions_summary) {
+            anyhow::bail!("Thread summaries are written to their own file alongside each room's export, so they can't be written to stdout, or combined with exports which replace the usual one.");
+        }
+        if let Some(pattern) = &self.name_pattern {
+            if let Err(e) = full_match_regex(pattern) {
+                anyhow::bail!("Invalid name pattern '{}': {}", pattern, e);
+            }
+        }
+        if let Some(pattern) = &self.exclude_sender_pattern {
+            if let Err(e) = full_match_regex(pattern) {
+                anyhow::bail!("Invalid sender pattern '{}': {}", pattern, e);
+            }
+        }
+        if !self.all_rooms && (self.min_members.is_some() || self.max_members.is_some() || self.encrypted.is_some() || self.only_new_rooms) {
+            anyhow::bail!("Member-count, encryption, and new-room filters only apply to --all, which wasn't requested.");
+        }
+        if self.only_new_rooms && !self.records_exported_rooms() {
+            anyhow::bail!("Only full exports to an output directory keep a record of the rooms they've exported, so --only-new-rooms can't be combined with stdout, summary, count, or context exports.");
+        }
+        if let (Some(min_members), Some(max_members)) = (self.min_members, self.max_members) {
+            if min_members > max_members {
+                anyhow::bail!("Minimum member count ({}) is greater than maximum member count ({}).", min_members, max_members);
+            }
+        }
+        if self.media_concurrency == Some(0) {
+            anyhow::bail!("Media concurrency must be at least 1.");
+        }
+        if self.batch_size == Some(0) {
+            anyhow::bail!("Batch size must be at least 1.");
+        }
+        if self.rate_limit.is_some_and(|rate_limit| !(rate_limit.is_finite() && rate_limit > 0.0)) {
+            anyhow::bail!("Rate limit must be a positive number of requests per second.");
+        }
+        if let Some(format) = &self.datestamp_format {
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                anyhow::bail!("Invalid datestamp format '{}'.", format);
+            }
+        }
+        Ok(())
+    }
+
+    fn to_stdout(&self) -> bool {
+        self.output_path.as_ref().is_some_and(|path| path == Path::new(STDOUT_SENTINEL))
+    }
+
+    // Only full exports of all rooms to an output directory count towards the record of exported rooms
+    fn records_exported_rooms(&self) -> bool {
+        let full_export = !self.to_stdout() && !self.summary_only && !self.state_only && !self.count_only && self.reactions_summary.is_none() && self.context_event_id.is_none();
+        self.all_rooms && full_export && self.exported_rooms_path.is_some()
+    }
+}
+
+// Keyed by room ID rather than by export filename, so that it survives room renames
+#[derive(Deserialize, Serialize)]
+struct RoomExportState {
+    room_id: String,
+    last_export_time: String,
+    end_token: Option<String>,
+    // Events up to and including this one are skipped on resumption, since resuming from end_token may re-fetch some already-exported events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_event_id: Option<String>,
+}
+
+/// The json export's representation of an event. Unless raw json output is requested, this is the stable output schema, independent of matrix-sdk's internal representations.
+#[derive(Serialize)]
+pub struct ExportedEvent {
+    pub event_id: String,
+    pub event_type: String,
+    pub sender: String,
+    /// RFC 3339, in UTC
+    pub timestamp: String,
+    pub origin_server_ts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_key: Option<String>,
+    pub redacted: bool,
+    pub encryption: ExportedEncryptionStatus,
+    /// Present for `m.room.message` events which haven't been redacted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<ExportedMessage>,
+    /// Present for every other event, passing its content through as-is. For undecrypted events, this is the complete ciphertext envelope (algorithm, ciphertext, and megolm session and sender details), so they can still be decrypted later if their keys turn up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<serde_json::Value>,
+    /// For state events, the content of the state event this one replaced, if the server provided it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_content: Option<serde_json::Value>,
+    /// Present if permalinks were requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permalink: Option<String>,
+    /// Present for VoIP call events (`m.call.invite`, `m.call.answer`, `m.call.reject`, and `m.call.hangup`), alongside their content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub call: Option<ExportedCall>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportedEncryptionStatus {
+    /// Sent in the clear.
+    Unencrypted,
+    /// Sent encrypted, and decrypted by trace; the rest of the event describes the decrypted event.
+    Decrypted,
+    /// Sent encrypted, and couldn't be decrypted (generally for lack of the megolm session's keys).
+    Undecrypted,
+}
+
+#[derive(Serialize)]
+pub struct ExportedMessage {
+    pub msgtype: String,
+    pub body: String,
+    /// HTML, if the sender provided a formatted version of the body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatted_body: Option<String>,
+    pub relations: ExportedRelations,
+}
+
+#[derive(Default, Serialize)]
+pub struct ExportedRelations {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_root: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ExportedCall {
+    pub call_id: String,
+    /// `invite`, `answer`, `reject`, or `hangup`
+    pub action: String,
+    /// For invites: the user being called, if the invite was addressed to one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invitee: Option<String>,
+    /// For invites: whether the offer includes video.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video: Option<bool>,
+    /// For rejections and hangups: the reason given, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// For hangups: milliseconds since the call's invite, if the invite is among the exported events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+impl ExportedCall {
+    // Takes the invitee's string representation separately, since that depends on the export's formatting
+    pub(crate) fn description(&self, invitee: Option<&str>) -> String {
+        match self.action.as_str() {
+            "invite" => format!("started a {} call{}", if self.video == Some(true) { "video" } else { "voice" }, invitee.map(|invitee| format!(" with {}", invitee)).unwrap_or_default()),
+            "answer" => String::from("answered the call"),
+            "reject" => String::from("declined the call"),
+            _ => {
+                let duration = self.duration_ms.map(|duration_ms| format!(" after {}", format_duration(duration_ms))).unwrap_or_default();
+                // A plain hangup is the overwhelmingly common case, so only other reasons are worth noting
+                let reason = self.reason.as_deref().filter(|reason| *reason != "user_hangup").map(|reason| format!(" ({})", reason)).unwrap_or_default();
+                format!("ended the call{}{}", duration, reason)
+            }
+        }
+    }
+}
+
+/// Replaces user IDs with a keyed hash of them: deterministic within a run (or across runs sharing a salt), so that a user's events can still be correlated across rooms, but irreversible without the salt.
+pub struct SenderHasher {
+    salt: Vec<u8>,
+}
+
+impl SenderHasher {
+    pub fn new(salt: Vec<u8>) -> Self {
+        Self {
+            salt,
+        }
+    }
+
+    pub fn with_random_salt() -> Self {
+        let mut salt = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self::new(salt)
+    }
+
+    pub fn hash(&self, user_id: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.salt).expect("HMAC accepts keys of any length.");
+        mac.update(user_id.as_bytes());
+        let hash_hex = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        format!("user-{}", &hash_hex[..16])
+    }
+}
+
+// Everything the formatters need to know about a single room's export beyond its events
+pub(crate) struct RoomExportContext<'a> {
+    pub room_info: &'a RoomWithCachedInfo,
+    pub warnings: Vec<String>,
+    // Links to attachments, keyed by their events' IDs
+    pub media_links: BTreeMap<String, String>,
+    // Data URIs of images to embed in the html export, keyed by their events' IDs
+    pub inline_images: HashMap<String, String>,
+    read_receipts: BTreeMap<String, Vec<ExportedReadReceipt>>,
+    pub permalinks: Option<RoomPermalinks>,
+    pub interrupted: bool,
+    pub pinned: Vec<PinnedEvent>,
+    // The timestamp of the first event after each possible gap in history
+    gap_end_timestamps: BTreeSet<u64>,
+    // Event ID to the sender's display name as of that event, where it's known
+    display_names_at_event_time: HashMap<String, Option<String>>,
+}
+
+impl RoomExportContext<'_> {
+    // Whether a possible gap in history ends between the previous rendered event (if any) and this one, so that a gap is still marked when the event right after it was filtered out
+    pub fn gap_precedes(&self, previous_timestamp: Option<u64>, timestamp: u64) -> bool {
+        let lower_bound = match previous_timestamp {
+            Some(previous_timestamp) => Bound::Excluded(previous_timestamp),
+            None => Bound::Unbounded,
+        };
+        self.gap_end_timestamps.range((lower_bound, Bound::Included(timestamp))).next().is_some()
+    }
+}
+
+// A pinned event, taken from the export if it's there, or else fetched from the server individually. Fetching can fail (e.g. if the event has since been purged, or isn't visible to this account), in which case only its ID is known.
+pub(crate) struct PinnedEvent {
+    pub event_id: String,
+    pub event: Option<TimelineEvent>,
+    pub in_export: bool,
+}
+
+// Everything in a room's event permalinks except the event ID, which is computed once per room
+pub(crate) struct RoomPermalinks {
+    room_prefix: String,
+    via_query: String,
+}
+
+impl RoomPermalinks {
+    pub(crate) fn event_permalink(&self, event_id: &str) -> String {
+        format!("{}/{}{}", self.room_prefix, percent_encode_permalink_component(event_id), self.via_query)
+    }
+}
+
+// Run-wide settings affecting how events are formatted
+pub(crate) struct FormattingOptions {
+    raw_json: bool,
+    pub sender_hasher: Option<SenderHasher>,
+    mark_edits_in_txt: bool,
+    show_event_ids_in_txt: bool,
+    pub render_state: bool,
+    pub flatten_replies: bool,
+    json_compact: bool,
+    pub header_text: Option<String>,
+    pub footer_text: Option<String>,
+}
+
+// Run-wide settings affecting which events are collected for each room
+struct EventCollectionOptions<'a> {
+    context_event_id: Option<&'a EventId>,
+    context_lines: u16,
+    from_token: Option<&'a str>,
+    batch_size: u16,
+    rate_limiter: Option<&'a RateLimiter>,
+    interrupt: Option<&'a AtomicBool>,
+    additional_clients: &'a [Client],
+    gap_threshold: Option<Duration>,
+    display_names_at_event_time: bool,
+    media_only: bool,
+    exclude_sender_regex: Option<&'a Regex>,
+    event_transform: Option<&'a EventTransform>,
+}
+
+// A room's events, as fetched, merged, and filtered for export, along with what was found out about them along the way
+struct CollectedEvents {
+    events: Vec<TimelineEvent>,
+    warnings: Vec<String>,
+    gaps: Vec<(u64, u64)>,
+    display_names_at_event_time: HashMap<String, Option<String>>,
+    undecryptable_megolm_session_ids: BTreeSet<String>,
+    last_end_token: Option<String>,
+    last_event_id: Option<String>,
+    interrupted: bool,
+}
+
+// Run-wide settings affecting how each room's export files are formatted and written
+struct RoomOutputOptions<'a> {
+    formats: &'a HashSet<ExportOutputFormat>,
+    formatting: &'a FormattingOptions,
+    to_stdout: bool,
+    existing_file_policy: ExistingFilePolicy,
+    raw_dump: bool,
+    thread_summary: bool,
+    single_file: bool,
+    stream_json: bool,
+    max_file_size: Option<u64>,
+    split_txt_by_day: bool,
+    txt_encoding: Option<&'static Encoding>,
+    unmappable_character_policy: UnmappableCharacterPolicy,
+}
+
+#[derive(Serialize)]
+struct JsonExportRoomHeader {
+    id: String,
+    name: Option<String>,
+    canonical_alias: Option<String>,
+    history_visibility: String,
+    warnings: Vec<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    interrupted: bool,
+    // Event ID to path of the downloaded media, relative to the output directory
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    media: BTreeMap<String, String>,
+    // Event ID to the users whose read receipts are on that event
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    read_receipts: BTreeMap<String, Vec<ExportedReadReceipt>>,
+    // In the order the room lists them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pinned: Vec<ExportedPinnedEvent>,
+}
+
+#[derive(Serialize)]
+struct ExportedPinnedEvent {
+    event_id: String,
+    // Whether the event is also among the export's messages, rather than having been fetched separately
+    in_export: bool,
+    // Formatted as the export's messages are; absent if the event couldn't be fetched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Serialize)]
+struct ExportedReadReceipt {
+    user_id: String,
+    timestamp: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RoomSummary {
+    pub id: String,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub canonical_alias: Option<String>,
+    pub alt_aliases: Vec<String>,
+    pub joined_member_count: u64,
+    pub encrypted: bool,
+    pub history_visibility: String,
+    pub creation_event: Option<serde_json::Value>,
+    pub power_levels: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct JsonExport {
+    room: JsonExportRoomHeader,
+    messages: Vec<serde_json::Value>,
+}
+
+// One of the files a json export is split into, when it's too big for one; each is a complete export in itself, just with only some of the messages
+#[derive(Serialize)]
+struct JsonExportPart<'a> {
+    room: &'a JsonExportRoomHeader,
+    part: usize,
+    part_count: usize,
+    messages: &'a [serde_json::Value],
+}
+
+// What pagination fetched, and why it stopped, if not for reaching the end of the room
+struct PaginatedEvents<T> {
+    events: Vec<T>,
+    end_token: Option<String>,
+    interrupted: bool,
+    // Whether the server handed back the token it was given along with more events, so that continuing would only have fetched the same page again
+    stuck: bool,
+}
+
+// One row of a reactions summary
+#[derive(Serialize)]
+struct ReactionTally {
+    event_id: String,
+    sender: Option<String>,
+    reaction: String,
+    count: usize,
+}
+
+// One entry of a thread summary
+#[derive(Serialize)]
+struct ThreadSummary {
+    root_event_id: String,
+    root_sender: Option<String>,
+    reply_count: usize,
+    latest_reply_event_id: String,
+    latest_reply_sender: Option<String>,
+    latest_reply_timestamp: String,
+}
+
+// Kept as separate text for each event, so that the export can be split between files at event boundaries
+struct TxtExport {
+    // The user's header and footer text, if any, which begin and end every part
+    custom_header: String,
+    custom_footer: String,
+    // Notes and pinned messages, repeated at the top of every part
+    header: String,
+    events: Vec<String>,
+    // The (UTC) date of each of `events`, where known
+    event_dates: Vec<Option<NaiveDate>>,
+    footer: &'static str,
+}
+
 enum RoomIndexRetrievalError {
     MultipleRoomsWithSpecifiedName(Vec<String>),
     NoRoomsWithSpecifiedName,
+    /// The identifier is a well-formed room ID or alias, just not of any room the user has joined, so it's their membership rather than the identifier that's the problem.
+    NotJoined,
+}
+
+impl RoomIndexRetrievalError {
+    fn description(&self, user_id: &UserId, room_identifier: &str) -> String {
+        match self {
+            Self::MultipleRoomsWithSpecifiedName(room_ids) => format!("Found more than one room accessible to {} with name {}. Room IDs: {:?}", user_id, room_identifier, room_ids),
+            Self::NoRoomsWithSpecifiedName => format!("Couldn't find any rooms accessible to {} with name {}.", user_id, room_identifier),
+            Self::NotJoined => format!("{} isn't a member of room {}. Join it first, or export it with an account which is a member.", user_id, room_identifier),
+        }
+    }
 }
 
 //////////////
@@ -57,6 +872,7 @@ fn get_room_index_by_identifier(rooms_info: &Vec<RoomWithCachedInfo>, identifier
     } else {
         let name_matches = rooms_info.iter().filter(|room_info| room_info.name.as_ref().is_some_and(|name| name == identifier)).collect::<Vec<&RoomWithCachedInfo>>();
         match name_matches.len() {
+            0 if RoomId::parse(identifier).is_ok() || RoomAliasId::parse(identifier).is_ok() => Err(RoomIndexRetrievalError::NotJoined),
             0 => Err(RoomIndexRetrievalError::NoRoomsWithSpecifiedName),
             1 => Ok(rooms_info.iter().position(|room_info| room_info.name.as_ref().is_some_and(|name| name  == identifier)).unwrap()),
             _ => Err(RoomIndexRetrievalError::MultipleRoomsWithSpecifiedName(name_matches.iter().map(|room_info| room_info.id.to_string()).collect())),
@@ -64,6 +880,31 @@ fn get_room_index_by_identifier(rooms_info: &Vec<RoomWithCachedInfo>, identifier
     }
 }
 
+// Joining a room is visible to its other members, so only rooms the user named are joined, never every pending invite
+async fn accept_requested_invites(client: &Client, requested_rooms: &[String], run_log: Option<&RunLog>) -> anyhow::Result<()> {
+    for room in client.invited_rooms() {
+        let canonical_alias = room.canonical_alias();
+        let name = room.name();
+        let is_requested = requested_rooms.iter().any(|identifier| room.room_id().as_str() == identifier
+            || canonical_alias.as_ref().is_some_and(|alias| alias.as_str() == identifier)
+            || name.as_ref().is_some_and(|name| name == identifier));
+        if is_requested {
+            eprintln!("Accepting invite to room {}.", room.room_id());
+            room.join().await?;
+            run_log::record(run_log, "invite_accepted", json!({ "room_id": room.room_id().as_str() }));
+        }
+    }
+
+    Ok(())
+}
+
+fn room_matches_identifier(room_info: &RoomWithCachedInfo, identifier: &str) -> bool {
+    &room_info.id == identifier
+        || room_info.canonical_alias.as_ref().is_some_and(|alias| alias == identifier)
+        || room_info.alt_aliases.iter().any(|alias| alias == identifier)
+        || room_info.name.as_ref().is_some_and(|name| name == identifier)
+}
+
 fn format_export_filename(room_info: &RoomWithCachedInfo) -> String {
     let (nonserver_id_component, server) = room_info.id.as_str().split_once(':').unwrap();
     match (&room_info.name, &room_info.canonical_alias) {
@@ -74,152 +915,2104 @@ fn format_export_filename(room_info: &RoomWithCachedInfo) -> String {
     }
 }
 
-fn messages_to_json(events: &Vec<TimelineEvent>) -> String {
-    // Possibly add more secondary-representations-of-events here, analogous to e.g. the display-name-retrieval and datetime-formatting and so forth in the txt output?
-    // Also possibly some metadata analogous to what gets output at the head of DiscordChatExporter's JSON exports?
-    let mut events_to_export = Vec::new();
+// Patterns have to match the whole of what they're matched against, rather than just somewhere within it
+fn full_match_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", pattern))
+}
 
-    for event in events {
-        let event_serialized = event.event.deserialize_as::<serde_json::Value>().expect("Failed to deserialize a message to JSON value. (This is surprising.)"); // Add real error-handling here
-        events_to_export.push(event_serialized);
+pub(crate) fn make_filesystem_safe(filename_component: &str) -> String {
+    filename_component.chars().map(|c| match c {
+        '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '-',
+        c if c.is_control() => '-',
+        c => c,
+    }).collect()
+}
+
+// Pagination starts at the earliest event the server is willing to show us, so if that isn't the room's creation event and the room's history visibility is restrictive, the gap is almost certainly due to history visibility rather than to an empty early room
+fn history_visibility_warning(room_info: &RoomWithCachedInfo, events: &Vec<TimelineEvent>) -> Option<String> {
+    let history_visibility = room_info.room.history_visibility();
+    let reaches_room_creation = events.first().is_some_and(|event| event.event.get_field::<String>("type").ok().flatten().is_some_and(|event_type| event_type == "m.room.create"));
+    match history_visibility {
+        HistoryVisibility::Joined | HistoryVisibility::Invited if !reaches_room_creation => Some(format!("This room's history visibility is '{}', and the exported history doesn't reach back to the room's creation, so messages from before this account joined (or was invited to) the room are likely missing.", history_visibility)),
+        _ => None,
     }
+}
 
-    serde_json::to_string_pretty(&events_to_export).unwrap()
+// Returns each possible gap's start and end timestamps, i.e. those of the events either side of it
+fn possible_history_gaps(events: &[TimelineEvent], gap_threshold: Duration) -> Vec<(u64, u64)> {
+    let timestamps = events.iter().filter_map(|event| event.event.get_field::<u64>("origin_server_ts").ok().flatten()).collect::<Vec<u64>>();
+    timestamps.windows(2).map(|pair| (pair[0], pair[1])).filter(|(start, end)| end.saturating_sub(*start) > gap_threshold.as_millis() as u64).collect()
 }
 
-async fn user_id_to_string_representation(user_ids_to_string_representations: &mut HashMap<String, String>, room_info: &RoomWithCachedInfo, event_sender_id: &UserId) -> anyhow::Result<String> {
-    let event_sender_id_string = event_sender_id.to_string();
-    match user_ids_to_string_representations.get(&event_sender_id_string) {
-        Some(string_representation) => Ok(string_representation.clone()),
-        None => match room_info.room.get_member_no_sync(event_sender_id).await? {
-            Some(room_member) => {
-                let string_representation = match room_member.display_name() {
-                    Some(display_name) => format!("{} ({})", display_name, event_sender_id_string),
-                    None => event_sender_id_string.clone(),
-                };
-                user_ids_to_string_representations.insert(event_sender_id_string.clone(), string_representation);
-                Ok(user_ids_to_string_representations.get(&event_sender_id_string).unwrap().clone())
-            }
-            None => {
-                user_ids_to_string_representations.insert(event_sender_id_string.clone(), event_sender_id_string.clone());
-                Ok(event_sender_id_string)
-            },
-        },
-    }
+fn history_gaps_warning(gaps: &[(u64, u64)], gap_threshold: Duration) -> Option<String> {
+    let (longest_start, longest_end) = gaps.iter().max_by_key(|(start, end)| end - start)?;
+    let timestamp_string = |timestamp: &u64| DateTime::from_timestamp_millis(*timestamp as i64).map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)).unwrap_or_default();
+    Some(format!("Found {} possible gap(s) in this room's history, where more than {} passed between consecutive events; the longest, of {}, is from {} to {}. Homeservers can't always backfill history from other servers, so messages may be missing there.", gaps.len(), format_duration(gap_threshold.as_millis() as u64), format_duration(longest_end - longest_start), timestamp_string(longest_start), timestamp_string(longest_end)))
 }
 
-async fn messages_to_txt(events: &Vec<TimelineEvent>, room_info: &RoomWithCachedInfo) -> anyhow::Result<String> {
-    let mut user_ids_to_string_representations: HashMap<String, String> = HashMap::new();
-    let mut room_export = String::new();
+// Events of types ruma doesn't know (e.g. a bot's structured data) are shown in full, since there's no telling which parts of them matter; widgets, though custom state events, have descriptions of their own. Returns a label for the event and its pretty-printed content.
+pub(crate) fn custom_event_description(event_json: &serde_json::Value) -> (String, String) {
+    let event_type = event_json.get("type").and_then(|event_type| event_type.as_str()).unwrap_or_default();
+    let label = match event_json.get("state_key").and_then(|state_key| state_key.as_str()) {
+        Some("") => format!("custom state event {}", event_type),
+        Some(state_key) => format!("custom state event {} with state key {}", event_type, state_key),
+        None => format!("custom event {}", event_type),
+    };
+    let content = event_json.get("content").and_then(|content| serde_json::to_string_pretty(content).ok()).unwrap_or_else(|| String::from("{}"));
+    (label, content)
+}
 
-    for event in events {
-        let event_deserialized = match event.event.deserialize() {
-            Ok(event_deserialized) => event_deserialized,
-            Err(_) => {
-                // Add more nuanced error-handling here; it seems like a lot of these are in fact redacted messages, just weirdly-formed ones that don't deserialize right?
-                room_export.push_str("[Message skipped due to deserialization failure]\n");
-                continue
-            }
-        };
+// Returns the reacted-to event's ID and the reaction's key, if the event is a reaction. Redacted reactions have had their relation stripped, so aren't counted.
+pub(crate) fn reaction(event: &serde_json::Value) -> Option<(String, String)> {
+    if event.get("type")?.as_str()? != "m.reaction" {
+        return None
+    }
+    let relates_to = event.get("content")?.get("m.relates_to")?;
+    if relates_to.get("rel_type")?.as_str()? != "m.annotation" {
+        return None
+    }
+    Some((relates_to.get("event_id")?.as_str()?.to_owned(), relates_to.get("key")?.as_str()?.to_owned()))
+}
 
-        let event_timestamp_millis = event_deserialized.origin_server_ts().0.into();
-        let event_timestamp_string_representation = DateTime::from_timestamp_millis(event_timestamp_millis).expect(&format!("Found message with millisecond timestamp {}, which can't be converted to datetime.", event_timestamp_millis)).to_rfc3339_opts(SecondsFormat::Millis, true); // Add real error-handling, and also an option to use local time zones
+// Event ID to each event's position in the export and sender
+fn event_positions_and_senders(events: &[TimelineEvent]) -> HashMap<String, (usize, Option<String>)> {
+    events.iter().enumerate().filter_map(|(index, event)| {
+        let event_id = event.event.get_field::<String>("event_id").ok().flatten()?;
+        let sender = event.event.get_field::<String>("sender").ok().flatten();
+        Some((event_id, (index, sender)))
+    }).collect()
+}
 
-        let event_sender_id = event_deserialized.sender();
-        let event_sender_string_representation = user_id_to_string_representation(&mut user_ids_to_string_representations, room_info, event_sender_id).await?;
+// Rows follow the reacted-to messages' order in the export (with those outside it last), then the reaction keys' order
+fn reaction_tallies(events: &[TimelineEvent], sender_hasher: Option<&SenderHasher>) -> Vec<ReactionTally> {
+    let mut counts: HashMap<(String, String), usize> = HashMap::new();
+    for event_json in events.iter().filter_map(|event| event.event.deserialize_as::<serde_json::Value>().ok()) {
+        if let Some(reaction) = reaction(&event_json) {
+            *counts.entry(reaction).or_default() += 1;
+        }
+    }
+    let event_positions_and_senders = event_positions_and_senders(events);
 
-        let event_prefix = format!("[{}] {}:", event_timestamp_string_representation, event_sender_string_representation);
+    let mut tallies = counts.into_iter().map(|((event_id, reaction), count)| {
+        let sender = event_positions_and_senders.get(&event_id).and_then(|(_index, sender)| sender.as_deref()).map(|sender| match sender_hasher {
+            Some(sender_hasher) => sender_hasher.hash(sender),
+            None => sender.to_owned(),
+        });
+        ReactionTally { event_id, sender, reaction, count }
+    }).collect::<Vec<ReactionTally>>();
+    tallies.sort_by(|tally_1, tally_2| {
+        let position = |tally: &ReactionTally| event_positions_and_senders.get(&tally.event_id).map_or(usize::MAX, |(index, _sender)| *index);
+        (position(tally_1), &tally_1.event_id, &tally_1.reaction).cmp(&(position(tally_2), &tally_2.event_id, &tally_2.reaction))
+    });
+    tallies
+}
 
-        let event_stringified = match &event_deserialized {
-            AnyTimelineEvent::MessageLike(e) => match e {
-                AnyMessageLikeEvent::RoomMessage(e) => match &e.as_original() {
-                    Some(unredacted_room_message) => match &unredacted_room_message.content.msgtype {
-                        // Possibly revisit here at some point to add more detail beyond the body into various of these formats
-                        MessageType::Audio(e) => format!("{} [Audio; textual representation: {}]", event_prefix, &e.body),
-                        MessageType::Emote(e) => format!("{} *{}*", event_prefix, &e.body), // Think harder about whether asterisks are the correct representation here
-                        MessageType::File(e) => format!("{} [File; textual representation: {}]", event_prefix, &e.body), // In the longer term maybe include filename directly? But currently it seems like the textual representation is the main thing that's actually used to encode the filename
-                        MessageType::Image(e) => format!("{} [Image; textual representation: {}]", event_prefix, &e.body),
-                        MessageType::Location(e) => format!("{} [Location; geo URI: {}; textual representation: {}]", event_prefix, &e.geo_uri, &e.body),
-                        MessageType::Notice(e) => format!("{} [{}]", event_prefix, &e.body), // Think harder about whether brackets are the correct representation here
-                        MessageType::ServerNotice(e) => format!("{} [Server notice: {}]", event_prefix, &e.body),
-                        MessageType::Text(e) => format!("{} {}", event_prefix, &e.body),
-                        MessageType::Video(e) => format!("{} [Video; textual representation: {}]", event_prefix, &e.body),
-                        MessageType::VerificationRequest(e) => format!("{} [Verification request sent to {}]", event_prefix, user_id_to_string_representation(&mut user_ids_to_string_representations, room_info, &e.to).await?),
-                        _ => String::from("[Message of unrecognized type]"),
-                    }
-                    None => format!("{} [Redacted message]", event_prefix),
-                },
-                _ => String::from("[Placeholder message-like]"),
-            },
-            AnyTimelineEvent::State(_e) => String::from("[Placeholder state-like]"),
+// Only replies within the export are counted. Threads follow their roots' order in the export (with those whose roots are outside it last).
+fn thread_summaries(events: &[TimelineEvent], sender_hasher: Option<&SenderHasher>) -> Vec<ThreadSummary> {
+    let hash_sender = |sender: String| match sender_hasher {
+        Some(sender_hasher) => sender_hasher.hash(&sender),
+        None => sender,
+    };
+    // Thread root event ID to reply count and latest reply; events are in chronological order, so the latest reply is the last one seen
+    let mut threads: HashMap<String, (usize, &TimelineEvent)> = HashMap::new();
+    for event in events {
+        let Ok(event_json) = event.event.deserialize_as::<serde_json::Value>() else {
+            continue
         };
-        room_export.push_str(&format!("{}\n", event_stringified))
+        if event_json.pointer("/content/m.relates_to/rel_type").and_then(|rel_type| rel_type.as_str()) != Some("m.thread") {
+            continue
+        }
+        if let Some(root_event_id) = event_json.pointer("/content/m.relates_to/event_id").and_then(|event_id| event_id.as_str()) {
+            let thread = threads.entry(root_event_id.to_owned()).or_insert((0, event));
+            *thread = (thread.0 + 1, event);
+        }
     }
+    let event_positions_and_senders = event_positions_and_senders(events);
 
-    Ok(room_export)
+    let mut summaries = threads.into_iter().map(|(root_event_id, (reply_count, latest_reply))| ThreadSummary {
+        root_sender: event_positions_and_senders.get(&root_event_id).and_then(|(_index, sender)| sender.clone()).map(&hash_sender),
+        reply_count,
+        latest_reply_event_id: latest_reply.event.get_field::<String>("event_id").ok().flatten().unwrap_or_default(),
+        latest_reply_sender: latest_reply.event.get_field::<String>("sender").ok().flatten().map(&hash_sender),
+        latest_reply_timestamp: event_timestamp_string(latest_reply),
+        root_event_id,
+    }).collect::<Vec<ThreadSummary>>();
+    summaries.sort_by(|summary_1, summary_2| {
+        let position = |summary: &ThreadSummary| event_positions_and_senders.get(&summary.root_event_id).map_or(usize::MAX, |(index, _sender)| *index);
+        (position(summary_1), &summary_1.root_event_id).cmp(&(position(summary_2), &summary_2.root_event_id))
+    });
+    summaries
 }
 
-pub async fn export(client: &Client, rooms: Vec<String>, output_path: Option<PathBuf>, formats: HashSet<ExportOutputFormat>) -> anyhow::Result<()> {
-    if let Some(path) = output_path.as_ref() {
-        if path.exists() {
-            if !path.is_dir() {
-                // Add real error-handling here
-                panic!("Output path {} isn't a directory.", path.display());
-            }
-        } else {
-            create_dir_all(path).unwrap();
-        }
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
     }
+}
 
-    let accessible_rooms_info = get_rooms_info(&client).await?; // This should be possible to optimize out for request-piles without names included, given client.resolve_room_alias and client.get_room. Although that might end up actually costlier if handled indelicately, since it'll involve more serial processing.
+fn reaction_tallies_to_csv(tallies: &[ReactionTally]) -> String {
+    let mut csv = String::from("event_id,sender,reaction,count\n");
+    for tally in tallies {
+        csv.push_str(&format!("{},{},{},{}\n", csv_field(&tally.event_id), csv_field(tally.sender.as_deref().unwrap_or_default()), csv_field(&tally.reaction), tally.count));
+    }
+    csv
+}
 
-    for room_identifier in rooms {
-        let room_to_export_info = match get_room_index_by_identifier(&accessible_rooms_info, &room_identifier) {
-            Ok(index) => &accessible_rooms_info[index],
-            Err(e) => match e {
-                // This is currently CLI-biased; modify it to return error-info in a more neutral way
-                RoomIndexRetrievalError::MultipleRoomsWithSpecifiedName(room_ids) => {
-                    println!("Found more than one room accessible to {} with name {}. Room IDs: {:?}", client.user_id().unwrap(), room_identifier, room_ids);
-                    continue
-                },
-                RoomIndexRetrievalError::NoRoomsWithSpecifiedName => {
-                    println!("Couldn't find any rooms accessible to {} with name {}.", client.user_id().unwrap(), room_identifier);
-                    continue
-                },
-            }
-        };
+fn undecryptable_megolm_session_ids(events: &Vec<TimelineEvent>) -> BTreeSet<String> {
+    events.iter().filter_map(|event| match event.event.deserialize() {
+        Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomEncrypted(encrypted_event))) => match &encrypted_event.as_original()?.content.scheme {
+            EncryptedEventScheme::MegolmV1AesSha2(content) => Some(content.session_id.clone()),
+            _ => None,
+        },
+        _ => None,
+    }).collect()
+}
 
-        let mut events = Vec::new();
-        let mut last_end_token = None;
-        let mut total_messages = 0;
-        loop {
-            let mut messages_options = MessagesOptions::forward().from(last_end_token.as_deref());
-            messages_options.limit = 1_000_u16.into(); // On an initial test, this seems to be a server-side limit, at least on matrix.org. Worth setting higher just in case other servers are less limited?
-            let mut messages = room_to_export_info.room.messages(messages_options).await?;
-            let messages_length = messages.chunk.len();
-            total_messages += messages_length;
-            if messages_length == 0 || total_messages > 10_000_000 {
-                break
-            }
-            events.append(&mut messages.chunk);
-            last_end_token = messages.end;
-        }
+// Works from the event's json rather than from ruma's types, so that the schema stays put even as ruma's types change
+pub(crate) fn exported_event_from_json(event: &serde_json::Value) -> ExportedEvent {
+    let string_field = |value: &serde_json::Value, field: &str| value.get(field).and_then(|field_value| field_value.as_str()).map(String::from);
 
-        let base_output_path = output_path.clone().unwrap_or_else(|| PathBuf::new());
-        let base_output_filename = format_export_filename(&room_to_export_info);
-        if formats.contains(&ExportOutputFormat::Json) {
-            let json_output_file = messages_to_json(&events);
-            let mut json_output_path_buf = base_output_path.clone();
-            json_output_path_buf.push(format!("{}.json", base_output_filename));
-            write(json_output_path_buf, json_output_file).unwrap();
-        }
-        if formats.contains(&ExportOutputFormat::Txt) {
-            let txt_output_file = messages_to_txt(&events, room_to_export_info).await?;
-            let mut txt_output_path_buf = base_output_path.clone();
-            txt_output_path_buf.push(format!("{}.txt", base_output_filename));
-            write(txt_output_path_buf, txt_output_file).unwrap();
+    let event_type = string_field(event, "type").unwrap_or_default();
+    let origin_server_ts = event.get("origin_server_ts").and_then(|timestamp| timestamp.as_u64()).unwrap_or(0);
+    let content = event.get("content").cloned().unwrap_or(serde_json::Value::Null);
+    let redacted = event.get("unsigned").and_then(|unsigned| unsigned.get("redacted_because")).is_some();
+
+    let message = match (event_type.as_str(), string_field(&content, "msgtype")) {
+        ("m.room.message", Some(msgtype)) => {
+            let relates_to = content.get("m.relates_to");
+            let relation_target = |rel_type: &str| relates_to.filter(|relates_to| relates_to.get("rel_type").and_then(|value| value.as_str()) == Some(rel_type)).and_then(|relates_to| string_field(relates_to, "event_id"));
+            Some(ExportedMessage {
+                msgtype,
+                body: string_field(&content, "body").unwrap_or_default(),
+                formatted_body: string_field(&content, "formatted_body").filter(|_| string_field(&content, "format").as_deref() == Some("org.matrix.custom.html")),
+                relations: ExportedRelations {
+                    in_reply_to: relates_to.and_then(|relates_to| relates_to.get("m.in_reply_to")).and_then(|in_reply_to| string_field(in_reply_to, "event_id")),
+                    replaces: relation_target("m.replace"),
+                    thread_root: relation_target("m.thread"),
+                },
+            })
         }
+        _ => None,
+    };
+
+    ExportedEvent {
+        event_id: string_field(event, "event_id").unwrap_or_default(),
+        sender: string_field(event, "sender").unwrap_or_default(),
+        timestamp: DateTime::from_timestamp_millis(origin_server_ts as i64).map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)).unwrap_or_default(),
+        origin_server_ts,
+        state_key: string_field(event, "state_key"),
+        redacted,
+        encryption: if event_type == "m.room.encrypted" {
+            ExportedEncryptionStatus::Undecrypted
+        } else {
+            ExportedEncryptionStatus::Unencrypted
+        },
+        content: if message.is_none() && !(event_type == "m.room.message" && redacted) {
+            Some(content)
+        } else {
+            None
+        },
+        message,
+        event_type,
+        prev_content: event.get("unsigned").and_then(|unsigned| unsigned.get("prev_content")).cloned(),
+        permalink: None,
+        call: None,
     }
+}
 
-    Ok(())
+fn format_duration(duration_ms: u64) -> String {
+    let seconds = duration_ms / 1000;
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, seconds) => format!("{}s", seconds),
+        (0, minutes, seconds) => format!("{}m {}s", minutes, seconds),
+        (hours, minutes, seconds) => format!("{}h {}m {}s", hours, minutes, seconds),
+    }
+}
+
+// Maps each call ID to the timestamp of the call's invite, so that hangups can be given the call's duration. Calls whose invite predates the exported range are left without one.
+pub(crate) fn call_invite_timestamps(events: &[TimelineEvent]) -> HashMap<String, u64> {
+    events.iter().filter(|event| event.event.get_field::<String>("type").ok().flatten().as_deref() == Some("m.call.invite")).filter_map(|event| {
+        let call_id = event.event.get_field::<serde_json::Value>("content").ok().flatten()?.get("call_id")?.as_str()?.to_owned();
+        let timestamp = event.event.get_field::<u64>("origin_server_ts").ok().flatten()?;
+        Some((call_id, timestamp))
+    }).collect()
+}
+
+pub(crate) fn exported_call(event: &serde_json::Value, call_invite_timestamps: &HashMap<String, u64>) -> Option<ExportedCall> {
+    let action = event.get("type")?.as_str()?.strip_prefix("m.call.")?;
+    if !matches!(action, "invite" | "answer" | "reject" | "hangup") {
+        return None
+    }
+    let content = event.get("content")?;
+    let call_id = content.get("call_id")?.as_str()?.to_owned();
+    let string_field = |field: &str| content.get(field).and_then(|value| value.as_str()).map(String::from);
+
+    let duration_ms = match (action, call_invite_timestamps.get(&call_id), event.get("origin_server_ts").and_then(|timestamp| timestamp.as_u64())) {
+        ("hangup", Some(invite_timestamp), Some(hangup_timestamp)) => hangup_timestamp.checked_sub(*invite_timestamp),
+        _ => None,
+    };
+    Some(ExportedCall {
+        action: action.to_owned(),
+        invitee: string_field("invitee").filter(|_| action == "invite"),
+        // Whether a call is a video call is only expressed in the SDP offer itself, as a video media line
+        video: (action == "invite").then(|| content.pointer("/offer/sdp").and_then(|sdp| sdp.as_str()).is_some_and(|sdp| sdp.lines().any(|line| line.starts_with("m=video")))),
+        reason: string_field("reason").filter(|_| matches!(action, "reject" | "hangup")),
+        duration_ms,
+        call_id,
+    })
+}
+
+// Widgets are state events of a custom type, keyed by widget ID; removing one empties its content
+pub(crate) fn widget_description(event: &serde_json::Value) -> Option<String> {
+    if !matches!(event.get("type")?.as_str()?, "m.widget" | "im.vector.modular.widgets") {
+        return None
+    }
+    let string_field = |content: Option<&serde_json::Value>, field: &str| content.and_then(|content| content.get(field)).and_then(|value| value.as_str()).filter(|value| !value.is_empty()).map(String::from);
+    let content = event.get("content");
+    Some(match string_field(content, "url") {
+        Some(url) => format!("[Widget: {} ({})]", string_field(content, "name").unwrap_or_else(|| String::from("Unnamed widget")), url),
+        None => match string_field(event.get("unsigned").and_then(|unsigned| unsigned.get("prev_content")), "name") {
+            Some(previous_name) => format!("[Widget removed: {}]", previous_name),
+            None => String::from("[Widget removed]"),
+        },
+    })
+}
+
+// Describes room name, topic, avatar, and power level changes relative to the previous state, e.g. `changed the topic from "a" to "b"`. Servers only sometimes provide the previous state (in `unsigned.prev_content`), so without it a change is described as setting the new value.
+pub(crate) fn state_change_description(event: &serde_json::Value, sender_hasher: Option<&SenderHasher>) -> Option<String> {
+    let (description, field) = match event.get("type")?.as_str()? {
+        "m.room.name" => ("room name", "name"),
+        "m.room.topic" => ("topic", "topic"),
+        "m.room.avatar" => ("room avatar", "url"),
+        "m.room.power_levels" => return Some(power_levels_change_description(event, sender_hasher)),
+        _ => return None,
+    };
+    let field_value = |content: Option<&serde_json::Value>| content.and_then(|content| content.get(field)).and_then(|value| value.as_str()).filter(|value| !value.is_empty()).map(String::from);
+    let previous_value = field_value(event.get("unsigned").and_then(|unsigned| unsigned.get("prev_content")));
+    let new_value = field_value(event.get("content"));
+
+    Some(match (previous_value, new_value) {
+        (Some(previous_value), Some(new_value)) => format!("changed the {} from \"{}\" to \"{}\"", description, previous_value, new_value),
+        (None, Some(new_value)) => format!("set the {} to \"{}\"", description, new_value),
+        (Some(previous_value), None) => format!("removed the {} (previously \"{}\")", description, previous_value),
+        (None, None) => format!("removed the {}", description),
+    })
+}
+
+// Levels can be integers or, in rooms from before that was enforced, strings of integers
+fn power_level(value: Option<&serde_json::Value>) -> Option<i64> {
+    let value = value?;
+    value.as_i64().or_else(|| value.as_str()?.trim().parse().ok())
+}
+
+// Lists whose power level changed (as promotions and demotions, taking each side's users_default into account) and which of the levels required for actions changed, e.g. `changed the power levels: promoted @a:example.org from 0 to 50; ban level from 50 to 100`. Without the previous power levels, the users' current levels are listed instead.
+fn power_levels_change_description(event: &serde_json::Value, sender_hasher: Option<&SenderHasher>) -> String {
+    let user_representation = |user_id: &str| match sender_hasher {
+        Some(sender_hasher) => sender_hasher.hash(user_id),
+        None => user_id.to_owned(),
+    };
+    let empty = serde_json::Value::Object(serde_json::Map::new());
+    let content = event.get("content").unwrap_or(&empty);
+    let users = |content: &serde_json::Value| content.get("users").and_then(|users| users.as_object()).map(|users| users.iter().filter_map(|(user_id, level)| Some((user_id.clone(), power_level(Some(level))?))).collect::<BTreeMap<String, i64>>()).unwrap_or_default();
+    let users_default = |content: &serde_json::Value| power_level(content.get("users_default")).unwrap_or(0);
+
+    let Some(previous_content) = event.get("unsigned").and_then(|unsigned| unsigned.get("prev_content")) else {
+        let user_levels = users(content).iter().map(|(user_id, level)| format!("{} at {}", user_representation(user_id), level)).collect::<Vec<_>>();
+        return if user_levels.is_empty() {
+            String::from("set the power levels")
+        } else {
+            format!("set the power levels: {}", user_levels.join(", "))
+        }
+    };
+
+    let mut changes = Vec::new();
+    let (previous_users, new_users) = (users(previous_content), users(content));
+    let (previous_users_default, new_users_default) = (users_default(previous_content), users_default(content));
+    for user_id in previous_users.keys().chain(new_users.keys()).collect::<BTreeSet<_>>() {
+        let previous_level = previous_users.get(user_id).copied().unwrap_or(previous_users_default);
+        let new_level = new_users.get(user_id).copied().unwrap_or(new_users_default);
+        if new_level != previous_level {
+            changes.push(format!("{} {} from {} to {}", if new_level > previous_level { "promoted" } else { "demoted" }, user_representation(user_id), previous_level, new_level));
+        }
+    }
+    // Defaults as given by the spec for when a level is left unspecified
+    for (field, default) in [("users_default", 0), ("events_default", 0), ("state_default", 50), ("ban", 50), ("kick", 50), ("redact", 50), ("invite", 0)] {
+        let previous_level = power_level(previous_content.get(field)).unwrap_or(default);
+        let new_level = power_level(content.get(field)).unwrap_or(default);
+        if new_level != previous_level {
+            changes.push(format!("{} level from {} to {}", field, previous_level, new_level));
+        }
+    }
+    let event_levels = |content: &serde_json::Value| content.get("events").and_then(|events| events.as_object()).map(|events| events.iter().filter_map(|(event_type, level)| Some((event_type.clone(), power_level(Some(level))?))).collect::<BTreeMap<String, i64>>()).unwrap_or_default();
+    let (previous_event_levels, new_event_levels) = (event_levels(previous_content), event_levels(content));
+    for event_type in previous_event_levels.keys().chain(new_event_levels.keys()).collect::<BTreeSet<_>>() {
+        let level_representation = |level: Option<&i64>| level.map(|level| level.to_string()).unwrap_or_else(|| String::from("default"));
+        let (previous_level, new_level) = (previous_event_levels.get(event_type), new_event_levels.get(event_type));
+        if new_level != previous_level {
+            changes.push(format!("{} events level from {} to {}", event_type, level_representation(previous_level), level_representation(new_level)));
+        }
+    }
+
+    if changes.is_empty() {
+        String::from("changed the power levels, without changing any levels")
+    } else {
+        format!("changed the power levels: {}", changes.join("; "))
+    }
+}
+
+pub(crate) fn anonymize_event_json(event: &mut serde_json::Value, sender_hasher: &SenderHasher) {
+    let is_membership_event = event.get("type").and_then(|event_type| event_type.as_str()) == Some("m.room.member");
+    let is_power_levels_event = event.get("type").and_then(|event_type| event_type.as_str()) == Some("m.room.power_levels");
+    let hash_field = |value: &mut serde_json::Value, field: &str| {
+        if let Some(user_id) = value.get(field).and_then(|user_id| user_id.as_str()).map(String::from) {
+            value[field] = serde_json::Value::String(sender_hasher.hash(&user_id));
+        }
+    };
+
+    hash_field(event, "sender");
+    if is_membership_event {
+        hash_field(event, "state_key");
+        for identifying_field in ["displayname", "avatar_url"] {
+            if let Some(content) = event.get_mut("content").and_then(|content| content.as_object_mut()) {
+                content.remove(identifying_field);
+            }
+        }
+        if let Some(unsigned) = event.get_mut("unsigned").and_then(|unsigned| unsigned.as_object_mut()) {
+            unsigned.remove("prev_content");
+        }
+    }
+    if let Some(content) = event.get_mut("content") {
+        hash_field(content, "to"); // Verification requests
+        hash_field(content, "invitee"); // Call invites
+    }
+    if is_power_levels_event {
+        for users_pointer in ["/content/users", "/unsigned/prev_content/users"] {
+            if let Some(users) = event.pointer_mut(users_pointer).and_then(|users| users.as_object_mut()) {
+                *users = users.iter().map(|(user_id, power_level)| (sender_hasher.hash(user_id), power_level.clone())).collect();
+            }
+        }
+    }
+}
+
+fn json_to_string<T: Serialize>(value: &T, compact: bool) -> serde_json::Result<String> {
+    if compact {
+        serde_json::to_string(value)
+    } else {
+        serde_json::to_string_pretty(value)
+    }
+}
+
+// Assembled from each event's original JSON text rather than serialized, so that nothing (key order, number formatting, unknown fields) can be altered on the way through
+fn raw_event_dump(events: &[TimelineEvent]) -> String {
+    if events.is_empty() {
+        return String::from("[]\n")
+    }
+    let raw_events = events.iter().map(|event| event.event.json().get()).collect::<Vec<&str>>();
+    format!("[\n{}\n]\n", raw_events.join(",\n"))
+}
+
+fn exported_event_json(event: &TimelineEvent, permalinks: Option<&RoomPermalinks>, formatting: &FormattingOptions, call_invite_timestamps: &HashMap<String, u64>) -> serde_json::Value {
+    let mut event_serialized = event.event.deserialize_as::<serde_json::Value>().expect("Failed to deserialize a message to JSON value. (This is surprising.)"); // Add real error-handling here
+    if let Some(sender_hasher) = &formatting.sender_hasher {
+        anonymize_event_json(&mut event_serialized, sender_hasher);
+    }
+    if formatting.raw_json {
+        return event_serialized
+    }
+    let mut exported_event = exported_event_from_json(&event_serialized);
+    if event.encryption_info.is_some() {
+        exported_event.encryption = ExportedEncryptionStatus::Decrypted;
+    }
+    exported_event.permalink = permalinks.map(|permalinks| permalinks.event_permalink(&exported_event.event_id));
+    exported_event.call = exported_call(&event_serialized, call_invite_timestamps);
+    serde_json::to_value(exported_event).unwrap()
+}
+
+fn json_export(events: &Vec<TimelineEvent>, context: &RoomExportContext<'_>, formatting: &FormattingOptions) -> JsonExport {
+    let mut events_to_export = Vec::new();
+    let call_invite_timestamps = call_invite_timestamps(events);
+
+    for event in events {
+        events_to_export.push(exported_event_json(event, context.permalinks.as_ref(), formatting, &call_invite_timestamps));
+    }
+
+    JsonExport {
+        room: json_export_room_header(context, formatting, &call_invite_timestamps),
+        messages: events_to_export,
+    }
+}
+
+fn json_export_room_header(context: &RoomExportContext<'_>, formatting: &FormattingOptions, call_invite_timestamps: &HashMap<String, u64>) -> JsonExportRoomHeader {
+    let room_info = context.room_info;
+    JsonExportRoomHeader {
+        id: room_info.id.to_string(),
+        name: room_info.name.clone(),
+        canonical_alias: room_info.canonical_alias.as_ref().map(|alias| alias.to_string()),
+        history_visibility: room_info.room.history_visibility().to_string(),
+        warnings: context.warnings.clone(),
+        interrupted: context.interrupted,
+        media: context.media_links.clone(),
+        pinned: context.pinned.iter().map(|pinned_event| ExportedPinnedEvent {
+            event_id: pinned_event.event_id.clone(),
+            in_export: pinned_event.in_export,
+            event: pinned_event.event.as_ref().map(|event| exported_event_json(event, context.permalinks.as_ref(), formatting, call_invite_timestamps)),
+        }).collect(),
+        read_receipts: context.read_receipts.iter().map(|(event_id, receipts)| (event_id.clone(), receipts.iter().map(|receipt| ExportedReadReceipt {
+            user_id: match &formatting.sender_hasher {
+                Some(sender_hasher) => sender_hasher.hash(&receipt.user_id),
+                None => receipt.user_id.clone(),
+            },
+            timestamp: receipt.timestamp.clone(),
+        }).collect())).collect(),
+    }
+}
+
+// Pretty-printed json nested inside other pretty-printed json has to be indented to its depth; newlines can only be structural, since any within strings are escaped
+fn nested_json_to_string<T: Serialize>(value: &T, compact: bool, depth: usize) -> serde_json::Result<String> {
+    let json = json_to_string(value, compact)?;
+    Ok(if compact { json } else { json.replace('\n', &format!("\n{}", "  ".repeat(depth))) })
+}
+
+// Writes exactly what serializing the room's `JsonExport` would, but formats each message only as it's written, rather than holding every message as json at once
+fn write_json_export_streamed(writer: &mut dyn Write, events: &Vec<TimelineEvent>, context: &RoomExportContext<'_>, formatting: &FormattingOptions) -> io::Result<()> {
+    let call_invite_timestamps = call_invite_timestamps(events);
+    let room = json_export_room_header(context, formatting, &call_invite_timestamps);
+    let messages = events.iter().map(|event| exported_event_json(event, context.permalinks.as_ref(), formatting, &call_invite_timestamps));
+    write_json_export_messages_streamed(writer, &room, messages, formatting.json_compact)
+}
+
+fn write_json_export_messages_streamed(writer: &mut dyn Write, room: &JsonExportRoomHeader, messages: impl Iterator<Item = serde_json::Value>, compact: bool) -> io::Result<()> {
+    let (field_separator, message_separator) = if compact { ("", "") } else { ("\n  ", "\n    ") };
+    let key_separator = if compact { ":" } else { ": " };
+
+    write!(writer, "{{{}\"room\"{}{},{}\"messages\"{}[", field_separator, key_separator, nested_json_to_string(room, compact, 1)?, field_separator, key_separator)?;
+    let mut is_empty = true;
+    for message in messages {
+        if !is_empty {
+            writer.write_all(b",")?;
+        }
+        is_empty = false;
+        write!(writer, "{}{}", message_separator, nested_json_to_string(&message, compact, 2)?)?;
+    }
+    if !is_empty {
+        writer.write_all(field_separator.as_bytes())?;
+    }
+    let closing_separator = if compact { "" } else { "\n" };
+    write!(writer, "]{}}}", closing_separator)
+}
+
+// Strips bidi controls (which can reorder the surrounding text of a line, scrambling terminals and txt files) and invisible zero-width characters (which can make names impersonate one another). The zero-width joiner is kept, since it's also what glues multi-codepoint emoji together.
+fn sanitize_display_name(display_name: &str) -> String {
+    display_name.chars().filter(|c| !matches!(c,
+        '\u{061C}' // Arabic letter mark
+        | '\u{200B}' | '\u{200C}' // Zero-width space and non-joiner
+        | '\u{200E}' | '\u{200F}' // Left-to-right and right-to-left marks
+        | '\u{202A}'..='\u{202E}' // Bidi embeddings and overrides
+        | '\u{2060}'..='\u{2064}' // Word joiner and invisible operators
+        | '\u{2066}'..='\u{2069}' // Bidi isolates
+        | '\u{FEFF}' // Zero-width no-break space
+    )).collect()
+}
+
+pub(crate) fn in_reply_to(event: &TimelineEvent) -> Option<String> {
+    let content = event.event.get_field::<serde_json::Value>("content").ok().flatten()?;
+    content.get("m.relates_to")?.get("m.in_reply_to")?.get("event_id")?.as_str().map(String::from)
+}
+
+pub(crate) fn events_by_id(events: &[TimelineEvent]) -> HashMap<String, &TimelineEvent> {
+    events.iter().filter_map(|event| Some((event.event.get_field::<String>("event_id").ok().flatten()?, event))).collect()
+}
+
+// Walks a reply's chain of replied-to messages, nearest first, for as far as they're among the exported events (and within the depth limit). Also returns whether the chain continues beyond what could be walked.
+pub(crate) fn reply_ancestry<'a>(event: &TimelineEvent, events_by_id: &HashMap<String, &'a TimelineEvent>) -> (Vec<&'a TimelineEvent>, bool) {
+    let mut ancestry = Vec::new();
+    let mut next_event_id = in_reply_to(event);
+    while let Some(event_id) = next_event_id {
+        match events_by_id.get(&event_id) {
+            Some(ancestor) if ancestry.len() < FLATTENED_REPLY_DEPTH_LIMIT => {
+                next_event_id = in_reply_to(ancestor);
+                ancestry.push(*ancestor);
+            }
+            _ => return (ancestry, true),
+        }
+    }
+    (ancestry, false)
+}
+
+// The body of a message as quoted within a flattened reply chain. Replies' own fallback quotes (which older clients prepend to the body, as lines starting with `>`) are stripped, since the chain already shows what they quote.
+pub(crate) fn quoted_body(event: &TimelineEvent) -> String {
+    let content = event.event.get_field::<serde_json::Value>("content").ok().flatten();
+    match content.as_ref().and_then(|content| content.get("body")).and_then(|body| body.as_str()) {
+        Some(body) if in_reply_to(event).is_some() => match body.split_once("\n\n") {
+            Some((fallback, reply)) if fallback.lines().all(|line| line.starts_with('>')) => reply.to_owned(),
+            _ => body.to_owned(),
+        },
+        Some(body) => body.to_owned(),
+        None if content.as_ref().and_then(|content| content.as_object()).is_some_and(|content| content.is_empty()) => String::from("[Redacted message]"),
+        None => format!("[{}]", event.event.get_field::<String>("type").ok().flatten().unwrap_or_default()),
+    }
+}
+
+// Maps the ID of each edited event to the timestamp of its latest edit. Only edits by the original sender count, as in other clients. The server's own aggregation (in the original's unsigned data) is used as a fallback for edits outside the exported range.
+fn latest_edit_timestamps(events: &[TimelineEvent]) -> HashMap<String, u64> {
+    let event_json = events.iter().filter_map(|event| event.event.deserialize_as::<serde_json::Value>().ok()).collect::<Vec<_>>();
+    let string_field = |value: &serde_json::Value, field: &str| value.get(field).and_then(|field_value| field_value.as_str()).map(String::from);
+    let senders = event_json.iter().filter_map(|event| Some((string_field(event, "event_id")?, string_field(event, "sender")?))).collect::<HashMap<String, String>>();
+
+    let mut latest_edit_timestamps: HashMap<String, u64> = HashMap::new();
+    let mut record_edit = |edited_event_id: String, timestamp: u64| {
+        let latest_timestamp = latest_edit_timestamps.entry(edited_event_id).or_insert(timestamp);
+        *latest_timestamp = (*latest_timestamp).max(timestamp);
+    };
+    for event in &event_json {
+        let relates_to = event.get("content").and_then(|content| content.get("m.relates_to"));
+        if relates_to.and_then(|relates_to| string_field(relates_to, "rel_type")).as_deref() == Some("m.replace") {
+            let edited_event_id = relates_to.and_then(|relates_to| string_field(relates_to, "event_id"));
+            let timestamp = event.get("origin_server_ts").and_then(|timestamp| timestamp.as_u64());
+            if let (Some(edited_event_id), Some(timestamp)) = (edited_event_id, timestamp) {
+                if senders.get(&edited_event_id).is_some_and(|original_sender| string_field(event, "sender").as_ref() == Some(original_sender)) {
+                    record_edit(edited_event_id, timestamp);
+                }
+            }
+        }
+        let aggregated_edit_timestamp = event.pointer("/unsigned/m.relations/m.replace/origin_server_ts").and_then(|timestamp| timestamp.as_u64());
+        if let (Some(event_id), Some(timestamp)) = (string_field(event, "event_id"), aggregated_edit_timestamp) {
+            record_edit(event_id, timestamp);
+        }
+    }
+
+    latest_edit_timestamps
+}
+
+pub(crate) fn event_timestamp_string(event: &TimelineEvent) -> String {
+    event.event.get_field::<i64>("origin_server_ts").ok().flatten().and_then(DateTime::from_timestamp_millis).map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)).unwrap_or_default()
+}
+
+// For events which are shown outside the main timeline (quoted in reply chains, or pinned), and so haven't been deserialized in full
+pub(crate) async fn event_sender_string_representation(user_ids_to_string_representations: &mut HashMap<String, String>, room_info: &RoomWithCachedInfo, event: &TimelineEvent, sender_hasher: Option<&SenderHasher>) -> anyhow::Result<String> {
+    match event.event.get_field::<String>("sender").ok().flatten().and_then(|sender| UserId::parse(sender).ok()) {
+        Some(sender) => user_id_to_string_representation(user_ids_to_string_representations, room_info, &sender, sender_hasher).await,
+        None => Ok(String::from("[Unknown sender]")),
+    }
+}
+
+fn sender_string_representation(display_name: Option<&str>, user_id: &str) -> String {
+    match display_name {
+        Some(display_name) => format!("{} ({})", sanitize_display_name(display_name), user_id),
+        None => user_id.to_owned(),
+    }
+}
+
+// Replays the export's membership events to find each event's sender's display name as of that event. Until a user's first membership event in the export, their name is the one that event replaced, if the server says; otherwise their events before it are left for their current name.
+fn event_time_display_names(events: &[TimelineEvent]) -> HashMap<String, Option<String>> {
+    let event_jsons = events.iter().filter_map(|event| event.event.deserialize_as::<serde_json::Value>().ok()).collect::<Vec<serde_json::Value>>();
+    let is_membership_event = |event_json: &serde_json::Value| event_json.get("type").and_then(|event_type| event_type.as_str()) == Some("m.room.member");
+    let membership_state_key = |event_json: &serde_json::Value| event_json.get("state_key").and_then(|state_key| state_key.as_str()).map(String::from);
+    let display_name = |content: Option<&serde_json::Value>| content.and_then(|content| content.get("displayname")).and_then(|display_name| display_name.as_str()).map(String::from);
+
+    // Backwards, so that each user's earliest membership event has the last word
+    let mut current_display_names = HashMap::new();
+    for event_json in event_jsons.iter().rev().filter(|event_json| is_membership_event(event_json)) {
+        if let Some(user_id) = membership_state_key(event_json) {
+            match event_json.pointer("/unsigned/prev_content") {
+                Some(prev_content) => current_display_names.insert(user_id, display_name(Some(prev_content))),
+                None => current_display_names.remove(&user_id),
+            };
+        }
+    }
+
+    let mut display_names_at_event_time = HashMap::new();
+    for event_json in &event_jsons {
+        if is_membership_event(event_json) {
+            if let Some(user_id) = membership_state_key(event_json) {
+                current_display_names.insert(user_id, display_name(event_json.get("content")));
+            }
+        }
+        let event_id = event_json.get("event_id").and_then(|event_id| event_id.as_str());
+        let sender_display_name = event_json.get("sender").and_then(|sender| sender.as_str()).and_then(|sender| current_display_names.get(sender));
+        if let (Some(event_id), Some(sender_display_name)) = (event_id, sender_display_name) {
+            display_names_at_event_time.insert(event_id.to_owned(), sender_display_name.clone());
+        }
+    }
+    display_names_at_event_time
+}
+
+// As `user_id_to_string_representation`, but with the display name the sender had as of the event, where that's known
+pub(crate) async fn event_time_user_id_to_string_representation(user_ids_to_string_representations: &mut HashMap<String, String>, context: &RoomExportContext<'_>, event_id: &EventId, event_sender_id: &UserId, sender_hasher: Option<&SenderHasher>) -> anyhow::Result<String> {
+    match context.display_names_at_event_time.get(event_id.as_str()) {
+        Some(display_name) if sender_hasher.is_none() => Ok(sender_string_representation(display_name.as_deref(), event_sender_id.as_str())),
+        _ => user_id_to_string_representation(user_ids_to_string_representations, context.room_info, event_sender_id, sender_hasher).await,
+    }
+}
+
+pub(crate) async fn user_id_to_string_representation(user_ids_to_string_representations: &mut HashMap<String, String>, room_info: &RoomWithCachedInfo, event_sender_id: &UserId, sender_hasher: Option<&SenderHasher>) -> anyhow::Result<String> {
+    if let Some(sender_hasher) = sender_hasher {
+        return Ok(sender_hasher.hash(event_sender_id.as_str()))
+    }
+
+    let event_sender_id_string = event_sender_id.to_string();
+    match user_ids_to_string_representations.get(&event_sender_id_string) {
+        Some(string_representation) => Ok(string_representation.clone()),
+        None => match room_info.room.get_member_no_sync(event_sender_id).await? {
+            Some(room_member) => {
+                let string_representation = sender_string_representation(room_member.display_name(), &event_sender_id_string);
+                user_ids_to_string_representations.insert(event_sender_id_string.clone(), string_representation);
+                Ok(user_ids_to_string_representations.get(&event_sender_id_string).unwrap().clone())
+            }
+            None => {
+                user_ids_to_string_representations.insert(event_sender_id_string.clone(), event_sender_id_string.clone());
+                Ok(event_sender_id_string)
+            },
+        },
+    }
+}
+
+// A message's text in the txt export, after its prefix. Verification requests' recipients have to be looked up beforehand, since that may need the server.
+fn txt_message_text(msgtype: &MessageType, media_link: &str, verification_recipient: Option<&str>) -> String {
+    match msgtype {
+        // Possibly revisit here at some point to add more detail beyond the body into various of these formats
+        MessageType::Audio(e) => format!("[Audio; textual representation: {}{}]", &e.body, media_link),
+        MessageType::Emote(e) => format!("*{}*", &e.body), // Think harder about whether asterisks are the correct representation here
+        MessageType::File(e) => format!("[File; textual representation: {}{}]", &e.body, media_link), // In the longer term maybe include filename directly? But currently it seems like the textual representation is the main thing that's actually used to encode the filename
+        MessageType::Image(e) => format!("[Image; textual representation: {}{}]", &e.body, media_link),
+        MessageType::Location(e) => format!("[Location; geo URI: {}; textual representation: {}]", &e.geo_uri, &e.body),
+        MessageType::Notice(e) => format!("[{}]", &e.body), // Think harder about whether brackets are the correct representation here
+        MessageType::ServerNotice(e) => format!("[Server notice: {}]", &e.body),
+        MessageType::Text(e) => e.body.clone(),
+        MessageType::Video(e) => format!("[Video; textual representation: {}{}]", &e.body, media_link),
+        MessageType::VerificationRequest(e) => format!("[Verification request sent to {}]", verification_recipient.unwrap_or(e.to.as_str())),
+        // Covers msgtypes added to the spec since, and custom ones; the body is required of every msgtype precisely so that clients can fall back to it
+        other_msgtype => format!("[{}] {}", other_msgtype.msgtype(), other_msgtype.body()),
+    }
+}
+
+async fn txt_export(events: &Vec<TimelineEvent>, context: &RoomExportContext<'_>, formatting: &FormattingOptions) -> anyhow::Result<TxtExport> {
+    let room_info = context.room_info;
+    let sender_hasher = formatting.sender_hasher.as_ref();
+    let mut user_ids_to_string_representations: HashMap<String, String> = HashMap::new();
+    let mut header = String::new();
+    let mut event_texts = Vec::new();
+    let mut event_dates = Vec::new();
+    let latest_edit_timestamps = if formatting.mark_edits_in_txt {
+        latest_edit_timestamps(events)
+    } else {
+        HashMap::new()
+    };
+    let call_invite_timestamps = call_invite_timestamps(events);
+    let events_by_id = events_by_id(events);
+
+    for warning in &context.warnings {
+        header.push_str(&format!("[Note: {}]\n", warning));
+    }
+    if !context.pinned.is_empty() {
+        header.push_str("[Pinned messages]\n");
+        for pinned_event in &context.pinned {
+            match &pinned_event.event {
+                Some(event) => {
+                    let sender = event_sender_string_representation(&mut user_ids_to_string_representations, room_info, event, sender_hasher).await?;
+                    let out_of_range_note = if pinned_event.in_export { "" } else { " (not in this export)" };
+                    header.push_str(&format!("[{}] {}: {}{}\n", event_timestamp_string(event), sender, quoted_body(event), out_of_range_note));
+                }
+                None => header.push_str(&format!("[Pinned message {} couldn't be fetched]\n", pinned_event.event_id)),
+            }
+        }
+        header.push_str("[End of pinned messages]\n");
+    }
+    if events.is_empty() {
+        header.push_str("[No messages]\n");
+    }
+
+    let mut previous_timestamp = None;
+    for event in events {
+        let mut event_text = String::new();
+        let timestamp = event.event.get_field::<u64>("origin_server_ts").ok().flatten();
+        if let Some(timestamp) = timestamp {
+            if context.gap_precedes(previous_timestamp, timestamp) {
+                event_text.push_str("[--- possible gap in history ---]\n");
+            }
+            previous_timestamp = Some(timestamp);
+        }
+        event_dates.push(timestamp.and_then(|timestamp| DateTime::from_timestamp_millis(timestamp as i64)).map(|timestamp| timestamp.date_naive()));
+
+        let event_deserialized = match event.event.deserialize() {
+            Ok(event_deserialized) => event_deserialized,
+            Err(_) => {
+                // Add more nuanced error-handling here; it seems like a lot of these are in fact redacted messages, just weirdly-formed ones that don't deserialize right?
+                event_text.push_str("[Message skipped due to deserialization failure]\n");
+                event_texts.push(event_text);
+                continue
+            }
+        };
+
+        let event_timestamp_millis = event_deserialized.origin_server_ts().0.into();
+        let event_timestamp_string_representation = DateTime::from_timestamp_millis(event_timestamp_millis).expect(&format!("Found message with millisecond timestamp {}, which can't be converted to datetime.", event_timestamp_millis)).to_rfc3339_opts(SecondsFormat::Millis, true); // Add real error-handling, and also an option to use local time zones
+
+        let event_sender_id = event_deserialized.sender();
+        let event_sender_string_representation = event_time_user_id_to_string_representation(&mut user_ids_to_string_representations, context, event_deserialized.event_id(), event_sender_id, sender_hasher).await?;
+
+        let event_id_prefix = if formatting.show_event_ids_in_txt {
+            let event_id = event_deserialized.event_id().as_str();
+            format!("{{{}}} ", event_id.get(..TXT_SHORT_EVENT_ID_LENGTH).unwrap_or(event_id))
+        } else {
+            String::new()
+        };
+        let event_prefix = format!("{}[{}] {}:", event_id_prefix, event_timestamp_string_representation, event_sender_string_representation);
+
+        let event_json = event.event.deserialize_as::<serde_json::Value>().ok();
+        let call = event_json.as_ref().and_then(|event_json| exported_call(event_json, &call_invite_timestamps));
+        let call_invitee = match call.as_ref().and_then(|call| call.invitee.as_deref()).and_then(|invitee| UserId::parse(invitee).ok()) {
+            Some(invitee) => Some(user_id_to_string_representation(&mut user_ids_to_string_representations, room_info, &invitee, sender_hasher).await?),
+            None => None,
+        };
+
+        let edit_suffix = match latest_edit_timestamps.get(event_deserialized.event_id().as_str()).and_then(|timestamp| DateTime::from_timestamp_millis(*timestamp as i64)) {
+            Some(edit_timestamp) => format!(" (edited) [{}]", edit_timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            None => String::new(),
+        };
+
+        let custom_event = match (&event_deserialized, &event_json) {
+            (AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::_Custom(_)) | AnyTimelineEvent::State(AnyStateEvent::_Custom(_)), Some(event_json)) => Some(custom_event_description(event_json)),
+            _ => None,
+        };
+
+        let media_link = match context.media_links.get(event_deserialized.event_id().as_str()) {
+            Some(media_link) => format!("; link: {}", media_link),
+            None => String::new(),
+        };
+
+        let event_stringified = match &event_deserialized {
+            AnyTimelineEvent::MessageLike(e) => match e {
+                AnyMessageLikeEvent::RoomMessage(e) => match &e.as_original() {
+                    Some(unredacted_room_message) => {
+                        let msgtype = &unredacted_room_message.content.msgtype;
+                        let verification_recipient = match msgtype {
+                            MessageType::VerificationRequest(e) => Some(user_id_to_string_representation(&mut user_ids_to_string_representations, room_info, &e.to, sender_hasher).await?),
+                            _ => None,
+                        };
+                        format!("{} {}{}", event_prefix, txt_message_text(msgtype, &media_link, verification_recipient.as_deref()), edit_suffix)
+                    },
+                    None => format!("{} [Redacted message]", event_prefix),
+                },
+                AnyMessageLikeEvent::RoomEncrypted(_) => format!("{} [Undecryptable encrypted message]", event_prefix),
+                _ => match (&call, &custom_event) {
+                    (Some(call), _) => format!("{}[{}] * {} {}", event_id_prefix, event_timestamp_string_representation, event_sender_string_representation, call.description(call_invitee.as_deref())),
+                    (None, Some((label, content))) => format!("{} [{}]: {}", event_prefix, label, content),
+                    (None, None) => String::from("[Placeholder message-like]"),
+                },
+            },
+            AnyTimelineEvent::State(e) => match (event_json.as_ref().and_then(widget_description), &custom_event) {
+                (Some(widget), _) => format!("{} {}", event_prefix, widget),
+                (None, Some((label, content))) => format!("{} [{}]: {}", event_prefix, label, content),
+                (None, None) if formatting.render_state => match event_json.as_ref().and_then(|event_json| state_change_description(event_json, sender_hasher)) {
+                    Some(description) => format!("{}[{}] * {} {}", event_id_prefix, event_timestamp_string_representation, event_sender_string_representation, description),
+                    None => format!("{} [State event of type {}]", event_prefix, e.event_type()),
+                },
+                (None, None) => String::from("[Placeholder state-like]"),
+            },
+        };
+
+        // Quoted oldest first, so that the chain reads downwards in order, with each level of ancestry getting another `>`
+        if formatting.flatten_replies {
+            let (ancestry, chain_continues) = reply_ancestry(event, &events_by_id);
+            if chain_continues {
+                event_text.push_str(&format!("{}[Earlier messages in this reply chain not included]\n", "> ".repeat(ancestry.len() + 1)));
+            }
+            for (depth, ancestor) in ancestry.iter().enumerate().rev() {
+                let quote_prefix = "> ".repeat(depth + 1);
+                let ancestor_sender = event_sender_string_representation(&mut user_ids_to_string_representations, room_info, ancestor, sender_hasher).await?;
+                event_text.push_str(&format!("{}[{}] {}: {}\n", quote_prefix, event_timestamp_string(ancestor), ancestor_sender, quoted_body(ancestor).replace('\n', &format!("\n{}", quote_prefix))));
+            }
+        }
+        event_text.push_str(&format!("{}\n", event_stringified));
+        event_texts.push(event_text);
+    }
+
+    // Given a line of their own, whether or not they end in a newline
+    let custom_text = |text: &Option<String>| text.as_ref().map(|text| format!("{}\n", text.trim_end_matches('\n'))).unwrap_or_default();
+    Ok(TxtExport {
+        custom_header: custom_text(&formatting.header_text),
+        custom_footer: custom_text(&formatting.footer_text),
+        header,
+        events: event_texts,
+        event_dates,
+        footer: if context.interrupted { "[Export interrupted]\n" } else { "" },
+    })
+}
+
+fn room_export_state_path(output_directory: &Path, room_info: &RoomWithCachedInfo) -> PathBuf {
+    output_directory.join(format!("{}.{}", make_filesystem_safe(room_info.id.as_str()), ROOM_EXPORT_STATE_EXTENSION))
+}
+
+fn read_room_export_state(output_directory: &Path, room_info: &RoomWithCachedInfo) -> anyhow::Result<Option<RoomExportState>> {
+    match read_to_string(room_export_state_path(output_directory, room_info)) {
+        Ok(state_file) => Ok(Some(serde_json::from_str(&state_file)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_room_export_state(output_directory: &Path, room_info: &RoomWithCachedInfo, state: &RoomExportState) -> Result<(), TraceError> {
+    let path = room_export_state_path(output_directory, room_info);
+    write(&path, serde_json::to_string(state).unwrap()).map_err(|e| TraceError::OutputIo { path, source: e })
+}
+
+// The record is only an optimization, so a damaged one just means rooms get exported again, rather than that nothing can be
+fn read_exported_room_ids(path: &Path) -> BTreeSet<String> {
+    let exported_room_ids = match read_to_string(path) {
+        Ok(exported_rooms_file) => serde_json::from_str(&exported_rooms_file).map_err(anyhow::Error::from),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e.into()),
+    };
+    exported_room_ids.unwrap_or_else(|e| {
+        eprintln!("Couldn't read the record of exported rooms at {}, so treating every room as new. Error: {}", path.display(), e);
+        BTreeSet::new()
+    })
+}
+
+fn write_exported_room_ids(path: &Path, room_ids: &BTreeSet<String>) -> Result<(), TraceError> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).map_err(|e| TraceError::OutputIo { path: parent.to_path_buf(), source: e })?;
+    }
+    write(path, serde_json::to_string(room_ids).unwrap()).map_err(|e| TraceError::OutputIo { path: path.to_path_buf(), source: e })
+}
+
+// Servers are free to return short (or even empty) chunks mid-room, so it's the end token, not the chunk length, that says whether there's more history: pagination continues until the end token is missing, or repeats the one just used (which would otherwise loop forever)
+fn next_pagination_token(previous_token: Option<&str>, end_token: Option<String>) -> Option<String> {
+    end_token.filter(|end_token| previous_token != Some(end_token.as_str()))
+}
+
+fn is_interrupted(interrupt: Option<&AtomicBool>) -> bool {
+    interrupt.is_some_and(|interrupt| interrupt.load(Ordering::SeqCst))
+}
+
+// Pagination's stopping conditions, apart from the fetching of pages itself, so that they can be exercised with made-up pages. `fetch_page` is given the token to fetch from, and returns that page's events and end token.
+async fn paginate<T, F, Fut>(from_token: Option<String>, interrupt: Option<&AtomicBool>, mut fetch_page: F) -> anyhow::Result<PaginatedEvents<T>>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = anyhow::Result<(Vec<T>, Option<String>)>>,
+{
+    let mut events = Vec::new();
+    let mut last_end_token = from_token;
+    loop {
+        if is_interrupted(interrupt) {
+            return Ok(PaginatedEvents { events, end_token: last_end_token, interrupted: true, stuck: false })
+        }
+        let (mut chunk, end_token) = fetch_page(last_end_token.clone()).await?;
+        // An empty chunk with the same token is just a server's way of saying there's nothing more; a non-empty one means it's stuck handing back the same page
+        let stuck = !chunk.is_empty() && last_end_token.is_some() && end_token == last_end_token;
+        events.append(&mut chunk);
+        if events.len() > 10_000_000 {
+            break
+        }
+        match next_pagination_token(last_end_token.as_deref(), end_token) {
+            Some(end_token) => last_end_token = Some(end_token),
+            None => return Ok(PaginatedEvents { events, end_token: last_end_token, interrupted: false, stuck }),
+        }
+    }
+
+    Ok(PaginatedEvents { events, end_token: last_end_token, interrupted: false, stuck: false })
+}
+
+// Returns the fetched events alongside the pagination token to resume from later. Where the server marks the end of the room by omitting the end token, that's the token the final chunk was fetched from, so the final chunk's events will be fetched again on resumption.
+// Also returns whether pagination was cut short by an interrupt, in which case the returned end token is that of the last page fetched, and so can be resumed from
+async fn fetch_room_events(room: &Room, from_token: Option<String>, batch_size: u16, rate_limiter: Option<&RateLimiter>, interrupt: Option<&AtomicBool>) -> anyhow::Result<(Vec<TimelineEvent>, Option<String>, bool)> {
+    let paginated_events = paginate(from_token, interrupt, |token| async move {
+        let mut messages_options = MessagesOptions::forward().from(token.as_deref());
+        messages_options.limit = batch_size.into();
+        rate_limit::acquire(rate_limiter).await;
+        let messages = room.messages(messages_options).await?;
+        Ok::<_, anyhow::Error>((messages.chunk, messages.end))
+    }).await?;
+    if paginated_events.stuck {
+        eprintln!("Warning for room {}: the homeserver returned the same pagination token it was given ({}) along with more events, so pagination stopped there rather than fetch the same page forever. The room's history may continue beyond this point.", room.room_id(), paginated_events.end_token.as_deref().unwrap_or_default());
+    }
+
+    Ok((paginated_events.events, paginated_events.end_token, paginated_events.interrupted))
+}
+
+async fn state_event_json(room: &Room, event_type: StateEventType) -> anyhow::Result<Option<serde_json::Value>> {
+    Ok(match room.get_state_event(event_type, "").await? {
+        Some(RawAnySyncOrStrippedState::Sync(event)) => Some(event.deserialize_as()?),
+        Some(RawAnySyncOrStrippedState::Stripped(event)) => Some(event.deserialize_as()?),
+        None => None,
+    })
+}
+
+// Event type to state key to event, fetched fresh from the server rather than from the sync store, which may only have some of the room's state (e.g. with lazy-loaded members)
+async fn room_state(room: &Room, sender_hasher: Option<&SenderHasher>) -> anyhow::Result<BTreeMap<String, BTreeMap<String, serde_json::Value>>> {
+    let response = room.client().send(get_state_events::v3::Request::new(room.room_id().to_owned()), None).await?;
+    let mut state: BTreeMap<String, BTreeMap<String, serde_json::Value>> = BTreeMap::new();
+    for event in response.room_state {
+        let mut event_json: serde_json::Value = event.deserialize_as()?;
+        if let Some(sender_hasher) = sender_hasher {
+            anonymize_event_json(&mut event_json, sender_hasher);
+        }
+        let event_type = event_json.get("type").and_then(|event_type| event_type.as_str()).unwrap_or_default().to_owned();
+        let state_key = event_json.get("state_key").and_then(|state_key| state_key.as_str()).unwrap_or_default().to_owned();
+        state.entry(event_type).or_default().insert(state_key, event_json);
+    }
+    Ok(state)
+}
+
+/// Summarizes a single joined room's metadata, as in summary-only exports. The room can be specified by any identifier accepted for room requests.
+pub async fn get_room_summary(client: &Client, room_identifier: &str) -> anyhow::Result<RoomSummary> {
+    let accessible_rooms_info = get_rooms_info(client).await?;
+    match get_room_index_by_identifier(&accessible_rooms_info, room_identifier) {
+        Ok(index) => room_summary(&accessible_rooms_info[index], None).await,
+        Err(e @ RoomIndexRetrievalError::MultipleRoomsWithSpecifiedName(_)) => anyhow::bail!(e.description(client.user_id().unwrap(), room_identifier)),
+        Err(e) => Err(TraceError::RoomNotFound(vec![e.description(client.user_id().unwrap(), room_identifier)]).into()),
+    }
+}
+
+async fn room_summary(room_info: &RoomWithCachedInfo, sender_hasher: Option<&SenderHasher>) -> anyhow::Result<RoomSummary> {
+    let room = &room_info.room;
+    let mut creation_event = state_event_json(room, StateEventType::RoomCreate).await?;
+    let mut power_levels = state_event_json(room, StateEventType::RoomPowerLevels).await?;
+    if let Some(sender_hasher) = sender_hasher {
+        if let Some(creation_event) = &mut creation_event {
+            anonymize_event_json(creation_event, sender_hasher);
+            if let Some(content) = creation_event.get_mut("content").and_then(|content| content.as_object_mut()) {
+                if let Some(creator) = content.get("creator").and_then(|creator| creator.as_str()).map(String::from) {
+                    content.insert(String::from("creator"), serde_json::Value::String(sender_hasher.hash(&creator)));
+                }
+            }
+        }
+        if let Some(power_levels) = &mut power_levels {
+            anonymize_event_json(power_levels, sender_hasher);
+        }
+    }
+
+    Ok(RoomSummary {
+        id: room_info.id.to_string(),
+        name: room_info.name.clone(),
+        topic: room.topic(),
+        canonical_alias: room_info.canonical_alias.as_ref().map(|alias| alias.to_string()),
+        alt_aliases: room_info.alt_aliases.iter().map(|alias| alias.to_string()).collect(),
+        joined_member_count: room_info.joined_member_count,
+        encrypted: room.is_encrypted().await?,
+        history_visibility: room.history_visibility().to_string(),
+        creation_event,
+        power_levels,
+    })
+}
+
+pub fn room_summary_to_txt(summary: &RoomSummary) -> String {
+    let mut summary_txt = String::new();
+    summary_txt.push_str(&format!("Room ID: {}\n", summary.id));
+    summary_txt.push_str(&format!("Name: {}\n", summary.name.as_deref().unwrap_or("[Unnamed]")));
+    summary_txt.push_str(&format!("Topic: {}\n", summary.topic.as_deref().unwrap_or("[No topic]")));
+    summary_txt.push_str(&format!("Canonical alias: {}\n", summary.canonical_alias.as_deref().unwrap_or("[No canonical alias]")));
+    summary_txt.push_str(&format!("Alternative aliases: {}\n", if summary.alt_aliases.is_empty() { String::from("[None]") } else { summary.alt_aliases.join(", ") }));
+    summary_txt.push_str(&format!("Joined members: {}\n", summary.joined_member_count));
+    summary_txt.push_str(&format!("Encrypted: {}\n", if summary.encrypted { "yes" } else { "no" }));
+    summary_txt.push_str(&format!("History visibility: {}\n", summary.history_visibility));
+    if let Some(creation_event) = &summary.creation_event {
+        let creator = creation_event.get("sender").and_then(|sender| sender.as_str()).unwrap_or("[Unknown]");
+        let created_at = creation_event.get("origin_server_ts").and_then(|timestamp| timestamp.as_i64()).and_then(DateTime::from_timestamp_millis).map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)).unwrap_or_else(|| String::from("[Unknown time]"));
+        summary_txt.push_str(&format!("Created by {} at {}\n", creator, created_at));
+    }
+    if let Some(users) = summary.power_levels.as_ref().and_then(|power_levels| power_levels.get("content")).and_then(|content| content.get("users")).and_then(|users| users.as_object()) {
+        summary_txt.push_str("Power levels:\n");
+        for (user_id, power_level) in users {
+            summary_txt.push_str(&format!("    {}: {}\n", user_id, power_level));
+        }
+    }
+
+    summary_txt
+}
+
+// Matrix identifiers can contain characters with special meanings in URIs (e.g. the `#` of an alias, or the `/` and `+` of base64-encoded event IDs), so those are escaped
+fn percent_encode_permalink_component(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for byte in component.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b':' | b'@' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+async fn room_permalinks(room: &Room, permalink_base: &str) -> anyhow::Result<RoomPermalinks> {
+    let mut server_member_counts: HashMap<String, usize> = HashMap::new();
+    for member in room.members(RoomMemberships::JOIN).await? {
+        *server_member_counts.entry(member.user_id().server_name().to_string()).or_default() += 1;
+    }
+    let mut servers = server_member_counts.into_iter().collect::<Vec<(String, usize)>>();
+    servers.sort_by(|(server_1, count_1), (server_2, count_2)| count_2.cmp(count_1).then_with(|| server_1.cmp(server_2)));
+    let via_query = servers.iter().take(PERMALINK_VIA_SERVER_COUNT).enumerate().map(|(index, (server, _count))| format!("{}via={}", if index == 0 { "?" } else { "&" }, percent_encode_permalink_component(server))).collect::<String>();
+
+    Ok(RoomPermalinks {
+        room_prefix: format!("{}{}", permalink_base, percent_encode_permalink_component(room.room_id().as_str())),
+        via_query,
+    })
+}
+
+async fn read_receipts(room: &Room, events: &[TimelineEvent]) -> anyhow::Result<BTreeMap<String, Vec<ExportedReadReceipt>>> {
+    let mut read_receipts = BTreeMap::new();
+    for event in events {
+        let Some(event_id) = event.event.get_field::<OwnedEventId>("event_id").ok().flatten() else {
+            continue
+        };
+        let mut event_receipts = Vec::new();
+        for thread in [ReceiptThread::Unthreaded, ReceiptThread::Main] {
+            for (user_id, receipt) in room.load_event_receipts(ReceiptType::Read, thread, &event_id).await? {
+                event_receipts.push(ExportedReadReceipt {
+                    user_id: user_id.to_string(),
+                    timestamp: receipt.ts.and_then(|ts| DateTime::from_timestamp_millis(u64::from(ts.0) as i64)).map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                });
+            }
+        }
+        if !event_receipts.is_empty() {
+            read_receipts.insert(event_id.to_string(), event_receipts);
+        }
+    }
+
+    Ok(read_receipts)
+}
+
+// Pinned events outside the exported range are fetched one by one (with Room::event, which also decrypts them), since pins are few but can be arbitrarily old. Those in the range are taken from the export, already transformed, so only the fetched ones need transforming here.
+async fn pinned_events(room: &Room, events: &[TimelineEvent], rate_limiter: Option<&RateLimiter>, event_transform: Option<&EventTransform>) -> anyhow::Result<Vec<PinnedEvent>> {
+    let Some(pinned_events_state) = state_event_json(room, StateEventType::RoomPinnedEvents).await? else {
+        return Ok(Vec::new())
+    };
+    let exported_events = events_by_id(events);
+    let mut pinned_events = Vec::new();
+    for event_id in pinned_events_state.pointer("/content/pinned").and_then(|pinned| pinned.as_array()).into_iter().flatten().filter_map(|event_id| event_id.as_str()) {
+        let pinned_event = match exported_events.get(event_id) {
+            Some(event) => PinnedEvent {
+                event_id: event_id.to_owned(),
+                event: Some((*event).clone()),
+                in_export: true,
+            },
+            None => PinnedEvent {
+                event_id: event_id.to_owned(),
+                event: match EventId::parse(event_id) {
+                    Ok(event_id) => {
+                        rate_limit::acquire(rate_limiter).await;
+                        room.event(&event_id).await.ok().map(|event| match event_transform {
+                            Some(event_transform) => transformed_event(event, event_transform),
+                            None => event,
+                        })
+                    }
+                    Err(_) => None,
+                },
+                in_export: false,
+            },
+        };
+        pinned_events.push(pinned_event);
+    }
+    Ok(pinned_events)
+}
+
+fn transformed_event(mut event: TimelineEvent, event_transform: &EventTransform) -> TimelineEvent {
+    let Ok(mut event_json) = event.event.deserialize_as::<serde_json::Value>() else {
+        return event
+    };
+    if !event_json.is_object() {
+        return event
+    }
+    event_transform(&mut event_json);
+    if let Ok(transformed_raw) = Raw::new(&event_json) {
+        event.event = transformed_raw.cast();
+    }
+    event
+}
+
+// Events from the context endpoint arrive undecrypted, unlike those from Room::messages, so this does the decryption Room::messages would have done
+async fn timeline_event_from_raw(room: &Room, event: Raw<AnyTimelineEvent>) -> TimelineEvent {
+    if event.get_field::<String>("type").ok().flatten().as_deref() == Some("m.room.encrypted") {
+        if let Ok(decrypted_event) = room.decrypt_event(event.cast_ref::<OriginalSyncRoomEncryptedEvent>()).await {
+            return decrypted_event
+        }
+    }
+    TimelineEvent::new(event)
+}
+
+async fn fetch_event_context(room: &Room, event_id: &EventId, context_lines: u16, rate_limiter: Option<&RateLimiter>) -> anyhow::Result<Vec<TimelineEvent>> {
+    let mut request = get_context::v3::Request::new(room.room_id().to_owned(), event_id.to_owned());
+    request.limit = UInt::from(u32::from(context_lines) * 2); // The limit covers events before and after combined
+    rate_limit::acquire(rate_limiter).await;
+    let response = match room.client().send(request, None).await {
+        Ok(response) => response,
+        Err(e) if matches!(e.client_api_error_kind(), Some(ErrorKind::NotFound)) => anyhow::bail!("Event {} isn't in room {} (or isn't visible to this account).", event_id, room.room_id()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut events = Vec::new();
+    for event in response.events_before.into_iter().rev() { // Served newest-first
+        events.push(timeline_event_from_raw(room, event).await);
+    }
+    if let Some(event) = response.event {
+        events.push(timeline_event_from_raw(room, event).await);
+    }
+    for event in response.events_after {
+        events.push(timeline_event_from_raw(room, event).await);
+    }
+
+    Ok(events)
+}
+
+// Higher is better; decryptability is considered more important than unredactedness, since a redacted event has lost its content on every copy anyway
+fn event_copy_preference(event: &TimelineEvent) -> (bool, bool) {
+    let is_decrypted = event.event.get_field::<String>("type").ok().flatten().is_some_and(|event_type| event_type != "m.room.encrypted");
+    let is_unredacted = event.event.get_field::<serde_json::Value>("unsigned").ok().flatten().map_or(true, |unsigned| unsigned.get("redacted_because").is_none());
+    (is_decrypted, is_unredacted)
+}
+
+fn merge_events(primary_events: Vec<TimelineEvent>, additional_events: Vec<Vec<TimelineEvent>>) -> Vec<TimelineEvent> {
+    if additional_events.iter().all(|events| events.is_empty()) {
+        return primary_events
+    }
+
+    let mut merged_events: Vec<TimelineEvent> = Vec::new();
+    let mut event_id_indices: HashMap<String, usize> = HashMap::new();
+    for event in primary_events.into_iter().chain(additional_events.into_iter().flatten()) {
+        match event.event.get_field::<String>("event_id").ok().flatten() {
+            Some(event_id) => match event_id_indices.get(&event_id) {
+                Some(index) => if event_copy_preference(&event) > event_copy_preference(&merged_events[*index]) {
+                    merged_events[*index] = event;
+                },
+                None => {
+                    event_id_indices.insert(event_id, merged_events.len());
+                    merged_events.push(event);
+                }
+            },
+            None => merged_events.push(event),
+        }
+    }
+    // Different accounts' timelines can only be interleaved by timestamp; the sort is stable, so the main client's ordering wins ties
+    merged_events.sort_by_key(|event| event.event.get_field::<u64>("origin_server_ts").ok().flatten().unwrap_or(0));
+
+    merged_events
+}
+
+// Groups consecutive items into parts whose sizes (plus a fixed overhead for each part) stay within the maximum, if possible; an item too big to fit even on its own gets a part to itself. There's always at least one part, even with no items.
+fn split_into_parts(item_sizes: &[usize], overhead: usize, max_size: u64) -> Vec<Range<usize>> {
+    let mut parts = Vec::new();
+    let (mut part_start, mut part_size) = (0, overhead);
+    for (index, item_size) in item_sizes.iter().enumerate() {
+        if index > part_start && (part_size + item_size) as u64 > max_size {
+            parts.push(part_start..index);
+            part_start = index;
+            part_size = overhead;
+        }
+        part_size += item_size;
+    }
+    parts.push(part_start..item_sizes.len());
+    parts
+}
+
+fn txt_export_parts(txt_export: &TxtExport, max_file_size: Option<u64>) -> Vec<String> {
+    let Some(max_file_size) = max_file_size else {
+        return vec![format!("{}{}{}{}{}", txt_export.custom_header, txt_export.header, txt_export.events.concat(), txt_export.footer, txt_export.custom_footer)]
+    };
+    let event_sizes = txt_export.events.iter().map(String::len).collect::<Vec<_>>();
+    let overhead = txt_export.custom_header.len() + txt_export.header.len() + txt_export.footer.len() + txt_export.custom_footer.len() + PART_LABEL_ALLOWANCE;
+    let parts = split_into_parts(&event_sizes, overhead, max_file_size);
+    let part_count = parts.len();
+    parts.into_iter().enumerate().map(|(index, part)| {
+        let part_label = if part_count > 1 { format!("[Part {} of {}]\n", index + 1, part_count) } else { String::new() };
+        let footer = if index + 1 == part_count { txt_export.footer } else { "" };
+        format!("{}{}{}{}{}{}", txt_export.custom_header, part_label, txt_export.header, txt_export.events[part].concat(), footer, txt_export.custom_footer)
+    }).collect()
+}
+
+// Events without a timestamp stay with the events before them (or, at the very start, the events after them). Each day repeats the header, as split parts do. Days with no events get no entry at all.
+fn txt_export_days(txt_export: TxtExport) -> Vec<(NaiveDate, TxtExport)> {
+    let TxtExport { custom_header, custom_footer, header, events, event_dates, footer } = txt_export;
+    let mut current_date = event_dates.iter().flatten().next().copied();
+    let mut days: Vec<(NaiveDate, TxtExport)> = Vec::new();
+    for (event, event_date) in events.into_iter().zip(event_dates) {
+        current_date = event_date.or(current_date);
+        let Some(date) = current_date else {
+            continue
+        };
+        match days.last_mut() {
+            Some((day_date, day)) if *day_date == date => {
+                day.events.push(event);
+                day.event_dates.push(Some(date));
+            }
+            _ => days.push((date, TxtExport {
+                custom_header: custom_header.clone(),
+                custom_footer: custom_footer.clone(),
+                header: header.clone(),
+                events: vec![event],
+                event_dates: vec![Some(date)],
+                footer: "",
+            })),
+        }
+    }
+    if let Some((_date, last_day)) = days.last_mut() {
+        last_day.footer = footer;
+    }
+    days
+}
+
+fn json_export_parts(json_export: &JsonExport, compact: bool, max_file_size: Option<u64>) -> serde_json::Result<Vec<String>> {
+    let Some(max_file_size) = max_file_size else {
+        return Ok(vec![json_to_string(json_export, compact)?])
+    };
+    // Pretty-printed messages are nested two levels deep, so each of their lines gains four spaces of indentation, and each is followed by a comma and newline
+    let mut message_sizes = Vec::new();
+    for message in &json_export.messages {
+        let message_string = json_to_string(message, compact)?;
+        message_sizes.push(if compact { message_string.len() + 1 } else { message_string.len() + 4 * message_string.lines().count() + 2 });
+    }
+    let overhead = json_to_string(&JsonExportPart { room: &json_export.room, part: 0, part_count: 0, messages: &[] }, compact)?.len() + PART_LABEL_ALLOWANCE;
+    let parts = split_into_parts(&message_sizes, overhead, max_file_size);
+    if parts.len() == 1 {
+        return Ok(vec![json_to_string(json_export, compact)?])
+    }
+    let part_count = parts.len();
+    parts.into_iter().enumerate().map(|(index, part)| json_to_string(&JsonExportPart {
+        room: &json_export.room,
+        part: index + 1,
+        part_count,
+        messages: &json_export.messages[part],
+    }, compact)).collect()
+}
+
+fn write_export_parts(output_directory: &Path, base_filename: &str, extension: &str, parts: Vec<Vec<u8>>, to_stdout: bool, existing_file_policy: ExistingFilePolicy) -> Result<(), TraceError> {
+    let part_count = parts.len();
+    for (index, contents) in parts.into_iter().enumerate() {
+        let filename = if part_count > 1 {
+            format!("{}.part{}.{}", base_filename, index + 1, extension)
+        } else {
+            format!("{}.{}", base_filename, extension)
+        };
+        write_export_file(output_directory, filename, contents, to_stdout, existing_file_policy)?;
+    }
+    Ok(())
+}
+
+// encoding_rs already replaces unmappable characters with numeric character references, so only erroring on them needs handling here
+fn encode_txt(contents: String, encoding: Option<&'static Encoding>, unmappable_character_policy: UnmappableCharacterPolicy) -> anyhow::Result<Vec<u8>> {
+    let Some(encoding) = encoding else {
+        return Ok(contents.into_bytes())
+    };
+    let (encoded, _, had_unmappable_characters) = encoding.encode(&contents);
+    if had_unmappable_characters && unmappable_character_policy == UnmappableCharacterPolicy::Error {
+        let unmappable_character = contents.chars().find(|c| encoding.encode(c.encode_utf8(&mut [0; 4])).2).unwrap_or_default();
+        anyhow::bail!("The txt export contains a character ({:?}, U+{:04X}) which can't be represented in {}.", unmappable_character, unmappable_character as u32, encoding.name());
+    }
+    Ok(encoded.into_owned())
+}
+
+// As `write_export_file`, but for contents which are written out bit by bit rather than all at once
+fn write_streamed_export_file(output_directory: &Path, filename: String, to_stdout: bool, existing_file_policy: ExistingFilePolicy, write_contents: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> Result<(), TraceError> {
+    if to_stdout {
+        let mut stdout = io::stdout().lock();
+        write_contents(&mut stdout).and_then(|()| stdout.flush()).map_err(|e| TraceError::OutputIo { path: PathBuf::from(STDOUT_SENTINEL), source: e })
+    } else {
+        let path = output_directory.join(filename);
+        let file = match existing_file_policy {
+            ExistingFilePolicy::Overwrite => File::create(&path),
+            ExistingFilePolicy::NoClobber => OpenOptions::new().write(true).create_new(true).open(&path),
+        };
+        let write_result = file.and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write_contents(&mut writer)?;
+            writer.flush()
+        });
+        write_result.map_err(|e| TraceError::OutputIo { path, source: e })
+    }
+}
+
+fn write_export_file(output_directory: &Path, filename: String, contents: impl AsRef<[u8]>, to_stdout: bool, existing_file_policy: ExistingFilePolicy) -> Result<(), TraceError> {
+    if to_stdout {
+        io::stdout().write_all(contents.as_ref()).map_err(|e| TraceError::OutputIo { path: PathBuf::from(STDOUT_SENTINEL), source: e })
+    } else {
+        let path = output_directory.join(filename);
+        let write_result = match existing_file_policy {
+            ExistingFilePolicy::Overwrite => write(&path, contents),
+            ExistingFilePolicy::NoClobber => OpenOptions::new().write(true).create_new(true).open(&path).and_then(|mut file| file.write_all(contents.as_ref())),
+        };
+        write_result.map_err(|e| TraceError::OutputIo { path, source: e })
+    }
+}
+
+// Fetches the room's events (from wherever the export starts, and from every client), then filters them, noting anything amiss on the way
+async fn collect_room_events(room_identifier: &str, room_info: &RoomWithCachedInfo, previous_export_state: Option<&RoomExportState>, options: &EventCollectionOptions<'_>) -> anyhow::Result<CollectedEvents> {
+    let (primary_events, last_end_token, mut interrupted) = match (options.context_event_id, previous_export_state) {
+        (Some(event_id), _) => (fetch_event_context(&room_info.room, event_id, options.context_lines, options.rate_limiter).await?, None, false),
+        (None, Some(state)) => {
+            let (mut events, end_token, interrupted) = fetch_room_events(&room_info.room, state.end_token.clone(), options.batch_size, options.rate_limiter, options.interrupt).await?;
+            if let Some(last_event_id) = &state.last_event_id {
+                if let Some(last_exported_index) = events.iter().position(|event| event.event.get_field::<String>("event_id").ok().flatten().as_ref() == Some(last_event_id)) {
+                    events.drain(..=last_exported_index);
+                }
+            }
+            (events, end_token, interrupted)
+        },
+        (None, None) => match options.from_token {
+            Some(token) => fetch_room_events(&room_info.room, Some(String::from(token)), options.batch_size, options.rate_limiter, options.interrupt).await.map_err(|e| anyhow::anyhow!("Couldn't paginate room {} from token {}: {}", room_identifier, token, e))?,
+            None => fetch_room_events(&room_info.room, None, options.batch_size, options.rate_limiter, options.interrupt).await?,
+        },
+    };
+    let last_event_id = primary_events.iter().rev().find_map(|event| event.event.get_field::<String>("event_id").ok().flatten()).or_else(|| previous_export_state.and_then(|state| state.last_event_id.clone()));
+    let mut additional_events = Vec::new();
+    for additional_client in options.additional_clients {
+        match additional_client.get_room(&room_info.id) {
+            Some(room) => {
+                let (events, _end_token, additional_interrupted) = fetch_room_events(&room, None, options.batch_size, options.rate_limiter, options.interrupt).await?;
+                additional_events.push(events);
+                interrupted |= additional_interrupted;
+            }
+            None => eprintln!("Additional account {} isn't in room {}, so it couldn't contribute to that room's export.", additional_client.user_id().unwrap(), room_identifier),
+        }
+    }
+    let events = merge_events(primary_events, additional_events);
+
+    let undecryptable_megolm_session_ids = undecryptable_megolm_session_ids(&events);
+
+    let mut warnings = Vec::new();
+    // Resumed, context, and from-token exports all start part-way through the room, so their first event is never the creation event anyway
+    if previous_export_state.is_none() && options.context_event_id.is_none() && options.from_token.is_none() {
+        if let Some(warning) = history_visibility_warning(room_info, &events) {
+            eprintln!("Warning for room {}: {}", room_identifier, warning);
+            warnings.push(warning);
+        }
+    }
+    let gaps = match options.gap_threshold {
+        Some(gap_threshold) => possible_history_gaps(&events, gap_threshold),
+        None => Vec::new(),
+    };
+    if let Some(warning) = options.gap_threshold.and_then(|gap_threshold| history_gaps_warning(&gaps, gap_threshold)) {
+        eprintln!("Warning for room {}: {}", room_identifier, warning);
+        warnings.push(warning);
+    }
+
+    let display_names_at_event_time = if options.display_names_at_event_time {
+        event_time_display_names(&events)
+    } else {
+        HashMap::new()
+    };
+
+    // Filtered only after the history-visibility, gap, and display name checks, which need to see the room's events whatever they are
+    let events = if options.media_only {
+        events.into_iter().filter(|event| media_attachment(event).is_some() || event.event.get_field::<String>("type").ok().flatten().as_deref() == Some("m.sticker")).collect()
+    } else {
+        events
+    };
+    let events = match options.exclude_sender_regex {
+        Some(exclude_sender_regex) => events.into_iter().filter(|event| !event.event.get_field::<String>("sender").ok().flatten().is_some_and(|sender| exclude_sender_regex.is_match(&sender))).collect(),
+        None => events,
+    };
+    let events = match options.event_transform {
+        Some(event_transform) => events.into_iter().map(|event| transformed_event(event, event_transform)).collect(),
+        None => events,
+    };
+
+    Ok(CollectedEvents {
+        events,
+        warnings,
+        gaps,
+        display_names_at_event_time,
+        undecryptable_megolm_session_ids,
+        last_end_token,
+        last_event_id,
+        interrupted,
+    })
+}
+
+// Downloads the attachments of the room's events into the media directory, returning relative links to them (if those were requested) and data URIs of the images to inline, keyed by event ID
+async fn download_room_media(client: &Client, room_identifier: &str, events: &[TimelineEvent], media_directory: &Path, media_variant: MediaVariant, media_link_kind: MediaLinkKind, inline_image_max_size: Option<u64>, media_download_semaphore: &Semaphore, rate_limiter: Option<&RateLimiter>, warnings: &mut Vec<String>) -> anyhow::Result<(BTreeMap<String, String>, HashMap<String, String>)> {
+    create_dir_all(media_directory).map_err(|e| TraceError::OutputIo { path: media_directory.to_path_buf(), source: e })?;
+    let attachments = events.iter().filter_map(media_attachment).collect::<Vec<MediaAttachment>>();
+    // Attachments reposted under the same name share a file, so each file is downloaded once for all of its events, rather than by several concurrent downloads which would trample one another's writes
+    let mut attachments_by_filename: BTreeMap<String, (&MediaAttachment, Vec<String>)> = BTreeMap::new();
+    for attachment in &attachments {
+        attachments_by_filename.entry(attachment_media_filename(attachment, media_variant)).or_insert((attachment, Vec::new())).1.push(attachment.event_id.to_string());
+    }
+    let downloads = join_all(attachments_by_filename.into_values().map(|(attachment, event_ids)| async move {
+        let download = async {
+            let _permit = media_download_semaphore.acquire().await?;
+            anyhow::Result::<String>::Ok(download_media(client, attachment, media_variant, media_directory, rate_limiter).await?)
+        }.await;
+        (event_ids, download)
+    })).await;
+
+    let mut media_links = BTreeMap::new();
+    let mut inline_images = HashMap::new();
+    // A failed download costs only its own attachment, which is left unlinked, rather than the room's whole export
+    for (event_ids, download) in downloads {
+        let media_filename = match download {
+            Ok(media_filename) => media_filename,
+            Err(e) => {
+                let event_label = if event_ids.len() == 1 { "event" } else { "events" };
+                let warning = format!("Couldn't download the attachment of {} {}, so it isn't included. Error: {:#}", event_label, event_ids.join(", "), e);
+                eprintln!("Warning for room {}: {}", room_identifier, warning);
+                warnings.push(warning);
+                continue
+            }
+        };
+        let data_uri = match inline_image_max_size {
+            Some(inline_image_max_size) => inline_image_data_uri(&media_directory.join(&media_filename), inline_image_max_size)?,
+            None => None,
+        };
+        for event_id in event_ids {
+            if let Some(data_uri) = &data_uri {
+                inline_images.insert(event_id.clone(), data_uri.clone());
+            }
+            if media_link_kind == MediaLinkKind::Relative {
+                media_links.insert(event_id, format!("{}/{}", MEDIA_DIRECTORY_NAME, media_filename));
+            }
+        }
+    }
+    Ok((media_links, inline_images))
+}
+
+// Formats and writes a room's export files, in every requested format. Single-file exports are collected by the caller instead, and written once every room is done.
+async fn write_room_export(base_output_path: &Path, base_output_filename: &str, events: &Vec<TimelineEvent>, context: &RoomExportContext<'_>, options: &RoomOutputOptions<'_>) -> anyhow::Result<()> {
+    let RoomOutputOptions { formats, formatting, to_stdout, existing_file_policy, .. } = *options;
+    if options.raw_dump {
+        write_export_file(base_output_path, format!("{}.raw.json", base_output_filename), raw_event_dump(events), to_stdout, existing_file_policy)?;
+    }
+    if options.thread_summary {
+        write_export_file(base_output_path, format!("{} [threads].json", base_output_filename), json_to_string(&thread_summaries(events, formatting.sender_hasher.as_ref()), formatting.json_compact)?, to_stdout, existing_file_policy)?;
+    }
+    if formats.contains(&ExportOutputFormat::Json) && !options.single_file {
+        if options.stream_json {
+            write_streamed_export_file(base_output_path, format!("{}.json", base_output_filename), to_stdout, existing_file_policy, |writer| write_json_export_streamed(writer, events, context, formatting))?;
+        } else {
+            let json_output_parts = json_export_parts(&json_export(events, context, formatting), formatting.json_compact, options.max_file_size)?;
+            write_export_parts(base_output_path, base_output_filename, "json", json_output_parts.into_iter().map(String::into_bytes).collect(), to_stdout, existing_file_policy)?;
+        }
+    }
+    if formats.contains(&ExportOutputFormat::Txt) {
+        let room_txt_export = txt_export(events, context, formatting).await?;
+        // A room with no dated messages has no days to split into, so it gets the usual single file, saying as much
+        let txt_exports = if options.split_txt_by_day && room_txt_export.event_dates.iter().any(Option::is_some) {
+            txt_export_days(room_txt_export).into_iter().map(|(date, day_txt_export)| (format!("{} {}", base_output_filename, date), day_txt_export)).collect()
+        } else {
+            vec![(String::from(base_output_filename), room_txt_export)]
+        };
+        for (txt_output_filename, txt_export) in txt_exports {
+            let txt_output_parts = txt_export_parts(&txt_export, options.max_file_size);
+            write_export_parts(base_output_path, &txt_output_filename, "txt", txt_output_parts.into_iter().map(|part| encode_txt(part, options.txt_encoding, options.unmappable_character_policy)).collect::<anyhow::Result<_>>()?, to_stdout, existing_file_policy)?;
+        }
+    }
+    if formats.contains(&ExportOutputFormat::Html) {
+        let html_output_file = messages_to_html(events, context, formatting).await?;
+        write_export_file(base_output_path, format!("{}.html", base_output_filename), html_output_file, to_stdout, existing_file_policy)?;
+    }
+    if formats.contains(&ExportOutputFormat::Sqlite) {
+        write_sqlite_export(&base_output_path.join(SQLITE_EXPORT_FILENAME), events, context, formatting).await?;
+    }
+    Ok(())
+}
+
+/// Exports the specified rooms, returning the number of rooms exported. If the output path is `-`, the export is written to stdout rather than to a file, in which case exactly one room and one format must be requested so that their output can't collide on the one stream.
+pub async fn export(client: &Client, rooms: Vec<String>, options: ExportOptions) -> anyhow::Result<usize> {
+    options.validate(&rooms)?;
+    let to_stdout = options.to_stdout();
+    let records_exported_rooms = options.records_exported_rooms();
+    let ExportOptions {
+        output_path,
+        formats,
+        datestamp_format,
+        since_last_export,
+        mark_read,
+        download_media: should_download_media,
+        media_variant,
+        inline_image_max_size,
+        media_link_kind,
+        media_concurrency,
+        all_rooms,
+        min_members,
+        max_members,
+        encrypted,
+        only_new_rooms,
+        exported_rooms_path,
+        name_pattern,
+        accept_invites,
+        exclude_rooms,
+        fail_on_decrypt_error,
+        additional_clients,
+        raw_json,
+        raw_dump,
+        json_compact,
+        stream_json,
+        sender_hasher,
+        rate_limit,
+        include_read_receipts,
+        summary_only,
+        reactions_summary,
+        state_only,
+        count_only,
+        thread_summary,
+        mark_edits_in_txt,
+        show_event_ids_in_txt,
+        render_state,
+        flatten_replies,
+        display_names_at_event_time,
+        txt_encoding,
+        unmappable_character_policy,
+        permalink_base,
+        media_only,
+        exclude_sender_pattern,
+        event_transform,
+        max_file_size,
+        split_txt_by_day,
+        gap_threshold,
+        header_text,
+        footer_text,
+        skip_empty,
+        context_event_id,
+        context_lines,
+        from_token,
+        single_file,
+        batch_size,
+        existing_file_policy,
+        run_log,
+        interrupt,
+    } = options;
+    let interrupt = interrupt.as_deref();
+    let batch_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+    let formatting = FormattingOptions {
+        raw_json,
+        sender_hasher,
+        mark_edits_in_txt,
+        show_event_ids_in_txt,
+        render_state,
+        flatten_replies,
+        json_compact,
+        header_text,
+        footer_text,
+    };
+
+    let name_regex = name_pattern.as_deref().map(full_match_regex).transpose()?;
+    let exclude_sender_regex = exclude_sender_pattern.as_deref().map(full_match_regex).transpose()?;
+    let exported_rooms_path = exported_rooms_path.filter(|_| records_exported_rooms);
+    let media_concurrency = media_concurrency.unwrap_or(DEFAULT_MEDIA_CONCURRENCY);
+    let rate_limiter = rate_limit.map(RateLimiter::new);
+    // Shared across rooms, so that the limit is on the export as a whole
+    let media_download_semaphore = Semaphore::new(media_concurrency);
+
+    // Computed once up-front so that every room in a run gets the same stamp, even if the run crosses midnight
+    let datestamp = datestamp_format.map(|format| make_filesystem_safe(&Local::now().format(&format).to_string()));
+
+    if let Some(path) = output_path.as_ref().filter(|_| !to_stdout && !count_only) {
+        if path.exists() {
+            if !path.is_dir() {
+                return Err(TraceError::OutputIo {
+                    path: path.clone(),
+                    source: io::Error::other("output path isn't a directory"),
+                }.into());
+            }
+        } else {
+            create_dir_all(path).map_err(|e| TraceError::OutputIo { path: path.clone(), source: e })?;
+        }
+    }
+
+    if accept_invites {
+        accept_requested_invites(client, &rooms, run_log.as_ref()).await?;
+    }
+
+    let accessible_rooms_info = get_rooms_info(&client).await?; // This should be possible to optimize out for request-piles without names included, given client.resolve_room_alias and client.get_room. Although that might end up actually costlier if handled indelicately, since it'll involve more serial processing.
+
+    // Pairs of the identifier each room was requested by and its index in accessible_rooms_info
+    let mut rooms_to_export = Vec::new();
+    let mut room_failures = Vec::new();
+    let previously_exported_room_ids = match &exported_rooms_path {
+        Some(exported_rooms_path) => read_exported_room_ids(exported_rooms_path),
+        None => BTreeSet::new(),
+    };
+    let mut exported_room_ids = previously_exported_room_ids.clone();
+    if all_rooms {
+        let candidate_rooms = accessible_rooms_info.iter().enumerate()
+            .filter(|(_index, room_info)| min_members.map_or(true, |min_members| room_info.joined_member_count >= min_members))
+            .filter(|(_index, room_info)| max_members.map_or(true, |max_members| room_info.joined_member_count <= max_members))
+            .filter(|(_index, room_info)| !only_new_rooms || !previously_exported_room_ids.contains(room_info.id.as_str()));
+        for (index, room_info) in candidate_rooms {
+            // Only checked definitively when filtering on it, since that can take a request per room
+            if let Some(encrypted) = encrypted {
+                let room_encrypted = match room_info.room.is_encrypted().await {
+                    Ok(room_encrypted) => room_encrypted,
+                    Err(e) => {
+                        eprintln!("Couldn't check whether room {} is encrypted, so going by the last sync, which says it {}. Error: {}", room_info.id, if room_info.encrypted { "is" } else { "isn't" }, e);
+                        room_info.encrypted
+                    }
+                };
+                if room_encrypted != encrypted {
+                    continue
+                }
+            }
+            rooms_to_export.push((room_info.id.to_string(), index));
+        }
+    }
+    if let Some(name_regex) = &name_regex {
+        let mut name_match_count = 0;
+        for (index, room_info) in accessible_rooms_info.iter().enumerate() {
+            if room_info.name.as_ref().is_some_and(|name| name_regex.is_match(name)) {
+                name_match_count += 1;
+                if !rooms_to_export.iter().any(|(_identifier, preexisting_index)| *preexisting_index == index) {
+                    rooms_to_export.push((room_info.id.to_string(), index));
+                }
+            }
+        }
+        eprintln!("Name pattern matched {} rooms.", name_match_count);
+    }
+    for room_identifier in rooms {
+        match get_room_index_by_identifier(&accessible_rooms_info, &room_identifier) {
+            Ok(index) => if !rooms_to_export.iter().any(|(_identifier, preexisting_index)| *preexisting_index == index) {
+                rooms_to_export.push((room_identifier, index))
+            },
+            Err(e) => room_failures.push(e.description(client.user_id().unwrap(), room_identifier.as_str())),
+        }
+    }
+    rooms_to_export.retain(|(_identifier, index)| !exclude_rooms.iter().any(|excluded_identifier| room_matches_identifier(&accessible_rooms_info[*index], excluded_identifier)));
+
+    let event_collection = EventCollectionOptions {
+        context_event_id: context_event_id.as_deref(),
+        context_lines,
+        from_token: from_token.as_deref(),
+        batch_size,
+        rate_limiter: rate_limiter.as_ref(),
+        interrupt,
+        additional_clients: &additional_clients,
+        gap_threshold,
+        display_names_at_event_time,
+        media_only,
+        exclude_sender_regex: exclude_sender_regex.as_ref(),
+        event_transform: event_transform.as_ref(),
+    };
+    let room_output = RoomOutputOptions {
+        formats: &formats,
+        formatting: &formatting,
+        to_stdout,
+        existing_file_policy,
+        raw_dump,
+        thread_summary,
+        single_file,
+        stream_json,
+        max_file_size,
+        split_txt_by_day,
+        txt_encoding,
+        unmappable_character_policy,
+    };
+
+    let mut exported_room_count = 0;
+    let mut failed_export_count = 0;
+    let mut missing_megolm_session_ids = BTreeSet::new();
+    let mut export_interrupted = false;
+    let mut total_event_count = 0;
+    // Room ID to that room's json export
+    let mut single_file_export = BTreeMap::new();
+    for (room_identifier, room_index) in rooms_to_export {
+        if is_interrupted(interrupt) {
+            export_interrupted = true;
+            break
+        }
+        let room_to_export_info = &accessible_rooms_info[room_index];
+
+        // In bulk mode, each room gets its own error boundary, so that one bad room doesn't sink an unattended full-account backup
+        run_log::record(run_log.as_ref(), "room_start", json!({ "room": room_identifier, "room_id": room_to_export_info.id.as_str() }));
+        // Returns the number of events exported, and whether the export was interrupted partway through
+        let room_export_result: anyhow::Result<(usize, bool)> = async {
+            let base_output_path = output_path.clone().filter(|_| !to_stdout).unwrap_or_else(|| PathBuf::new());
+
+            if summary_only {
+                let summary = room_summary(room_to_export_info, formatting.sender_hasher.as_ref()).await?;
+                let base_output_filename = match &datestamp {
+                    Some(datestamp) => format!("{} {} [summary]", format_export_filename(&room_to_export_info), datestamp),
+                    None => format!("{} [summary]", format_export_filename(&room_to_export_info)),
+                };
+                if formats.contains(&ExportOutputFormat::Json) {
+                    write_export_file(&base_output_path, format!("{}.json", base_output_filename), json_to_string(&summary, formatting.json_compact)?, to_stdout, existing_file_policy)?;
+                }
+                if formats.contains(&ExportOutputFormat::Txt) {
+                    write_export_file(&base_output_path, format!("{}.txt", base_output_filename), encode_txt(room_summary_to_txt(&summary), txt_encoding, unmappable_character_policy)?, to_stdout, existing_file_policy)?;
+                }
+                if formats.contains(&ExportOutputFormat::Html) {
+                    write_export_file(&base_output_path, format!("{}.html", base_output_filename), text_to_html(&base_output_filename, &room_summary_to_txt(&summary)), to_stdout, existing_file_policy)?;
+                }
+                return Ok((0, false))
+            }
+            if state_only {
+                let state = room_state(&room_to_export_info.room, formatting.sender_hasher.as_ref()).await?;
+                let base_output_filename = match &datestamp {
+                    Some(datestamp) => format!("{} {} [state]", format_export_filename(&room_to_export_info), datestamp),
+                    None => format!("{} [state]", format_export_filename(&room_to_export_info)),
+                };
+                write_export_file(&base_output_path, format!("{}.json", base_output_filename), json_to_string(&state, formatting.json_compact)?, to_stdout, existing_file_policy)?;
+                return Ok((0, false))
+            }
+
+            let previous_export_state = if since_last_export {
+                read_room_export_state(&base_output_path, room_to_export_info)?
+            } else {
+                None
+            };
+            let export_start_time = Utc::now();
+
+            let CollectedEvents {
+                events,
+                mut warnings,
+                gaps,
+                display_names_at_event_time,
+                undecryptable_megolm_session_ids: mut room_undecryptable_megolm_session_ids,
+                last_end_token,
+                last_event_id,
+                interrupted,
+            } = collect_room_events(&room_identifier, room_to_export_info, previous_export_state.as_ref(), &event_collection).await?;
+            missing_megolm_session_ids.append(&mut room_undecryptable_megolm_session_ids);
+
+            if count_only {
+                println!("{}: {} messages", room_identifier, events.len());
+                return Ok((events.len(), interrupted))
+            }
+
+            if let Some(reactions_summary_format) = reactions_summary {
+                let tallies = reaction_tallies(&events, formatting.sender_hasher.as_ref());
+                let base_output_filename = match &datestamp {
+                    Some(datestamp) => format!("{} {} [reactions]", format_export_filename(&room_to_export_info), datestamp),
+                    None => format!("{} [reactions]", format_export_filename(&room_to_export_info)),
+                };
+                match reactions_summary_format {
+                    ReactionsSummaryFormat::Json => write_export_file(&base_output_path, format!("{}.json", base_output_filename), json_to_string(&tallies, formatting.json_compact)?, to_stdout, existing_file_policy)?,
+                    ReactionsSummaryFormat::Csv => write_export_file(&base_output_path, format!("{}.csv", base_output_filename), reaction_tallies_to_csv(&tallies), to_stdout, existing_file_policy)?,
+                }
+                return Ok((events.len(), interrupted))
+            }
+
+            let (mut media_links, inline_images) = if should_download_media {
+                download_room_media(client, &room_identifier, &events, &base_output_path.join(MEDIA_DIRECTORY_NAME), media_variant, media_link_kind, inline_image_max_size, &media_download_semaphore, rate_limiter.as_ref(), &mut warnings).await?
+            } else {
+                (BTreeMap::new(), HashMap::new())
+            };
+            if media_link_kind != MediaLinkKind::Relative {
+                let homeserver = client.homeserver().to_string();
+                media_links.extend(events.iter().filter_map(media_attachment).filter_map(|attachment| Some((attachment.event_id.to_string(), remote_media_link(&attachment, media_link_kind, &homeserver)?))));
+            }
+
+            let read_receipts = if include_read_receipts && formats.contains(&ExportOutputFormat::Json) {
+                read_receipts(&room_to_export_info.room, &events).await?
+            } else {
+                BTreeMap::new()
+            };
+
+            let permalinks = match &permalink_base {
+                Some(permalink_base) => Some(room_permalinks(&room_to_export_info.room, permalink_base).await?),
+                None => None,
+            };
+
+            let pinned = pinned_events(&room_to_export_info.room, &events, rate_limiter.as_ref(), event_transform.as_ref()).await?;
+
+            let context = RoomExportContext {
+                room_info: room_to_export_info,
+                warnings,
+                media_links,
+                inline_images,
+                read_receipts,
+                permalinks,
+                interrupted,
+                pinned,
+                gap_end_timestamps: gaps.into_iter().map(|(_start, end)| end).collect(),
+                display_names_at_event_time,
+            };
+
+            let mut base_output_filename = match &datestamp {
+                Some(datestamp) => format!("{} {}", format_export_filename(&room_to_export_info), datestamp),
+                None => format_export_filename(&room_to_export_info),
+            };
+            if let Some(state) = &previous_export_state {
+                base_output_filename.push_str(&format!(" [since {}]", make_filesystem_safe(&state.last_export_time)));
+            }
+            if let Some(token) = &from_token {
+                base_output_filename.push_str(&format!(" [from {}]", make_filesystem_safe(token)));
+            }
+            if let Some(event_id) = &context_event_id {
+                base_output_filename.push_str(&format!(" [context {}]", make_filesystem_safe(event_id.as_str())));
+            }
+            if events.is_empty() && skip_empty {
+                eprintln!("Room {} had no exportable messages, so no export was written for it.", room_identifier);
+            } else {
+                if single_file {
+                    single_file_export.insert(room_to_export_info.id.to_string(), json_export(&events, &context, &formatting));
+                }
+                write_room_export(&base_output_path, &base_output_filename, &events, &context, &room_output).await?;
+            }
+            if mark_read {
+                if let Some(latest_event_id) = events.iter().rev().find_map(|event| event.event.get_field::<OwnedEventId>("event_id").ok().flatten()) {
+                    room_to_export_info.room.send_multiple_receipts(Receipts::new().fully_read_marker(latest_event_id.clone()).public_read_receipt(latest_event_id)).await?;
+                }
+            }
+            // An interrupted room's state is saved even if this wasn't an incremental export, so that an incremental export can pick up where it left off
+            if since_last_export || (interrupted && !to_stdout && context_event_id.is_none()) {
+                write_room_export_state(&base_output_path, room_to_export_info, &RoomExportState {
+                    room_id: room_to_export_info.id.to_string(),
+                    last_export_time: export_start_time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    end_token: last_end_token,
+                    last_event_id,
+                })?;
+            }
+            if interrupted {
+                eprintln!("Export of room {} was interrupted; wrote the {} events fetched so far. Re-run with --since-last-export to resume it.", room_identifier, events.len());
+            }
+            Ok((events.len(), interrupted))
+        }.await;
+        match room_export_result {
+            Ok((exported_event_count, interrupted)) => {
+                run_log::record(run_log.as_ref(), "room_finish", json!({ "room": room_identifier, "events": exported_event_count, "interrupted": interrupted }));
+                exported_room_count += 1;
+                total_event_count += exported_event_count;
+                if !interrupted {
+                    exported_room_ids.insert(room_to_export_info.id.to_string());
+                }
+                if interrupted {
+                    export_interrupted = true;
+                    break
+                }
+            },
+            Err(e) if all_rooms => {
+                run_log::record(run_log.as_ref(), "room_error", json!({ "room": room_identifier, "error": format!("{:#}", e) }));
+                eprintln!("Failed to export room {}; continuing with the remaining rooms. Error: {:?}", room_identifier, e);
+                room_failures.push(format!("Failed to export room {}: {}", room_identifier, e));
+                failed_export_count += 1;
+            },
+            Err(e) => {
+                run_log::record(run_log.as_ref(), "room_error", json!({ "room": room_identifier, "error": format!("{:#}", e) }));
+                return Err(e)
+            },
+        }
+    }
+
+    if let Some(exported_rooms_path) = exported_rooms_path.filter(|_| exported_room_ids != previously_exported_room_ids) {
+        // By now the rooms themselves have been exported, so this isn't worth failing over
+        if let Err(e) = write_exported_room_ids(&exported_rooms_path, &exported_room_ids) {
+            eprintln!("Couldn't update the record of exported rooms, so --only-new-rooms may export some rooms again. Error: {}", e);
+        }
+    }
+
+    if !single_file_export.is_empty() {
+        let filename = match &datestamp {
+            Some(datestamp) => format!("{} {}.json", SINGLE_FILE_EXPORT_FILENAME, datestamp),
+            None => format!("{}.json", SINGLE_FILE_EXPORT_FILENAME),
+        };
+        write_export_file(&output_path.clone().filter(|_| !to_stdout).unwrap_or_default(), filename, json_to_string(&single_file_export, formatting.json_compact)?, to_stdout, existing_file_policy)?;
+    }
+
+    if count_only {
+        println!("Total: {} messages", total_event_count);
+    }
+
+    run_log::record(run_log.as_ref(), "export_finish", json!({ "exported_rooms": exported_room_count, "interrupted": export_interrupted, "failures": room_failures, "missing_megolm_sessions": missing_megolm_session_ids.len() }));
+    if !missing_megolm_session_ids.is_empty() {
+        eprintln!("Missing {} megolm sessions, so some messages couldn't be decrypted; import the room keys for them (e.g. from a key export or key backup, via another client) and re-export. Session IDs:\n{}", missing_megolm_session_ids.len(), missing_megolm_session_ids.iter().cloned().collect::<Vec<String>>().join("\n"));
+    }
+
+    if export_interrupted {
+        Err(TraceError::Interrupted {
+            exported: exported_room_count,
+        }.into())
+    } else if room_failures.is_empty() {
+        if missing_megolm_session_ids.is_empty() || !fail_on_decrypt_error {
+            Ok(exported_room_count)
+        } else {
+            Err(TraceError::MissingDecryptionKeys(missing_megolm_session_ids.into_iter().collect()).into())
+        }
+    } else if exported_room_count == 0 && failed_export_count == 0 {
+        Err(TraceError::RoomNotFound(room_failures).into())
+    } else {
+        Err(TraceError::PartialExport {
+            exported: exported_room_count,
+            failures: room_failures,
+        }.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use matrix_sdk::{
+        deserialized_responses::{
+            AlgorithmInfo,
+            EncryptionInfo,
+            VerificationState,
+        },
+        ruma::{
+            events::room::message::RoomMessageEventContent,
+            owned_user_id,
+        },
+    };
+
+    fn plain_formatting() -> FormattingOptions {
+        FormattingOptions {
+            raw_json: false,
+            sender_hasher: None,
+            mark_edits_in_txt: false,
+            show_event_ids_in_txt: false,
+            render_state: false,
+            flatten_replies: false,
+            json_compact: false,
+            header_text: None,
+            footer_text: None,
+        }
+    }
+
+    fn timeline_event(event_json: &str) -> TimelineEvent {
+        TimelineEvent::new(Raw::from_json_string(String::from(event_json)).unwrap())
+    }
+
+    fn msgtype(content: serde_json::Value) -> MessageType {
+        serde_json::from_value::<RoomMessageEventContent>(content).unwrap().msgtype
+    }
+
+    fn plain_exported_event_json(event: &TimelineEvent) -> serde_json::Value {
+        exported_event_json(event, None, &plain_formatting(), &HashMap::new())
+    }
+
+    fn sample_json_export(messages: Vec<serde_json::Value>) -> JsonExport {
+        JsonExport {
+            room: JsonExportRoomHeader {
+                id: String::from("!room:example.org"),
+                name: Some(String::from("Example room")),
+                canonical_alias: None,
+                history_visibility: String::from("shared"),
+                warnings: vec![String::from("A warning\nspanning lines")],
+                interrupted: false,
+                media: BTreeMap::from([(String::from("$a:example.org"), String::from("media/example.org-AbCdEf_image.png"))]),
+                read_receipts: BTreeMap::new(),
+                pinned: vec![ExportedPinnedEvent {
+                    event_id: String::from("$a:example.org"),
+                    in_export: true,
+                    event: Some(json!({ "type": "m.room.message", "content": { "body": "pinned" } })),
+                }],
+            },
+            messages,
+        }
+    }
+
+    fn streamed_json_export(json_export: &JsonExport, compact: bool) -> String {
+        let mut output = Vec::new();
+        write_json_export_messages_streamed(&mut output, &json_export.room, json_export.messages.iter().cloned(), compact).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn streamed_json_export_matches_buffered_export() {
+        let messages = vec![
+            json!({ "event_id": "$a:example.org", "content": { "body": "first\nmessage", "m.relates_to": {} }, "tags": [] }),
+            json!({ "event_id": "$b:example.org", "content": { "body": "second", "nested": { "list": [1, 2, { "deeper": null }] } } }),
+        ];
+        for compact in [false, true] {
+            for json_export in [sample_json_export(messages.clone()), sample_json_export(Vec::new())] {
+                assert_eq!(streamed_json_export(&json_export, compact), json_to_string(&json_export, compact).unwrap());
+            }
+        }
+    }
+
+    // Serves each page from the token it's requested with, recording the tokens requested
+    async fn paginate_pages(from_token: Option<&str>, pages: &[(Option<&str>, Vec<u32>, Option<&str>)]) -> (PaginatedEvents<u32>, Vec<Option<String>>) {
+        let mut requested_tokens = Vec::new();
+        let paginated_events = paginate(from_token.map(String::from), None, |token| {
+            let page = pages.iter().find(|(page_token, _, _)| page_token.map(String::from) == token).expect("Requested a page which doesn't exist");
+            requested_tokens.push(token);
+            let (chunk, end_token) = (page.1.clone(), page.2.map(String::from));
+            async move { Ok::<_, anyhow::Error>((chunk, end_token)) }
+        }).await.unwrap();
+        (paginated_events, requested_tokens)
+    }
+
+    #[test]
+    fn short_chunks_with_new_tokens_keep_pagination_going() {
+        assert_eq!(next_pagination_token(Some("a"), Some(String::from("b"))), Some(String::from("b")));
+        assert_eq!(next_pagination_token(None, Some(String::from("a"))), Some(String::from("a")));
+    }
+
+    #[test]
+    fn missing_end_tokens_stop_pagination() {
+        assert_eq!(next_pagination_token(Some("a"), None), None);
+        assert_eq!(next_pagination_token(None, None), None);
+    }
+
+    #[test]
+    fn repeated_tokens_stop_pagination() {
+        assert_eq!(next_pagination_token(Some("a"), Some(String::from("a"))), None);
+    }
+
+    #[tokio::test]
+    async fn pagination_continues_past_short_and_empty_chunks() {
+        let pages = [
+            (None, vec![1], Some("a")),
+            (Some("a"), vec![], Some("b")),
+            (Some("b"), vec![2, 3], Some("c")),
+            (Some("c"), vec![], Some("c")),
+        ];
+        let (paginated_events, requested_tokens) = paginate_pages(None, &pages).await;
+        assert_eq!(paginated_events.events, vec![1, 2, 3]);
+        assert_eq!(paginated_events.end_token.as_deref(), Some("c"));
+        assert!(!paginated_events.stuck && !paginated_events.interrupted);
+        assert_eq!(requested_tokens, vec![None, Some(String::from("a")), Some(String::from("b")), Some(String::from("c"))]);
+    }
+
+    #[tokio::test]
+    async fn pagination_stops_without_an_end_token() {
+        let pages = [
+            (Some("a"), vec![1], Some("b")),
+            (Some("b"), vec![2], None),
+        ];
+        let (paginated_events, _) = paginate_pages(Some("a"), &pages).await;
+        assert_eq!(paginated_events.events, vec![1, 2]);
+        // Resuming refetches the final chunk, since there's no later token to resume from
+        assert_eq!(paginated_events.end_token.as_deref(), Some("b"));
+        assert!(!paginated_events.stuck);
+    }
+
+    #[tokio::test]
+    async fn pagination_stops_when_stuck_on_a_token() {
+        let pages = [
+            (None, vec![1], Some("a")),
+            (Some("a"), vec![2], Some("a")),
+        ];
+        let (paginated_events, requested_tokens) = paginate_pages(None, &pages).await;
+        assert_eq!(paginated_events.events, vec![1, 2]);
+        assert!(paginated_events.stuck);
+        assert_eq!(requested_tokens.len(), 2);
+    }
+
+    #[test]
+    fn events_are_annotated_with_their_encryption() {
+        let plaintext_event = timeline_event(r#"{"type":"m.room.message","event_id":"$plain:example.org","sender":"@alice:example.org","origin_server_ts":1700000000000,"content":{"msgtype":"m.text","body":"in the clear"}}"#);
+
+        let mut decrypted_event = timeline_event(r#"{"type":"m.room.message","event_id":"$decrypted:example.org","sender":"@alice:example.org","origin_server_ts":1700000001000,"content":{"msgtype":"m.text","body":"decrypted"}}"#);
+        decrypted_event.encryption_info = Some(EncryptionInfo {
+            sender: owned_user_id!("@alice:example.org"),
+            sender_device: None,
+            algorithm_info: AlgorithmInfo::MegolmV1AesSha2 {
+                curve25519_key: String::from("SenderCurveKey"),
+                sender_claimed_keys: BTreeMap::new(),
+            },
+            verification_state: VerificationState::Verified,
+        });
+
+        let ciphertext_envelope = r#"{"algorithm":"m.megolm.v1.aes-sha2","ciphertext":"AwgAEnACgAkLmt6qF84IK++J7UDH2Za1YVchHyprqTqsg","device_id":"ALICEDEVICE","sender_key":"SenderCurveKey","session_id":"SessionId"}"#;
+        let undecrypted_event = timeline_event(&format!(r#"{{"type":"m.room.encrypted","event_id":"$undecrypted:example.org","sender":"@alice:example.org","origin_server_ts":1700000002000,"content":{}}}"#, ciphertext_envelope));
+
+        let plaintext_export = plain_exported_event_json(&plaintext_event);
+        assert_eq!(plaintext_export["encryption"], "unencrypted");
+        assert_eq!(plaintext_export["message"]["body"], "in the clear");
+
+        let decrypted_export = plain_exported_event_json(&decrypted_event);
+        assert_eq!(decrypted_export["encryption"], "decrypted");
+        assert_eq!(decrypted_export["message"]["body"], "decrypted");
+
+        let undecrypted_export = plain_exported_event_json(&undecrypted_event);
+        assert_eq!(undecrypted_export["encryption"], "undecrypted");
+        assert!(undecrypted_export.get("message").is_none());
+        assert_eq!(serde_json::to_string(&undecrypted_export["content"]).unwrap(), ciphertext_envelope);
+    }
+
+    #[test]
+    fn rare_and_unknown_msgtypes_are_rendered_with_their_bodies() {
+        let server_notice = msgtype(json!({ "msgtype": "m.server_notice", "body": "Usage limit reached", "server_notice_type": "m.server_notice.usage_limit_reached" }));
+        assert_eq!(txt_message_text(&server_notice, "", None), "[Server notice: Usage limit reached]");
+        let custom = msgtype(json!({ "msgtype": "org.example.custom", "body": "Custom body", "org.example.data": 1 }));
+        assert_eq!(txt_message_text(&custom, "", None), "[org.example.custom] Custom body");
+    }
 }
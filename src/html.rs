@@ -0,0 +1,300 @@
+use std::collections::{
+    BTreeSet,
+    HashMap,
+};
+
+use crate::export::{
+    call_invite_timestamps,
+    custom_event_description,
+    event_sender_string_representation,
+    event_time_user_id_to_string_representation,
+    events_by_id,
+    exported_call,
+    in_reply_to,
+    quoted_body,
+    reply_ancestry,
+    state_change_description,
+    user_id_to_string_representation,
+    widget_description,
+    FormattingOptions,
+    RoomExportContext,
+};
+
+use chrono::{
+    DateTime,
+    SecondsFormat,
+    Utc,
+};
+use matrix_sdk::{
+    deserialized_responses::TimelineEvent,
+    ruma::{
+        events::{
+            room::message::MessageType,
+            AnyMessageLikeEvent,
+            AnyStateEvent,
+            AnyTimelineEvent,
+        },
+        UserId,
+    },
+};
+
+///////////////////
+//   Constants   //
+///////////////////
+
+const STYLESHEET: &str = "body { font-family: sans-serif; max-width: 60em; margin: auto; padding: 1em; }
+nav ul { columns: 4; }
+.note { font-style: italic; }
+.event { padding: 0.2em 0; }
+.event:target { background-color: #fff3b0; }
+.timestamp { color: #777; font-size: 0.85em; text-decoration: none; }
+.sender { font-weight: bold; }
+.reply { display: block; margin-left: 2em; font-size: 0.85em; color: #555; }
+.quote { margin: 0.2em 0 0.2em 2em; padding-left: 0.5em; border-left: 2px solid #ccc; font-size: 0.85em; color: #555; }
+.placeholder { color: #777; }
+.pinned { border: 1px solid #ccc; padding: 0 1em; }
+.custom-text { white-space: pre-wrap; margin: 1em 0; }
+.gap { text-align: center; color: #a33; font-style: italic; }
+img { max-width: 100%; max-height: 30em; }";
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_html_multiline(text: &str) -> String {
+    escape_html(text).replace('\n', "<br>\n")
+}
+
+fn page_start(title: &str) -> String {
+    format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>{}</h1>\n", escape_html(title), STYLESHEET, escape_html(title))
+}
+
+const PAGE_END: &str = "</body>\n</html>\n";
+
+fn event_datetime(event: &TimelineEvent) -> Option<DateTime<Utc>> {
+    event.event.get_field::<i64>("origin_server_ts").ok().flatten().and_then(DateTime::from_timestamp_millis)
+}
+
+// A message's body in the html export. Verification requests' recipients have to be looked up beforehand, since that may need the server.
+fn message_html(msgtype: &MessageType, media_path: Option<&str>, inline_image: Option<&str>, verification_recipient: Option<&str>) -> String {
+    match (msgtype, media_path) {
+        (MessageType::Image(_), Some(media_path)) => format!("<img src=\"{}\" alt=\"{}\">", escape_html(inline_image.unwrap_or(media_path)), escape_html(msgtype.body())),
+        (MessageType::Audio(_) | MessageType::File(_) | MessageType::Image(_) | MessageType::Video(_), Some(media_path)) => format!("<a href=\"{}\">{}</a>", escape_html(media_path), escape_html(msgtype.body())),
+        (MessageType::Audio(e), None) => format!("<span class=\"placeholder\">[Audio: {}]</span>", escape_html(&e.body)),
+        (MessageType::Emote(e), _) => format!("<em>{}</em>", escape_html_multiline(&e.body)),
+        (MessageType::File(e), None) => format!("<span class=\"placeholder\">[File: {}]</span>", escape_html(&e.body)),
+        (MessageType::Image(e), None) => format!("<span class=\"placeholder\">[Image: {}]</span>", escape_html(&e.body)),
+        (MessageType::Location(e), _) => format!("<span class=\"placeholder\">[Location: {}]</span> {}", escape_html(&e.geo_uri), escape_html(&e.body)),
+        (MessageType::Notice(e), _) => format!("<span class=\"placeholder\">{}</span>", escape_html_multiline(&e.body)),
+        (MessageType::ServerNotice(e), _) => format!("<span class=\"placeholder\">[Server notice: {}]</span>", escape_html_multiline(&e.body)),
+        (MessageType::Text(e), _) => escape_html_multiline(&e.body),
+        (MessageType::Video(e), None) => format!("<span class=\"placeholder\">[Video: {}]</span>", escape_html(&e.body)),
+        (MessageType::VerificationRequest(e), _) => format!("<span class=\"placeholder\">[Verification request sent to {}]</span>", escape_html(verification_recipient.unwrap_or(e.to.as_str()))),
+        (other_msgtype, _) => format!("<span class=\"placeholder\">[{}]</span> {}", escape_html(other_msgtype.msgtype()), escape_html_multiline(other_msgtype.body())),
+    }
+}
+
+//////////////
+//   Main   //
+//////////////
+
+/// Wraps plain text (e.g. a room summary) in a standalone page.
+pub(crate) fn text_to_html(title: &str, text: &str) -> String {
+    format!("{}<pre>{}</pre>\n{}", page_start(title), escape_html(text), PAGE_END)
+}
+
+// Every event gets an anchor with its event ID, so that exports can be linked into (and replies can link to the messages they quote), and its timestamp links to that anchor, or to its permalink if requested; a table of contents links to the first event of each day
+pub(crate) async fn messages_to_html(events: &Vec<TimelineEvent>, context: &RoomExportContext<'_>, formatting: &FormattingOptions) -> anyhow::Result<String> {
+    let room_info = context.room_info;
+    let sender_hasher = formatting.sender_hasher.as_ref();
+    let mut user_ids_to_string_representations: HashMap<String, String> = HashMap::new();
+    let exported_event_ids = events.iter().filter_map(|event| event.event.get_field::<String>("event_id").ok().flatten()).collect::<BTreeSet<String>>();
+    let mut event_senders: HashMap<String, String> = HashMap::new();
+    let call_invite_timestamps = call_invite_timestamps(events);
+    let events_by_id = events_by_id(events);
+
+    let title = room_info.name.clone().unwrap_or_else(|| room_info.id.to_string());
+    let mut room_export = page_start(&title);
+    if let Some(header_text) = &formatting.header_text {
+        room_export.push_str(&format!("<header class=\"custom-text\">{}</header>\n", escape_html(header_text)));
+    }
+
+    for warning in &context.warnings {
+        room_export.push_str(&format!("<p class=\"note\">Note: {}</p>\n", escape_html(warning)));
+    }
+
+    // Pinned messages in the export link to where they are in it
+    if !context.pinned.is_empty() {
+        room_export.push_str("<section class=\"pinned\">\n<h2>Pinned messages</h2>\n<ul>\n");
+        for pinned_event in &context.pinned {
+            match &pinned_event.event {
+                Some(event) => {
+                    let sender = escape_html(&event_sender_string_representation(&mut user_ids_to_string_representations, room_info, event, sender_hasher).await?);
+                    let body = escape_html_multiline(&quoted_body(event));
+                    if pinned_event.in_export {
+                        room_export.push_str(&format!("<li><a href=\"#{}\">{}</a>: {}</li>\n", escape_html(&pinned_event.event_id), sender, body));
+                    } else {
+                        room_export.push_str(&format!("<li>{}: {} <span class=\"note\">(not in this export)</span></li>\n", sender, body));
+                    }
+                }
+                None => room_export.push_str(&format!("<li class=\"placeholder\">[Pinned message {} couldn't be fetched]</li>\n", escape_html(&pinned_event.event_id))),
+            }
+        }
+        room_export.push_str("</ul>\n</section>\n");
+    }
+
+    if events.is_empty() {
+        room_export.push_str("<p class=\"note\">No messages</p>\n");
+    }
+
+    let dates = events.iter().filter_map(event_datetime).map(|datetime| datetime.date_naive()).collect::<BTreeSet<_>>();
+    if !dates.is_empty() {
+        room_export.push_str("<nav>\n<h2>Dates</h2>\n<ul>\n");
+        for date in &dates {
+            room_export.push_str(&format!("<li><a href=\"#date-{}\">{}</a></li>\n", date, date));
+        }
+        room_export.push_str("</ul>\n</nav>\n");
+    }
+
+    let mut current_date = None;
+    let mut previous_timestamp = None;
+    for event in events {
+        if let Some(timestamp) = event.event.get_field::<u64>("origin_server_ts").ok().flatten() {
+            if context.gap_precedes(previous_timestamp, timestamp) {
+                room_export.push_str("<p class=\"gap\">--- possible gap in history ---</p>\n");
+            }
+            previous_timestamp = Some(timestamp);
+        }
+
+        let datetime = event_datetime(event);
+        if let Some(date) = datetime.map(|datetime| datetime.date_naive()) {
+            if current_date != Some(date) {
+                room_export.push_str(&format!("<h2 id=\"date-{}\">{}</h2>\n", date, date));
+                current_date = Some(date);
+            }
+        }
+
+        let event_deserialized = match event.event.deserialize() {
+            Ok(event_deserialized) => event_deserialized,
+            Err(_) => {
+                room_export.push_str("<div class=\"event placeholder\">[Message skipped due to deserialization failure]</div>\n");
+                continue
+            }
+        };
+        let event_id = event_deserialized.event_id().to_string();
+        let event_sender_string_representation = event_time_user_id_to_string_representation(&mut user_ids_to_string_representations, context, event_deserialized.event_id(), event_deserialized.sender(), sender_hasher).await?;
+        event_senders.insert(event_id.clone(), event_sender_string_representation.clone());
+        let event_json = event.event.deserialize_as::<serde_json::Value>().ok();
+        let call = event_json.as_ref().and_then(|event_json| exported_call(event_json, &call_invite_timestamps));
+        let call_invitee = match call.as_ref().and_then(|call| call.invitee.as_deref()).and_then(|invitee| UserId::parse(invitee).ok()) {
+            Some(invitee) => Some(user_id_to_string_representation(&mut user_ids_to_string_representations, room_info, &invitee, sender_hasher).await?),
+            None => None,
+        };
+        let custom_event = match (&event_deserialized, &event_json) {
+            (AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::_Custom(_)) | AnyTimelineEvent::State(AnyStateEvent::_Custom(_)), Some(event_json)) => Some(custom_event_description(event_json)),
+            _ => None,
+        };
+
+        let body = match &event_deserialized {
+            AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(e)) => match e.as_original() {
+                Some(unredacted_room_message) => {
+                    let msgtype = &unredacted_room_message.content.msgtype;
+                    let verification_recipient = match msgtype {
+                        MessageType::VerificationRequest(e) => Some(user_id_to_string_representation(&mut user_ids_to_string_representations, room_info, &e.to, sender_hasher).await?),
+                        _ => None,
+                    };
+                    message_html(msgtype, context.media_links.get(&event_id).map(String::as_str), context.inline_images.get(&event_id).map(String::as_str), verification_recipient.as_deref())
+                }
+                None => String::from("<span class=\"placeholder\">[Redacted message]</span>"),
+            },
+            AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomEncrypted(_)) => String::from("<span class=\"placeholder\">[Undecryptable encrypted message]</span>"),
+            AnyTimelineEvent::MessageLike(_) => match (&call, &custom_event) {
+                (Some(call), _) => format!("<em>{}</em>", escape_html(&call.description(call_invitee.as_deref()))),
+                (None, Some((label, content))) => format!("<span class=\"placeholder\">[{}]</span><pre>{}</pre>", escape_html(label), escape_html(content)),
+                (None, None) => String::from("<span class=\"placeholder\">[Placeholder message-like]</span>"),
+            },
+            AnyTimelineEvent::State(e) => match (event_json.as_ref().and_then(widget_description), &custom_event) {
+                (Some(widget), _) => format!("<span class=\"placeholder\">{}</span>", escape_html(&widget)),
+                (None, Some((label, content))) => format!("<span class=\"placeholder\">[{}]</span><pre>{}</pre>", escape_html(label), escape_html(content)),
+                (None, None) if formatting.render_state => match event_json.as_ref().and_then(|event_json| state_change_description(event_json, sender_hasher)) {
+                    Some(description) => format!("<em>{}</em>", escape_html(&description)),
+                    None => format!("<span class=\"placeholder\">[State event of type {}]</span>", escape_html(&e.event_type().to_string())),
+                },
+                (None, None) => String::from("<span class=\"placeholder\">[Placeholder state-like]</span>"),
+            },
+        };
+
+        // Replies link to the quoted message when it's in the same export; otherwise there's nowhere within the page to link to
+        let (quoted_ancestry, reply_chain_continues) = if formatting.flatten_replies {
+            reply_ancestry(event, &events_by_id)
+        } else {
+            (Vec::new(), false)
+        };
+        let reply = match in_reply_to(event) {
+            // Nested from the inside out, so that each quoted message contains the quote of the message it in turn replies to
+            Some(_) if !quoted_ancestry.is_empty() => quoted_ancestry.iter().rev().fold(if reply_chain_continues {
+                String::from("<blockquote class=\"quote placeholder\">[Earlier messages in this reply chain not included]</blockquote>")
+            } else {
+                String::new()
+            }, |inner_quotes, ancestor| {
+                let ancestor_event_id = ancestor.event.get_field::<String>("event_id").ok().flatten().unwrap_or_default();
+                format!("<blockquote class=\"quote\">{}<a href=\"#{}\">{}</a>: {}</blockquote>", inner_quotes, escape_html(&ancestor_event_id), escape_html(event_senders.get(&ancestor_event_id).map(String::as_str).unwrap_or("[Unknown sender]")), escape_html_multiline(&quoted_body(ancestor)))
+            }) + "\n",
+            Some(replied_to_event_id) if exported_event_ids.contains(&replied_to_event_id) => format!("<a class=\"reply\" href=\"#{}\">In reply to a message from {}</a>\n", escape_html(&replied_to_event_id), escape_html(event_senders.get(&replied_to_event_id).map(String::as_str).unwrap_or("[Unknown sender]"))),
+            Some(_) => String::from("<span class=\"reply\">In reply to a message not included in this export</span>\n"),
+            None => String::new(),
+        };
+
+        let timestamp = datetime.map(|datetime| datetime.to_rfc3339_opts(SecondsFormat::Millis, true)).unwrap_or_default();
+        let timestamp_href = match &context.permalinks {
+            Some(permalinks) => permalinks.event_permalink(&event_id),
+            None => format!("#{}", event_id),
+        };
+        room_export.push_str(&format!("<div class=\"event\" id=\"{}\">\n{}<a class=\"timestamp\" href=\"{}\">[{}]</a> <span class=\"sender\">{}</span>: {}\n</div>\n", escape_html(&event_id), reply, escape_html(&timestamp_href), timestamp, escape_html(&event_sender_string_representation), body));
+    }
+
+    if context.interrupted {
+        room_export.push_str("<p class=\"note\">Export interrupted</p>\n");
+    }
+    if let Some(footer_text) = &formatting.footer_text {
+        room_export.push_str(&format!("<footer class=\"custom-text\">{}</footer>\n", escape_html(footer_text)));
+    }
+
+    room_export.push_str(PAGE_END);
+    Ok(room_export)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+    use serde_json::json;
+
+    fn msgtype(content: serde_json::Value) -> MessageType {
+        serde_json::from_value::<RoomMessageEventContent>(content).unwrap().msgtype
+    }
+
+    #[test]
+    fn rare_and_unknown_msgtypes_are_rendered_with_their_bodies() {
+        let server_notice = msgtype(json!({ "msgtype": "m.server_notice", "body": "Usage limit reached", "server_notice_type": "m.server_notice.usage_limit_reached" }));
+        assert_eq!(message_html(&server_notice, None, None, None), "<span class=\"placeholder\">[Server notice: Usage limit reached]</span>");
+        let custom = msgtype(json!({ "msgtype": "org.example.custom", "body": "<Custom> body", "org.example.data": 1 }));
+        assert_eq!(message_html(&custom, None, None, None), "<span class=\"placeholder\">[org.example.custom]</span> &lt;Custom&gt; body");
+    }
+}
@@ -1,17 +1,37 @@
 use std::{
     cmp::Ordering,
+    collections::HashMap,
+    error::Error,
+    fmt::{
+        self,
+        Display,
+        Formatter,
+    },
     fs::{
         create_dir_all,
+        read,
         read_to_string,
         remove_dir_all,
         write,
     },
+    io,
+    future::Future,
     path::{
         Path,
         PathBuf,
     },
+    time::Duration,
 };
 
+use chacha20poly1305::{
+    aead::{
+        Aead,
+        KeyInit,
+    },
+    ChaCha20Poly1305,
+    Key,
+    Nonce,
+};
 use directories::ProjectDirs;
 use futures::future::join_all;
 use matrix_sdk::{
@@ -19,24 +39,61 @@ use matrix_sdk::{
     matrix_auth::{
         MatrixSession,
         MatrixSessionTokens,
-    }, 
+    },
+    room::MessagesOptions,
     ruma::{
-        api::client::session::get_login_types::v3::LoginType,
+        api::client::{
+            account::whoami,
+            error::ErrorKind,
+            profile::get_profile,
+            session::get_login_types::v3::LoginType,
+        },
         presence::PresenceState,
         OwnedRoomAliasId,
         OwnedRoomId,
         UserId,
     },
+    BaseRoom,
     Client,
+    ClientBuilder,
+    HttpError,
     Room,
     SessionMeta,
 };
+use pbkdf2::pbkdf2_hmac;
+use rand::RngCore;
 use serde::{
     Deserialize,
     Serialize,
 };
+use sha2::Sha256;
 
 pub mod export;
+mod html;
+pub mod media;
+pub mod rate_limit;
+pub mod run_log;
+pub mod serve;
+mod sqlite;
+
+///////////////////
+//   Constants   //
+///////////////////
+
+pub const SESSIONS_FILENAME: &str = "sessions.json";
+pub const CONFIG_FILENAME: &str = "config.json";
+pub const SESSIONS_PASSPHRASE_ENV_VAR: &str = "TRACE_SESSIONS_PASSPHRASE";
+pub const DEFAULT_USER_AGENT: &str = concat!("trace/", env!("CARGO_PKG_VERSION"));
+pub const DEFAULT_DEVICE_DISPLAY_NAME: &str = "Trace";
+pub const EXPORTED_ROOMS_DIRECTORY_NAME: &str = "exported-rooms";
+
+// Encrypted sessions files are laid out as this magic header, then the KDF salt, then the cipher nonce, then the ciphertext
+const ENCRYPTED_SESSIONS_FILE_MAGIC: &[u8] = b"TRACEENC1";
+const SESSIONS_KDF_SALT_LENGTH: usize = 16;
+const SESSIONS_CIPHER_NONCE_LENGTH: usize = 12;
+const SESSIONS_KDF_ROUNDS: u32 = 600_000;
+// Bumped whenever the sessions file's schema changes, with a matching step added to migrate_sessions_file
+const SESSIONS_FILE_VERSION: u64 = 1;
 
 ////////////////////
 //   Re-exports   //
@@ -44,8 +101,24 @@ pub mod export;
 
 pub use export::{
     export,
+    get_room_summary,
+    room_summary_to_txt,
+    EventTransform,
+    ExistingFilePolicy,
+    ExportOptions,
     ExportOutputFormat,
+    ExportedEvent,
+    ReactionsSummaryFormat,
+    RoomSummary,
+    SenderHasher,
+    UnmappableCharacterPolicy,
+};
+pub use media::{
+    MediaLinkKind,
+    MediaVariant,
 };
+pub use rate_limit::RateLimiter;
+pub use run_log::RunLog;
 
 ///////////////
 //   Types   //
@@ -59,29 +132,86 @@ pub struct Session {
     pub refresh_token: Option<String>,
 }
 
+/// Settings applied to every client trace builds, so that homeserver admins can tell trace's traffic apart from other clients'.
+#[derive(Clone, Default)]
+pub struct ClientConfig {
+    /// HTTP user agent; `DEFAULT_USER_AGENT` if unset.
+    pub user_agent: Option<String>,
+    /// Homeserver base URLs to use for users on the given server names, in place of discovering them via `.well-known`, e.g. for servers whose `.well-known` is broken.
+    pub homeserver_map: HashMap<String, String>,
+}
+
+impl ClientConfig {
+    pub fn client_builder(&self, user: &UserId) -> ClientBuilder {
+        let builder = match self.homeserver_map.get(user.server_name().as_str()) {
+            Some(homeserver_url) => Client::builder().homeserver_url(homeserver_url),
+            None => Client::builder().server_name(user.server_name()),
+        };
+        builder.user_agent(self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+    }
+}
+
+/// Reads a homeserver map for `ClientConfig`: a json object of server names to homeserver base URLs, e.g. `{"example.org": "https://matrix.example.org"}`.
+pub fn read_homeserver_map(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let contents = read_to_string(path).map_err(|e| anyhow::anyhow!("Couldn't read homeserver map {}: {}", path.display(), e))?;
+    let homeserver_map: HashMap<String, String> = serde_json::from_str(&contents).map_err(|e| anyhow::anyhow!("Couldn't parse homeserver map {} (expected a json object of server names to homeserver URLs): {}", path.display(), e))?;
+    for (server_name, homeserver_url) in &homeserver_map {
+        if !(homeserver_url.starts_with("https://") || homeserver_url.starts_with("http://")) {
+            anyhow::bail!("Homeserver map {} maps {} to {}, which isn't an http(s) URL.", path.display(), server_name, homeserver_url);
+        }
+    }
+    Ok(homeserver_map)
+}
+
 pub struct SessionsFile {
     path: PathBuf,
+    passphrase: Option<String>,
     pub sessions: Vec<Session>,
 }
 
 impl SessionsFile {
     pub fn open(path: PathBuf) -> Self {
-        if let Ok(file) = read_to_string(&path) {
-            let sessions = serde_json::from_str(&file).expect("Sessions file is invalid JSON."); // Replace with better error-handling
-            Self {
+        Self::open_with_passphrase(path, None).unwrap() // Replace with better error-handling
+    }
+
+    /// As `open`, but with the file encrypted at rest under the given passphrase. A plaintext file opened with a passphrase is encrypted immediately, and stays encrypted from then on; an encrypted file can't be opened without one.
+    pub fn open_with_passphrase(path: PathBuf, passphrase: Option<String>) -> Result<Self, String> {
+        if let Ok(file) = read(&path) {
+            let file = if file.starts_with(ENCRYPTED_SESSIONS_FILE_MAGIC) {
+                match &passphrase {
+                    Some(passphrase) => decrypt_sessions_file(&file, passphrase)?,
+                    None => return Err(String::from("Sessions file is encrypted, but no passphrase was provided.")),
+                }
+            } else {
+                String::from_utf8(file).map_err(|_| String::from("Sessions file is neither valid UTF-8 nor encrypted."))?
+            };
+            let (sessions, migrated) = migrate_sessions_file(&file)?;
+            let sessions_file = Self {
                 path,
+                passphrase,
                 sessions,
+            };
+            if migrated || (sessions_file.passphrase.is_some() && !Self::is_encrypted(&sessions_file.path)) {
+                sessions_file.write();
             }
+            Ok(sessions_file)
         } else {
             create_dir_all(&path.parent().expect("Tried to open root as sessions file. (This should never happen.")).unwrap();
-            write(&path, "[]").unwrap();
-            Self {
+            let sessions_file = Self {
                 path,
+                passphrase,
                 sessions: Vec::new(),
-            }
+            };
+            sessions_file.write();
+            Ok(sessions_file)
         }
     }
 
+    /// Whether the file at `path` is an encrypted sessions file, and so needs a passphrase to open.
+    pub fn is_encrypted(path: &Path) -> bool {
+        read(path).is_ok_and(|file| file.starts_with(ENCRYPTED_SESSIONS_FILE_MAGIC))
+    }
+
     pub fn get(&self, user_id: &str) -> Result<Session, String> {
         match self.sessions.iter().find(|session| &session.user_id == user_id) {
             Some(session) => Ok(session.clone()),
@@ -110,24 +240,327 @@ impl SessionsFile {
         }
     }
 
+    /// Replaces a session's tokens, e.g. after refreshing them. A refresh token of `None` leaves the session's existing one in place, since homeservers needn't issue a new one on every refresh.
+    pub fn update_session_tokens(&mut self, user_id: &str, access_token: String, refresh_token: Option<String>) -> Result<(), String> {
+        match self.sessions.iter_mut().find(|session| &session.user_id == user_id) {
+            Some(session) => {
+                session.access_token = access_token;
+                if refresh_token.is_some() {
+                    session.refresh_token = refresh_token;
+                }
+                self.write();
+                Ok(())
+            }
+            None => Err(format!("Couldn't find currently-existing login session for user_id {}.", user_id))
+        }
+    }
+
     pub fn write(&self) {
-        let updated_file = serde_json::to_string(&self.sessions).unwrap();
-        write(&self.path, updated_file).unwrap();
+        let updated_file = serde_json::json!({
+            "version": SESSIONS_FILE_VERSION,
+            "sessions": self.sessions,
+        }).to_string();
+        match &self.passphrase {
+            Some(passphrase) => write(&self.path, encrypt_sessions_file(&updated_file, passphrase)).unwrap(),
+            None => write(&self.path, updated_file).unwrap(),
+        }
+    }
+}
+
+/// Persistent user preferences, stored alongside the sessions file in trace's data directory.
+#[derive(Default, Deserialize, Serialize)]
+pub struct TraceConfig {
+    /// Account to act as when a command isn't given a user ID explicitly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_user_id: Option<String>,
+}
+
+impl TraceConfig {
+    /// Reads the config at `path`, or returns the default config if there isn't one yet.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        match read(path) {
+            Ok(file) => Ok(serde_json::from_slice(&file)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), TraceError> {
+        let output_io_error = |e| TraceError::OutputIo { path: path.to_path_buf(), source: e };
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).map_err(output_io_error)?;
+        }
+        write(path, serde_json::to_string_pretty(self).unwrap()).map_err(output_io_error)
     }
 }
 
+/// Failure classes which callers may want to distinguish from one another (e.g. to pick an exit code). Errors from matrix-sdk itself are passed through as-is rather than being wrapped here.
+#[derive(Debug)]
+pub enum TraceError {
+    RoomNotFound(Vec<String>),
+    OutputIo {
+        path: PathBuf,
+        source: io::Error,
+    },
+    PartialExport {
+        exported: usize,
+        failures: Vec<String>,
+    },
+    Timeout(Duration),
+    MissingDecryptionKeys(Vec<String>),
+    Interrupted {
+        exported: usize,
+    },
+    SessionExpired(String),
+}
+
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RoomNotFound(failures) => write!(f, "Couldn't find any of the requested rooms:\n{}", failures.join("\n")),
+            Self::OutputIo { path, source } => write!(f, "Couldn't write output to {}: {}", path.display(), source),
+            Self::PartialExport { exported, failures } => write!(f, "Exported {} of {} requested rooms. Failures:\n{}", exported, exported + failures.len(), failures.join("\n")),
+            Self::Timeout(timeout) => write!(f, "Timed out after {} seconds waiting for the homeserver.", timeout.as_secs()),
+            Self::MissingDecryptionKeys(session_ids) => write!(f, "Exported all requested rooms, but messages encrypted with {} megolm sessions couldn't be decrypted.", session_ids.len()),
+            Self::SessionExpired(user_id) => write!(f, "The session for {} has expired or been logged out elsewhere. If it has a refresh token, 'session refresh {}' may renew it; otherwise, run 'session logout {}' and then 'session login {}' to log in again.", user_id, user_id, user_id, user_id),
+            Self::Interrupted { exported } => write!(f, "Export interrupted after {} rooms; the room in progress was written out as far as it had been fetched.", exported),
+        }
+    }
+}
+
+impl Error for TraceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::OutputIo { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Why a password login failed, so that callers can tell users whether to fix their credentials or just try again later.
+#[derive(Debug)]
+pub enum LoginError {
+    InvalidCredentials,
+    /// With how long the homeserver asked to wait before trying again, if it said.
+    RateLimited(Option<Duration>),
+    /// The homeserver doesn't offer password login at all; with the login types (e.g. `m.login.sso`) it does offer.
+    PasswordLoginUnsupported(Vec<String>),
+    Network(matrix_sdk::Error),
+    Other(matrix_sdk::Error),
+}
+
+impl From<matrix_sdk::Error> for LoginError {
+    fn from(error: matrix_sdk::Error) -> Self {
+        match error.client_api_error_kind() {
+            Some(ErrorKind::Forbidden) => Self::InvalidCredentials,
+            Some(ErrorKind::LimitExceeded { retry_after_ms }) => Self::RateLimited(*retry_after_ms),
+            Some(_) => Self::Other(error),
+            None => match error {
+                matrix_sdk::Error::Http(HttpError::Reqwest(_)) => Self::Network(error),
+                _ => Self::Other(error),
+            },
+        }
+    }
+}
+
+impl Display for LoginError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCredentials => write!(f, "The homeserver rejected the user ID or password. Check both and try again."),
+            Self::RateLimited(Some(retry_after)) => write!(f, "The homeserver is rate-limiting login attempts. Try again in {} seconds.", retry_after.as_secs().max(1)),
+            Self::RateLimited(None) => write!(f, "The homeserver is rate-limiting login attempts. Try again later."),
+            Self::PasswordLoginUnsupported(login_types) if login_types.is_empty() => write!(f, "The homeserver doesn't support password login, and didn't advertise any other login types."),
+            Self::PasswordLoginUnsupported(login_types) => write!(f, "The homeserver doesn't support password login, which is the only kind of login trace supports so far. It only offers: {}.", login_types.join(", ")),
+            Self::Network(e) => write!(f, "Couldn't reach the homeserver to log in ({}). Check your connection and the user ID's server, and try again.", e),
+            Self::Other(e) => write!(f, "Login failed: {}", e),
+        }
+    }
+}
+
+impl Error for LoginError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Network(e) | Self::Other(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub user_id: String,
+    pub device_name: String,
+    pub verification_status: SessionVerificationStatus,
+}
+
+/// Whether a session's device is verified (by cross-signing or locally), and so trusted by other sessions to receive room keys.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionVerificationStatus {
+    Verified,
+    Unverified,
+    /// The session's own device isn't in its crypto store (e.g. because its store was lost or lives on another machine), so its status couldn't be checked.
+    Unknown,
+}
+
+impl Display for SessionVerificationStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Verified => write!(f, "verified"),
+            Self::Unverified => write!(f, "unverified"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// The outcome of `refresh_session_tokens`.
+pub enum TokenRefresh {
+    /// The session has no refresh token (generally because the homeserver didn't issue one at login), so its access token can't be refreshed.
+    NoRefreshToken,
+    /// The session's tokens were refreshed, and the new ones saved to the sessions file. `expires_in` is the new access token's lifetime, if the homeserver gave it one.
+    Refreshed {
+        access_token: String,
+        expires_in: Option<Duration>,
+    },
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoomOrdering {
+    /// By name, then canonical alias, then room ID; see `get_rooms_info` for details.
+    #[default]
+    Name,
+    /// Most-recently-active first. Requires a request to the server per room, so is much slower than the other orderings.
+    RecentActivity,
+    /// Most joined members first.
+    MemberCount,
+}
+
 pub struct RoomWithCachedInfo {
     pub id: OwnedRoomId,
     pub name: Option<String>,
     pub canonical_alias: Option<OwnedRoomAliasId>,
     pub alt_aliases: Vec<OwnedRoomAliasId>,
+    pub joined_member_count: u64,
+    /// As of the last sync, so rooms whose encryption state hasn't been synced yet read as unencrypted; `Room::is_encrypted` gives a definitive answer, at the cost of a request to the server for such rooms.
+    pub encrypted: bool,
     pub room: Room,
 }
 
+// Everything rooms are sorted by, apart from the rooms themselves
+struct RoomSortKey<'a> {
+    name: Option<&'a str>,
+    canonical_alias: Option<&'a str>,
+    id: &'a str,
+    latest_timestamp: Option<u64>,
+    joined_member_count: u64,
+}
+
+impl<'a> RoomSortKey<'a> {
+    fn new(room_info: &'a RoomWithCachedInfo, latest_timestamp: Option<u64>) -> Self {
+        Self {
+            name: room_info.name.as_deref(),
+            canonical_alias: room_info.canonical_alias.as_ref().map(|alias| alias.as_str()),
+            id: room_info.id.as_str(),
+            latest_timestamp,
+            joined_member_count: room_info.joined_member_count,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserProfile {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub rooms: Vec<RoomMembershipInfo>,
+}
+
+#[derive(Serialize)]
+pub struct RoomMembershipInfo {
+    pub room_id: String,
+    pub room_name: Option<String>,
+    pub membership: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub power_level: i64,
+}
+
 ////////////////////////
 //   Shared helpers   //
 ////////////////////////
 
+fn sessions_file_key(passphrase: &str, salt: &[u8]) -> Key {
+    let mut key = Key::default();
+    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, SESSIONS_KDF_ROUNDS, &mut key);
+    key
+}
+
+// Implements `RoomOrdering`; see `get_rooms_info` for the order by name, which breaks the other orderings' ties. Rooms without any events sort last by recent activity.
+fn compare_rooms(ordering: RoomOrdering, room_1: &RoomSortKey<'_>, room_2: &RoomSortKey<'_>) -> Ordering {
+    let by_name = || match (room_1.name, room_2.name) {
+        (Some(name_1), Some(name_2)) => name_1.cmp(name_2),
+        (Some(_name), None) => Ordering::Greater,
+        (None, Some(_name)) => Ordering::Less,
+        (None, None) => match (room_1.canonical_alias, room_2.canonical_alias) {
+            (Some(alias_1), Some(alias_2)) => alias_1.cmp(alias_2),
+            (Some(_alias), None) => Ordering::Greater,
+            (None, Some(_alias)) => Ordering::Less,
+            (None, None) => room_1.id.cmp(room_2.id),
+        },
+    };
+    match ordering {
+        RoomOrdering::Name => by_name(),
+        RoomOrdering::RecentActivity => room_2.latest_timestamp.cmp(&room_1.latest_timestamp).then_with(by_name),
+        RoomOrdering::MemberCount => room_2.joined_member_count.cmp(&room_1.joined_member_count).then_with(by_name),
+    }
+}
+
+// Upgrades the sessions file's JSON from whichever historical schema it's in to the current one, one version at a time, so that old files keep loading as the schema changes. Also returns whether any upgrading was needed, so that the file can be rewritten in the current schema.
+fn migrate_sessions_file(file: &str) -> Result<(Vec<Session>, bool), String> {
+    let mut contents: serde_json::Value = serde_json::from_str(file).map_err(|e| format!("Sessions file is invalid JSON: {}", e))?;
+    let mut migrated = false;
+    // Version 0, from before the file was versioned, was a bare array of sessions
+    if contents.is_array() {
+        contents = serde_json::json!({
+            "version": 1,
+            "sessions": contents,
+        });
+        migrated = true;
+    }
+    match contents.get("version").and_then(|version| version.as_u64()) {
+        Some(version) if version > SESSIONS_FILE_VERSION => return Err(format!("Sessions file has schema version {}, but this version of trace only understands up to version {}. It was presumably written by a newer version of trace.", version, SESSIONS_FILE_VERSION)),
+        Some(_) => (),
+        None => return Err(String::from("Sessions file has no schema version.")),
+    }
+    let sessions = contents.get_mut("sessions").map(serde_json::Value::take).ok_or_else(|| String::from("Sessions file has no sessions list."))?;
+    let sessions = serde_json::from_value(sessions).map_err(|e| format!("Sessions file has invalid sessions: {}", e))?;
+    Ok((sessions, migrated))
+}
+
+fn encrypt_sessions_file(plaintext: &str, passphrase: &str) -> Vec<u8> {
+    let mut salt = [0; SESSIONS_KDF_SALT_LENGTH];
+    let mut nonce = [0; SESSIONS_CIPHER_NONCE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let cipher = ChaCha20Poly1305::new(&sessions_file_key(passphrase, &salt));
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes()).expect("Failed to encrypt sessions file. (This should never happen.)");
+
+    [ENCRYPTED_SESSIONS_FILE_MAGIC, &salt, &nonce, &ciphertext].concat()
+}
+
+fn decrypt_sessions_file(file: &[u8], passphrase: &str) -> Result<String, String> {
+    let header_length = ENCRYPTED_SESSIONS_FILE_MAGIC.len() + SESSIONS_KDF_SALT_LENGTH + SESSIONS_CIPHER_NONCE_LENGTH;
+    if file.len() < header_length {
+        return Err(String::from("Encrypted sessions file is truncated."))
+    }
+    let (salt, rest) = file[ENCRYPTED_SESSIONS_FILE_MAGIC.len()..].split_at(SESSIONS_KDF_SALT_LENGTH);
+    let (nonce, ciphertext) = rest.split_at(SESSIONS_CIPHER_NONCE_LENGTH);
+    let cipher = ChaCha20Poly1305::new(&sessions_file_key(passphrase, salt));
+    let plaintext = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| String::from("Couldn't decrypt sessions file. Is the passphrase right?"))?;
+
+    String::from_utf8(plaintext).map_err(|_| String::from("Decrypted sessions file isn't valid UTF-8."))
+}
+
 pub fn add_at_to_user_id_if_applicable(user_id: &str) -> String {
     if user_id.starts_with('@') {
         String::from(user_id)
@@ -136,6 +569,29 @@ pub fn add_at_to_user_id_if_applicable(user_id: &str) -> String {
     }
 }
 
+/// Sync settings which every sync trace performs should use. Presence is explicitly set to offline so that archiving never marks the account as online; beyond that, syncing by itself never sends read receipts, read markers, or typing notifications, and trace never sends any of those implicitly either, so an archiving account leaves no footprint in the rooms it reads.
+pub fn default_sync_settings() -> SyncSettings {
+    SyncSettings::new().set_presence(PresenceState::Offline)
+}
+
+/// Runs the operation, failing with `TraceError::Timeout` if it takes longer than `timeout`, so that unresponsive homeservers can't hang trace indefinitely. A `timeout` of `None` waits forever.
+pub async fn with_timeout<T>(timeout: Option<Duration>, operation: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, operation).await {
+            Ok(result) => result,
+            Err(_) => Err(TraceError::Timeout(timeout).into()),
+        },
+        None => operation.await,
+    }
+}
+
+/// The path, relative to the data directory, of the record of rooms which the account's `all_rooms` exports have exported, as used by `ExportOptions::only_new_rooms`. Kept apart from the crypto store, so that it outlives logging out and back in.
+pub fn user_id_to_exported_rooms_path(user_id: &str) -> PathBuf {
+    let mut exported_rooms_path = Path::new(EXPORTED_ROOMS_DIRECTORY_NAME).join(user_id_to_crypto_store_path(user_id));
+    exported_rooms_path.set_extension("json");
+    exported_rooms_path
+}
+
 pub fn user_id_to_crypto_store_path(user_id: &str) -> PathBuf {
     let atless_user_id = if user_id.starts_with('@') {
         user_id.chars().skip(1).collect()
@@ -150,11 +606,14 @@ pub fn user_id_to_crypto_store_path(user_id: &str) -> PathBuf {
     store_path
 }
 
-pub async fn nonfirst_login(user_id: &str, sessions_file: &SessionsFile, store_path: &Path) -> anyhow::Result<Client> {
+pub async fn nonfirst_login(user_id: &str, sessions_file: &SessionsFile, store_path: &Path, client_config: &ClientConfig) -> anyhow::Result<Client> {
     let normalized_user_id = add_at_to_user_id_if_applicable(user_id);
-    let session = sessions_file.get(&normalized_user_id).unwrap();
+    let session = match sessions_file.get(&normalized_user_id) {
+        Ok(session) => session,
+        Err(_) => anyhow::bail!("No logged-in session for {}. Run 'session login {}' to log in.", normalized_user_id, normalized_user_id),
+    };
     let user = UserId::parse(&session.user_id)?;
-    let client = Client::builder().server_name(user.server_name()).sqlite_store(store_path, None).build().await?;
+    let client = client_config.client_builder(&user).sqlite_store(store_path, None).build().await?;
     client.matrix_auth().restore_session(MatrixSession {
         meta: SessionMeta {
             user_id: user,
@@ -165,6 +624,13 @@ pub async fn nonfirst_login(user_id: &str, sessions_file: &SessionsFile, store_p
             refresh_token: session.refresh_token,
         }
     }).await?;
+    // Restoring a session doesn't contact the server, so an expired access token would otherwise surface as a confusing failure of whatever request happens to come first. Refreshing the token isn't attempted, since refresh tokens are single-use, and the sessions file isn't writable from here to save the new one; `refresh_session_tokens` does so explicitly.
+    if let Err(e) = client.send(whoami::v3::Request::new(), None).await {
+        return match e.client_api_error_kind() {
+            Some(ErrorKind::UnknownToken { .. }) => Err(TraceError::SessionExpired(normalized_user_id).into()),
+            _ => Err(e.into()),
+        }
+    }
     client.encryption().wait_for_e2ee_initialization_tasks().await;
 
     Ok(client)
@@ -176,21 +642,12 @@ pub async fn nonfirst_login(user_id: &str, sessions_file: &SessionsFile, store_p
 
 pub async fn first_login(client: &Client, sessions_file: &mut SessionsFile, user_id: &str, password: &str, session_name: Option<String>) -> anyhow::Result<()> {
     let auth = client.matrix_auth();
-    let supported_login_types = auth.get_login_types().await?.flows;
-    let login_result = if supported_login_types.iter().any(|login_type| match login_type {
-        LoginType::Password(_) => true,
-        _ => false,
-    }) {
-        let login_request = auth.login_username(user_id, password);
-        if let Some(name) = session_name {
-            login_request.initial_device_display_name(&name).send().await?
-        } else {
-            // Do we want some sort of default name here?
-            login_request.send().await?
-        }
-    } else {
-        panic!("Attempted login to a server which lacks password-based login support. (SSO support will be added eventually.)");
-    };
+    let supported_login_types = auth.get_login_types().await.map_err(|e| LoginError::from(matrix_sdk::Error::from(e)))?.flows;
+    if !supported_login_types.iter().any(|login_type| matches!(login_type, LoginType::Password(_))) {
+        return Err(LoginError::PasswordLoginUnsupported(supported_login_types.iter().map(|login_type| login_type.login_type().to_owned()).collect()).into())
+    }
+    let device_display_name = session_name.unwrap_or_else(|| String::from(DEFAULT_DEVICE_DISPLAY_NAME));
+    let login_result = auth.login_username(user_id, password).initial_device_display_name(&device_display_name).send().await.map_err(LoginError::from)?;
 
     sessions_file.new_session(Session {
         user_id: login_result.user_id.to_string(),
@@ -200,7 +657,7 @@ pub async fn first_login(client: &Client, sessions_file: &mut SessionsFile, user
     }).unwrap();
 
     client.encryption().wait_for_e2ee_initialization_tasks().await;
-    client.sync_once(SyncSettings::new().set_presence(PresenceState::Offline)).await?;
+    client.sync_once(default_sync_settings()).await?;
 
     Ok(())
 }
@@ -228,15 +685,28 @@ pub fn logout_local(user_id: &str, sessions_file: &mut SessionsFile, store_path:
     Ok(())
 }
 
-pub async fn list_sessions(sessions_file: &SessionsFile, dirs: &ProjectDirs) -> anyhow::Result<Vec<(String, String)>> {
+pub async fn list_sessions(sessions_file: &SessionsFile, dirs: &ProjectDirs, client_config: &ClientConfig) -> anyhow::Result<Vec<SessionInfo>> {
+    list_sessions_in_data_dir(sessions_file, dirs.data_local_dir(), client_config).await
+}
+
+async fn list_sessions_in_data_dir(sessions_file: &SessionsFile, data_dir: &Path, client_config: &ClientConfig) -> anyhow::Result<Vec<SessionInfo>> {
     let mut sessions_info = join_all(sessions_file.sessions.iter().map(|session| async {
-        let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&session.user_id));
-        let client = nonfirst_login(&session.user_id, sessions_file, &store_path).await?;
+        let store_path = data_dir.join(user_id_to_crypto_store_path(&session.user_id));
+        let client = nonfirst_login(&session.user_id, sessions_file, &store_path, client_config).await?;
         let device_list = client.devices().await?.devices;
         let device_name = device_list.into_iter().find(|device| device.device_id == session.device_id).unwrap().display_name.unwrap_or_else(|| String::from("[Unnamed]"));
-        anyhow::Result::<(String, String)>::Ok((session.user_id.clone(), device_name))
-    })).await.into_iter().collect::<anyhow::Result<Vec<(String, String)>, _>>()?;
-    sessions_info.sort_by(|(user_id_1, _display_name_1), (user_id_2, _display_name_2)| user_id_1.cmp(user_id_2)); // sort_by_key doesn't work here for weird lifetime reasons
+        let verification_status = match client.encryption().get_own_device().await {
+            Ok(Some(device)) if device.is_verified() => SessionVerificationStatus::Verified,
+            Ok(Some(_)) => SessionVerificationStatus::Unverified,
+            Ok(None) | Err(_) => SessionVerificationStatus::Unknown,
+        };
+        anyhow::Result::<SessionInfo>::Ok(SessionInfo {
+            user_id: session.user_id.clone(),
+            device_name,
+            verification_status,
+        })
+    })).await.into_iter().collect::<anyhow::Result<Vec<SessionInfo>, _>>()?;
+    sessions_info.sort_by(|session_info_1, session_info_2| session_info_1.user_id.cmp(&session_info_2.user_id)); // sort_by_key doesn't work here for weird lifetime reasons
 
     Ok(sessions_info)
 }
@@ -247,25 +717,284 @@ pub async fn rename_session(client: &Client, new_session_name: &str) -> anyhow::
     Ok(())
 }
 
-pub async fn get_rooms_info(client: &Client) -> anyhow::Result<Vec<RoomWithCachedInfo>> {
-    let mut rooms_info = client.joined_rooms().into_iter().map(|room| RoomWithCachedInfo {
-        id: room.room_id().to_owned(),
-        name: room.name(),
-        canonical_alias: room.canonical_alias(),
-        alt_aliases: room.alt_aliases(),
-        room,
-    }).collect::<Vec<RoomWithCachedInfo>>();
-    rooms_info.sort_by(|room_1, room_2| match (&room_1.name, &room_2.name) {
-        (Some(name_1), Some(name_2)) => name_1.cmp(&name_2),
-        (Some(_name), None) => Ordering::Greater,
-        (None, Some(_name)) => Ordering::Less,
-        (None, None) => match (&room_1.canonical_alias, &room_2.canonical_alias) {
-            (Some(alias_1), Some(alias_2)) => alias_1.cmp(&alias_2),
-            (Some(_alias), None) => Ordering::Greater,
-            (None, Some(_alias)) => Ordering::Less,
-            (None, None) => room_1.id.cmp(&room_2.id),
+/// Forces a refresh of a session's access token using its refresh token, saving the new tokens to the sessions file. Refresh tokens are single-use, so the saved session is only usable afterwards because the new ones are saved. As with `validate_session`, uses a throwaway in-memory store, since only the tokens are involved; this also means it works on sessions whose access token has already expired.
+pub async fn refresh_session_tokens(sessions_file: &mut SessionsFile, user_id: &str, client_config: &ClientConfig) -> anyhow::Result<TokenRefresh> {
+    let normalized_user_id = add_at_to_user_id_if_applicable(user_id);
+    let session = match sessions_file.get(&normalized_user_id) {
+        Ok(session) => session,
+        Err(_) => anyhow::bail!("No logged-in session for {}. Run 'session login {}' to log in.", normalized_user_id, normalized_user_id),
+    };
+    if session.refresh_token.is_none() {
+        return Ok(TokenRefresh::NoRefreshToken)
+    }
+    let user = UserId::parse(&session.user_id)?;
+    let client = client_config.client_builder(&user).build().await?;
+    client.matrix_auth().restore_session(MatrixSession {
+        meta: SessionMeta {
+            user_id: user,
+            device_id: session.device_id.as_str().into(),
         },
-    });
+        tokens: MatrixSessionTokens {
+            access_token: session.access_token,
+            refresh_token: session.refresh_token,
+        }
+    }).await?;
+    let Some(response) = client.matrix_auth().refresh_access_token().await? else {
+        return Ok(TokenRefresh::NoRefreshToken)
+    };
+    sessions_file.update_session_tokens(&normalized_user_id, response.access_token.clone(), response.refresh_token).map_err(|e| anyhow::anyhow!(e))?;
+
+    Ok(TokenRefresh::Refreshed {
+        access_token: response.access_token,
+        expires_in: response.expires_in_ms,
+    })
+}
+
+/// Checks with the homeserver that a session's access token is still valid and belongs to the session's own user and device. Uses a throwaway in-memory store, so it's safe to run on sessions whose crypto store lives on another machine.
+pub async fn validate_session(session: &Session, client_config: &ClientConfig) -> anyhow::Result<()> {
+    let user = UserId::parse(&session.user_id)?;
+    let client = client_config.client_builder(&user).build().await?;
+    client.matrix_auth().restore_session(MatrixSession {
+        meta: SessionMeta {
+            user_id: user.clone(),
+            device_id: session.device_id.as_str().into(),
+        },
+        tokens: MatrixSessionTokens {
+            access_token: session.access_token.clone(),
+            refresh_token: session.refresh_token.clone(),
+        }
+    }).await?;
+    let whoami_response = client.send(whoami::v3::Request::new(), None).await?;
+    if whoami_response.user_id != user {
+        anyhow::bail!("Session for {} has an access token belonging to {}.", session.user_id, whoami_response.user_id);
+    }
+    if let Some(device_id) = whoami_response.device_id {
+        if device_id.as_str() != session.device_id {
+            anyhow::bail!("Session for {} claims device {}, but its access token belongs to device {}.", session.user_id, session.device_id, device_id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns info on every room joined by the client, in the default `RoomOrdering::Name` order: rooms are sorted by display name; rooms without a name come before named ones and are sorted by canonical alias; rooms with neither come first of all and are sorted by room ID. This order is part of the API and won't change.
+pub async fn get_rooms_info(client: &Client) -> anyhow::Result<Vec<RoomWithCachedInfo>> {
+    get_rooms_info_sorted(client, RoomOrdering::Name).await
+}
+
+/// As `get_rooms_info`, but in the specified order. Non-default orderings break ties by the default ordering.
+pub async fn get_rooms_info_sorted(client: &Client, ordering: RoomOrdering) -> anyhow::Result<Vec<RoomWithCachedInfo>> {
+    let mut rooms_info = Vec::new();
+    for room in client.joined_rooms() {
+        rooms_info.push(RoomWithCachedInfo {
+            id: room.room_id().to_owned(),
+            name: room.name(),
+            canonical_alias: room.canonical_alias(),
+            alt_aliases: room.alt_aliases(),
+            joined_member_count: room.joined_members_count(),
+            encrypted: BaseRoom::is_encrypted(&room), // The cached state, since the definitive one can take a request per room, and every room listing and lookup goes through here
+            room,
+        });
+    }
+    let latest_timestamps = match ordering {
+        RoomOrdering::RecentActivity => join_all(rooms_info.iter().map(|room_info| latest_event_timestamp(&room_info.room))).await.into_iter().collect::<anyhow::Result<Vec<Option<u64>>>>()?,
+        RoomOrdering::Name | RoomOrdering::MemberCount => vec![None; rooms_info.len()],
+    };
+    let mut rooms_with_timestamps = rooms_info.into_iter().zip(latest_timestamps).collect::<Vec<(RoomWithCachedInfo, Option<u64>)>>();
+    rooms_with_timestamps.sort_by(|(room_1, timestamp_1), (room_2, timestamp_2)| compare_rooms(ordering, &RoomSortKey::new(room_1, *timestamp_1), &RoomSortKey::new(room_2, *timestamp_2)));
+
+    Ok(rooms_with_timestamps.into_iter().map(|(room_info, _timestamp)| room_info).collect())
+}
+
+async fn latest_event_timestamp(room: &Room) -> anyhow::Result<Option<u64>> {
+    let mut messages_options = MessagesOptions::backward();
+    messages_options.limit = 1_u16.into();
+    let messages = room.messages(messages_options).await?;
+    Ok(messages.chunk.first().and_then(|event| event.event.get_field::<u64>("origin_server_ts").ok().flatten()))
+}
+
+pub async fn get_user_profile(client: &Client, user_id: &UserId) -> anyhow::Result<UserProfile> {
+    let global_profile = client.send(get_profile::v3::Request::new(user_id.to_owned()), None).await?;
+
+    let mut rooms = Vec::new();
+    for room_info in get_rooms_info(client).await? {
+        if let Some(member) = room_info.room.get_member_no_sync(user_id).await? {
+            rooms.push(RoomMembershipInfo {
+                room_id: room_info.id.to_string(),
+                room_name: room_info.name,
+                membership: member.membership().to_string(),
+                display_name: member.display_name().map(String::from),
+                avatar_url: member.avatar_url().map(|url| url.to_string()),
+                power_level: member.power_level(),
+            });
+        }
+    }
+
+    Ok(UserProfile {
+        user_id: user_id.to_string(),
+        display_name: global_profile.displayname,
+        avatar_url: global_profile.avatar_url.map(|url| url.to_string()),
+        rooms,
+    })
+}
+
+////////////////
+//   Facade   //
+////////////////
+
+/// A handle bundling a data directory together with the sessions file inside it, for library consumers who'd rather not manage `SessionsFile`s and crypto store paths by hand. Each method wraps the free function of the same purpose.
+pub struct Trace {
+    data_dir: PathBuf,
+    pub sessions_file: SessionsFile,
+    pub client_config: ClientConfig,
+}
+
+impl Trace {
+    /// Panics if the sessions file can't be opened, e.g. because it's been encrypted with `--encrypt-sessions-file`; `open_with_passphrase` reports that as an error instead.
+    pub fn new(data_dir: PathBuf) -> Self {
+        Self::open_with_passphrase(data_dir, None).unwrap()
+    }
+
+    /// As `new`, but with the sessions file encrypted at rest under the given passphrase (see `SessionsFile::open_with_passphrase`), and failing rather than panicking if it can't be opened, including when it's encrypted and no passphrase (or the wrong one) is given.
+    pub fn open_with_passphrase(data_dir: PathBuf, passphrase: Option<String>) -> anyhow::Result<Self> {
+        let sessions_file_path = data_dir.join(SESSIONS_FILENAME);
+        let sessions_file = SessionsFile::open_with_passphrase(sessions_file_path.clone(), passphrase).map_err(|e| anyhow::anyhow!("Couldn't open sessions file {}: {}", sessions_file_path.display(), e))?;
+        Ok(Self {
+            data_dir,
+            sessions_file,
+            client_config: ClientConfig::default(),
+        })
+    }
+
+    /// Opens the same data directory which the Trace CLI uses.
+    pub fn with_default_data_dir() -> Option<Self> {
+        ProjectDirs::from("", "", "Trace").map(|dirs| Self::new(PathBuf::from(dirs.data_local_dir())))
+    }
+
+    pub fn store_path(&self, user_id: &str) -> PathBuf {
+        self.data_dir.join(user_id_to_crypto_store_path(user_id))
+    }
+
+    /// Restores a previously-logged-in session and syncs it, returning a client ready for use.
+    pub async fn client(&self, user_id: &str) -> anyhow::Result<Client> {
+        let client = nonfirst_login(user_id, &self.sessions_file, &self.store_path(user_id), &self.client_config).await?;
+        client.sync_once(default_sync_settings()).await?;
+        Ok(client)
+    }
+
+    pub async fn login(&mut self, user_id: &str, password: &str, session_name: Option<String>) -> anyhow::Result<Client> {
+        let normalized_user_id = add_at_to_user_id_if_applicable(user_id);
+        let user = UserId::parse(&normalized_user_id)?;
+        let client = self.client_config.client_builder(&user).sqlite_store(self.store_path(&normalized_user_id), None).build().await?;
+        first_login(&client, &mut self.sessions_file, &normalized_user_id, password, session_name).await?;
+        Ok(client)
+    }
+
+    /// Logs out on both the server and client sides.
+    pub async fn logout(&mut self, user_id: &str) -> anyhow::Result<()> {
+        let store_path = self.store_path(user_id);
+        let client = nonfirst_login(user_id, &self.sessions_file, &store_path, &self.client_config).await?;
+        logout_full(&client, &mut self.sessions_file, &store_path).await
+    }
+
+    /// Logs out on the client side only, for use when the server can't be reached.
+    pub fn logout_local(&mut self, user_id: &str) -> anyhow::Result<()> {
+        let store_path = self.store_path(user_id);
+        logout_local(&add_at_to_user_id_if_applicable(user_id), &mut self.sessions_file, &store_path)
+    }
+
+    pub async fn list_sessions(&self) -> anyhow::Result<Vec<SessionInfo>> {
+        list_sessions_in_data_dir(&self.sessions_file, &self.data_dir, &self.client_config).await
+    }
+
+    pub async fn rename_session(&self, user_id: &str, new_session_name: &str) -> anyhow::Result<()> {
+        let client = nonfirst_login(user_id, &self.sessions_file, &self.store_path(user_id), &self.client_config).await?;
+        rename_session(&client, new_session_name).await
+    }
 
-    Ok(rooms_info)
+    pub async fn rooms(&self, user_id: &str) -> anyhow::Result<Vec<RoomWithCachedInfo>> {
+        get_rooms_info(&self.client(user_id).await?).await
+    }
+
+    pub async fn export_rooms(&self, user_id: &str, rooms: Vec<String>, options: ExportOptions) -> anyhow::Result<usize> {
+        export(&self.client(user_id).await?, rooms, options).await
+    }
+
+    pub async fn export_room(&self, user_id: &str, room: &str, options: ExportOptions) -> anyhow::Result<()> {
+        self.export_rooms(user_id, vec![String::from(room)], options).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION_JSON: &str = r#"{"user_id":"@alice:example.org","device_id":"ALICEDEVICE","access_token":"secret","refresh_token":null}"#;
+
+    fn assert_is_alices_session(sessions: &[Session]) {
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].user_id, "@alice:example.org");
+        assert_eq!(sessions[0].device_id, "ALICEDEVICE");
+        assert_eq!(sessions[0].access_token, "secret");
+        assert_eq!(sessions[0].refresh_token, None);
+    }
+
+    #[test]
+    fn unversioned_sessions_files_are_migrated() {
+        let (sessions, migrated) = migrate_sessions_file(&format!("[{}]", SESSION_JSON)).unwrap();
+        assert_is_alices_session(&sessions);
+        assert!(migrated);
+    }
+
+    #[test]
+    fn version_1_sessions_files_load_unchanged() {
+        let (sessions, migrated) = migrate_sessions_file(&format!(r#"{{"version":1,"sessions":[{}]}}"#, SESSION_JSON)).unwrap();
+        assert_is_alices_session(&sessions);
+        assert!(!migrated);
+    }
+
+    #[test]
+    fn newer_and_unknown_sessions_file_versions_are_rejected() {
+        let newer_version = SESSIONS_FILE_VERSION + 1;
+        let error = migrate_sessions_file(&format!(r#"{{"version":{},"sessions":[{}]}}"#, newer_version, SESSION_JSON)).err().unwrap();
+        assert!(error.contains(&format!("schema version {}", newer_version)));
+        assert!(migrate_sessions_file(&format!(r#"{{"version":"one","sessions":[{}]}}"#, SESSION_JSON)).is_err());
+        assert!(migrate_sessions_file(&format!(r#"{{"sessions":[{}]}}"#, SESSION_JSON)).is_err());
+    }
+
+    fn room_sort_key<'a>(name: Option<&'a str>, canonical_alias: Option<&'a str>, id: &'a str, latest_timestamp: Option<u64>, joined_member_count: u64) -> RoomSortKey<'a> {
+        RoomSortKey { name, canonical_alias, id, latest_timestamp, joined_member_count }
+    }
+
+    fn sorted_room_ids(ordering: RoomOrdering, mut rooms: Vec<RoomSortKey<'_>>) -> Vec<&str> {
+        rooms.sort_by(|room_1, room_2| compare_rooms(ordering, room_1, room_2));
+        rooms.into_iter().map(|room| room.id).collect()
+    }
+
+    fn sample_rooms() -> Vec<RoomSortKey<'static>> {
+        vec![
+            room_sort_key(Some("Beta"), None, "!b:example.org", Some(300), 5),
+            room_sort_key(None, Some("#zeta:example.org"), "!z:example.org", Some(100), 20),
+            room_sort_key(Some("Alpha"), Some("#alpha:example.org"), "!a:example.org", Some(100), 5),
+            room_sort_key(None, None, "!y:example.org", None, 1),
+            room_sort_key(None, Some("#eta:example.org"), "!e:example.org", None, 20),
+            room_sort_key(None, None, "!x:example.org", Some(300), 1),
+        ]
+    }
+
+    #[test]
+    fn rooms_are_ordered_by_name_then_alias_then_id() {
+        assert_eq!(sorted_room_ids(RoomOrdering::Name, sample_rooms()), vec!["!x:example.org", "!y:example.org", "!e:example.org", "!z:example.org", "!a:example.org", "!b:example.org"]);
+    }
+
+    #[test]
+    fn rooms_are_ordered_by_recent_activity_with_ties_broken_by_name() {
+        assert_eq!(sorted_room_ids(RoomOrdering::RecentActivity, sample_rooms()), vec!["!x:example.org", "!b:example.org", "!z:example.org", "!a:example.org", "!y:example.org", "!e:example.org"]);
+    }
+
+    #[test]
+    fn rooms_are_ordered_by_member_count_with_ties_broken_by_name() {
+        assert_eq!(sorted_room_ids(RoomOrdering::MemberCount, sample_rooms()), vec!["!e:example.org", "!z:example.org", "!a:example.org", "!b:example.org", "!x:example.org", "!y:example.org"]);
+    }
 }
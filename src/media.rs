@@ -0,0 +1,324 @@
+use std::fs::{
+    metadata,
+    read,
+    remove_file,
+    rename,
+    write,
+};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{
+    export::make_filesystem_safe,
+    rate_limit::{
+        self,
+        RateLimiter,
+    },
+    TraceError,
+};
+
+use matrix_sdk::{
+    deserialized_responses::TimelineEvent,
+    media::{
+        MediaFormat,
+        MediaRequest,
+        MediaThumbnailSize,
+    },
+    ruma::{
+        api::client::{
+            error::ErrorKind,
+            media::get_content_thumbnail::v3::Method,
+        },
+        events::{
+            room::{
+                message::MessageType,
+                MediaSource,
+            },
+            AnyMessageLikeEvent,
+            AnyTimelineEvent,
+        },
+        MxcUri,
+        OwnedEventId,
+        UInt,
+    },
+    Client,
+    HttpError,
+};
+
+///////////////////
+//   Constants   //
+///////////////////
+
+pub const MEDIA_DIRECTORY_NAME: &str = "media";
+pub const DEFAULT_INLINE_IMAGE_MAX_SIZE: u64 = 1 << 20;
+
+const MEDIA_DOWNLOAD_ATTEMPTS: u32 = 3;
+// Doubled after each failed attempt
+const MEDIA_RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const PARTIAL_MEDIA_EXTENSION: &str = "part";
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Used when a thumbnail is requested but the sender didn't attach one, so we have to ask the server to generate one
+const SERVER_THUMBNAIL_WIDTH: u32 = 800;
+const SERVER_THUMBNAIL_HEIGHT: u32 = 600;
+// Attachment bodies are usually filenames, but can be whole captions, so only this many bytes of one go into a media filename, keeping it well within filesystems' 255-byte limit
+const MEDIA_FILENAME_NAME_LENGTH: usize = 64;
+// Longer "extensions" are just parts of names with dots in them, so they're left out
+const MEDIA_FILENAME_EXTENSION_LENGTH: usize = 16;
+
+///////////////
+//   Types   //
+///////////////
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum MediaVariant {
+    #[default]
+    Full,
+    Thumbnail,
+}
+
+impl MediaVariant {
+    pub fn from_specifier(specifier: &str) -> Option<Self> {
+        match specifier.to_lowercase().as_ref() {
+            "full" => Some(Self::Full),
+            "thumbnail" => Some(Self::Thumbnail),
+            _ => None,
+        }
+    }
+}
+
+/// How exports refer to attachments.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum MediaLinkKind {
+    /// Paths, relative to the export, of the copies downloaded into its media directory, for self-contained archives. Requires media to be downloaded; otherwise attachments aren't linked at all.
+    #[default]
+    Relative,
+    /// The attachments' original `mxc://` URIs.
+    Mxc,
+    /// Download URLs on the exporting account's homeserver, for re-fetching attachments from outside a Matrix client. They generally require authentication (with access tokens no longer accepted as query parameters), so aren't guaranteed to work from a browser.
+    Http,
+}
+
+impl MediaLinkKind {
+    pub fn from_specifier(specifier: &str) -> Option<Self> {
+        match specifier.to_lowercase().as_ref() {
+            "relative" => Some(Self::Relative),
+            "mxc" => Some(Self::Mxc),
+            "http" => Some(Self::Http),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct MediaAttachment {
+    pub event_id: OwnedEventId,
+    pub source: MediaSource,
+    pub thumbnail_source: Option<MediaSource>,
+    pub body: String,
+}
+
+//////////////
+//   Main   //
+//////////////
+
+pub(crate) fn media_attachment(event: &TimelineEvent) -> Option<MediaAttachment> {
+    let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(room_message)) = event.event.deserialize().ok()? else {
+        return None
+    };
+    let original_room_message = room_message.as_original()?;
+    let (source, thumbnail_source, body) = match &original_room_message.content.msgtype {
+        MessageType::Audio(content) => (content.source.clone(), None, content.body.clone()),
+        MessageType::File(content) => (content.source.clone(), content.info.as_ref().and_then(|info| info.thumbnail_source.clone()), content.body.clone()),
+        MessageType::Image(content) => (content.source.clone(), content.info.as_ref().and_then(|info| info.thumbnail_source.clone()), content.body.clone()),
+        MessageType::Video(content) => (content.source.clone(), content.info.as_ref().and_then(|info| info.thumbnail_source.clone()), content.body.clone()),
+        _ => return None,
+    };
+
+    Some(MediaAttachment {
+        event_id: original_room_message.event_id.clone(),
+        source,
+        thumbnail_source,
+        body,
+    })
+}
+
+// Also returns the variant which will actually be fetched, since thumbnails aren't always available
+fn media_request(attachment: &MediaAttachment, variant: MediaVariant) -> (MediaRequest, MediaVariant) {
+    match (variant, &attachment.thumbnail_source, &attachment.source) {
+        (MediaVariant::Thumbnail, Some(thumbnail_source), _) => (MediaRequest {
+            source: thumbnail_source.clone(),
+            format: MediaFormat::File,
+        }, MediaVariant::Thumbnail),
+        (MediaVariant::Thumbnail, None, MediaSource::Plain(_)) => (MediaRequest {
+            source: attachment.source.clone(),
+            format: MediaFormat::Thumbnail(MediaThumbnailSize {
+                method: Method::Scale,
+                width: UInt::from(SERVER_THUMBNAIL_WIDTH),
+                height: UInt::from(SERVER_THUMBNAIL_HEIGHT),
+            }),
+        }, MediaVariant::Thumbnail),
+        // Servers can't generate thumbnails of encrypted media, so those fall back to the full-resolution original
+        _ => (MediaRequest {
+            source: attachment.source.clone(),
+            format: MediaFormat::File,
+        }, MediaVariant::Full),
+    }
+}
+
+fn media_source_mxc_uri(source: &MediaSource) -> &MxcUri {
+    match source {
+        MediaSource::Plain(uri) => uri,
+        MediaSource::Encrypted(file) => &file.url,
+    }
+}
+
+// Links to the full-resolution original, whichever variant was downloaded. Encrypted attachments' links lead to their ciphertext, which can only be decrypted with the key in the event. Relative links can't be made here, since they depend on the download.
+pub(crate) fn remote_media_link(attachment: &MediaAttachment, kind: MediaLinkKind, homeserver: &str) -> Option<String> {
+    let mxc_uri = media_source_mxc_uri(&attachment.source);
+    match kind {
+        MediaLinkKind::Relative => None,
+        MediaLinkKind::Mxc => Some(mxc_uri.to_string()),
+        MediaLinkKind::Http => {
+            let (server_name, media_id) = mxc_uri.parts().ok()?;
+            Some(format!("{}/_matrix/media/v3/download/{}/{}", homeserver.trim_end_matches('/'), server_name, media_id))
+        }
+    }
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (index, byte)| group | (u32::from(*byte) << (16 - 8 * index)));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(char::from(BASE64_ALPHABET[((group >> (18 - 6 * index)) & 0x3F) as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Only types which browsers reliably display in `<img>` tags
+fn image_mime_type(path: &Path) -> Option<&'static str> {
+    match path.extension()?.to_string_lossy().to_lowercase().as_str() {
+        "gif" => Some("image/gif"),
+        "jpeg" | "jpg" => Some("image/jpeg"),
+        "png" => Some("image/png"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+// A data URI of a downloaded image, for embedding in html, or `None` if it's too big to be worth embedding or isn't of a type which can be
+pub(crate) fn inline_image_data_uri(path: &Path, max_size: u64) -> Result<Option<String>, TraceError> {
+    let Some(mime_type) = image_mime_type(path) else {
+        return Ok(None)
+    };
+    let size = metadata(path).map_err(|e| TraceError::OutputIo { path: path.to_path_buf(), source: e })?.len();
+    if size > max_size {
+        return Ok(None)
+    }
+    let image = read(path).map_err(|e| TraceError::OutputIo { path: path.to_path_buf(), source: e })?;
+    Ok(Some(format!("data:{};base64,{}", mime_type, base64_encode(&image))))
+}
+
+// How long to wait before retrying a failed download, if it failed in a way that's likely to be transient (network failures and rate limiting), or `None` if it isn't worth retrying
+fn media_retry_delay(error: &matrix_sdk::Error, retry: u32) -> Option<Duration> {
+    let backoff = MEDIA_RETRY_INITIAL_DELAY * 2u32.pow(retry);
+    match error.client_api_error_kind() {
+        Some(ErrorKind::LimitExceeded { retry_after_ms }) => Some(retry_after_ms.unwrap_or(backoff)),
+        Some(_) => None,
+        None => match error {
+            matrix_sdk::Error::Http(HttpError::Reqwest(_)) => Some(backoff),
+            _ => None,
+        },
+    }
+}
+
+// Cut at a character boundary, so that it stays valid UTF-8
+fn truncate_to_bytes(text: &str, max_length: usize) -> &str {
+    let mut end = text.len().min(max_length);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+fn media_filename(mxc_uri: &MxcUri, body: &str, variant_suffix: &str) -> String {
+    let body_path = Path::new(body);
+    let extension = match body_path.extension().map(|extension| make_filesystem_safe(&extension.to_string_lossy())) {
+        Some(extension) if extension.len() <= MEDIA_FILENAME_EXTENSION_LENGTH => format!(".{}", extension),
+        _ => String::new(),
+    };
+    let name = match body_path.file_stem() {
+        Some(stem) => format!("_{}", truncate_to_bytes(&make_filesystem_safe(&stem.to_string_lossy()), MEDIA_FILENAME_NAME_LENGTH)),
+        None => String::new(),
+    };
+    format!("{}{}{}{}", make_filesystem_safe(mxc_uri.as_str().trim_start_matches("mxc://")), name, variant_suffix, extension)
+}
+
+// The filename (within the media directory) which an attachment is saved under by `download_media`
+pub(crate) fn attachment_media_filename(attachment: &MediaAttachment, variant: MediaVariant) -> String {
+    let (request, fetched_variant) = media_request(attachment, variant);
+    let variant_suffix = match (fetched_variant, &request.format) {
+        (MediaVariant::Full, _) => "",
+        (MediaVariant::Thumbnail, MediaFormat::File) => "", // The sender's own thumbnail has an mxc URI of its own
+        (MediaVariant::Thumbnail, MediaFormat::Thumbnail(_)) => ".thumbnail",
+    };
+    media_filename(media_source_mxc_uri(&request.source), &attachment.body, variant_suffix)
+}
+
+// Media is stored content-addressed by its mxc URI, which is unique to each upload, so that attachments with the same name can't clobber one another, and the same attachment reposted across events or rooms under the same name is only downloaded and stored once. The attachment's own name follows the mxc URI, for readability (e.g. `example.org-AbCdEf_photo.jpg`). Returns the filename (within the media directory) which the media was saved under.
+pub(crate) async fn download_media(client: &Client, attachment: &MediaAttachment, variant: MediaVariant, media_directory: &Path, rate_limiter: Option<&RateLimiter>) -> anyhow::Result<String> {
+    let (request, _fetched_variant) = media_request(attachment, variant);
+    let filename = attachment_media_filename(attachment, variant);
+
+    let path = media_directory.join(&filename);
+    if !path.exists() {
+        let mut retry = 0;
+        let media_content = loop {
+            rate_limit::acquire(rate_limiter).await;
+            match client.media().get_media_content(&request, false).await {
+                Ok(media_content) => break media_content,
+                Err(e) => match media_retry_delay(&e, retry).filter(|_| retry + 1 < MEDIA_DOWNLOAD_ATTEMPTS) {
+                    Some(delay) => {
+                        retry += 1;
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => return Err(e.into()),
+                },
+            }
+        };
+        // Written under a temporary name, and only renamed into place once complete, so that a failed write can't leave behind a truncated file which would later be taken for the finished download
+        let partial_path = media_directory.join(format!("{}.{}", filename, PARTIAL_MEDIA_EXTENSION));
+        if let Err(e) = write(&partial_path, media_content).and_then(|()| rename(&partial_path, &path)) {
+            let _ = remove_file(&partial_path);
+            return Err(TraceError::OutputIo { path, source: e }.into())
+        }
+    }
+
+    Ok(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attachments_with_the_same_name_get_distinct_filenames() {
+        let first_filename = media_filename(<&MxcUri>::from("mxc://example.org/AbCdEf"), "image.png", "");
+        let second_filename = media_filename(<&MxcUri>::from("mxc://example.org/GhIjKl"), "image.png", "");
+        assert_eq!(first_filename, "example.org-AbCdEf_image.png");
+        assert_eq!(second_filename, "example.org-GhIjKl_image.png");
+    }
+
+    #[test]
+    fn long_names_and_extensions_are_capped() {
+        let body = format!("{}.{}", "n".repeat(300), "e".repeat(300));
+        let filename = media_filename(<&MxcUri>::from("mxc://example.org/AbCdEf"), &body, ".thumbnail");
+        assert_eq!(filename, format!("example.org-AbCdEf_{}.thumbnail", "n".repeat(MEDIA_FILENAME_NAME_LENGTH)));
+    }
+}
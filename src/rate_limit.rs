@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use tokio::{
+    sync::Mutex,
+    time::{
+        sleep,
+        Instant,
+    },
+};
+
+///////////////
+//   Types   //
+///////////////
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token-bucket limiter on outbound requests. It's shared by reference across everything making requests within an export, so that the aggregate rate is bounded no matter how many requests are in flight at once. Allows bursts of up to one second's worth of requests.
+pub struct RateLimiter {
+    requests_per_second: f64,
+    capacity: f64,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64) -> Self {
+        let capacity = requests_per_second.max(1.0);
+        Self {
+            requests_per_second,
+            capacity,
+            bucket: Mutex::new(TokenBucket {
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a request may be made, then claims it.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().await;
+                let now = Instant::now();
+                bucket.tokens = (bucket.tokens + now.duration_since(bucket.last_refill).as_secs_f64() * self.requests_per_second).min(self.capacity);
+                bucket.last_refill = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.requests_per_second)
+            };
+            sleep(wait).await;
+        }
+    }
+}
+
+// For call sites where rate-limiting is optional
+pub(crate) async fn acquire(rate_limiter: Option<&RateLimiter>) {
+    if let Some(rate_limiter) = rate_limiter {
+        rate_limiter.acquire().await;
+    }
+}
@@ -0,0 +1,64 @@
+use std::fs::{
+    File,
+    OpenOptions,
+};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+use crate::TraceError;
+
+use chrono::{
+    SecondsFormat,
+    Utc,
+};
+use serde_json::{
+    json,
+    Value,
+};
+
+///////////////
+//   Types   //
+///////////////
+
+/// An append-only log of a run's significant events (logins, each room's start and finish, errors), written as one JSON object per line. Cloning gives another handle onto the same file.
+#[derive(Clone)]
+pub struct RunLog {
+    file: Arc<Mutex<File>>,
+}
+
+impl RunLog {
+    pub fn open(path: &Path) -> Result<Self, TraceError> {
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| TraceError::OutputIo { path: path.to_path_buf(), source: e })?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Appends an entry for `event`, stamped with the current time. `details` should be a JSON object, whose fields are merged into the entry.
+    pub fn record(&self, event: &str, details: Value) {
+        let mut entry = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "event": event,
+        });
+        if let (Some(entry_fields), Value::Object(detail_fields)) = (entry.as_object_mut(), details) {
+            entry_fields.extend(detail_fields);
+        }
+
+        // Logging is best-effort; a full disk shouldn't abort an export which could otherwise succeed
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", entry) {
+            eprintln!("Failed to write to run log: {}", e);
+        }
+    }
+}
+
+// For call sites where logging is optional
+pub(crate) fn record(run_log: Option<&RunLog>, event: &str, details: Value) {
+    if let Some(run_log) = run_log {
+        run_log.record(event, details);
+    }
+}
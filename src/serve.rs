@@ -0,0 +1,215 @@
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+use std::sync::Arc;
+
+use crate::html::escape_html;
+
+use tokio::{
+    fs::{
+        self,
+        File,
+    },
+    io::{
+        self,
+        AsyncBufReadExt,
+        AsyncWriteExt,
+        BufReader,
+    },
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+};
+
+///////////////////
+//   Constants   //
+///////////////////
+
+pub const DEFAULT_SERVE_PORT: u16 = 8000;
+
+// Export filenames are free-form, so links to them are percent-encoded, leaving only these bytes as they are
+const UNRESERVED_URL_BYTES: &[u8] = b"-._~";
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "html" => "text/html; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "css" => "text/css",
+        "gif" => "image/gif",
+        "jpeg" | "jpg" => "image/jpeg",
+        "png" => "image/png",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes().map(|byte| if byte.is_ascii_alphanumeric() || UNRESERVED_URL_BYTES.contains(&byte) {
+        char::from(byte).to_string()
+    } else {
+        format!("%{:02X}", byte)
+    }).collect()
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Only plain path components are accepted, and the result must still be within the root once symlinks are resolved, so that nothing outside the export directory can be reached
+fn resolve_request_path(root: &Path, request_target: &str) -> Option<PathBuf> {
+    let request_path = percent_decode(request_target.split(['?', '#']).next()?)?;
+    let mut path = root.to_path_buf();
+    for component in Path::new(&request_path).components() {
+        match component {
+            Component::RootDir | Component::CurDir => (),
+            Component::Normal(component) => path.push(component),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    let path = path.canonicalize().ok()?;
+    path.starts_with(root).then_some(path)
+}
+
+async fn directory_listing(directory: &Path, request_path: &str) -> io::Result<String> {
+    let mut entries = Vec::new();
+    let mut directory_entries = fs::read_dir(directory).await?;
+    while let Some(entry) = directory_entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_directory = entry.file_type().await?.is_dir();
+        entries.push((!is_directory, name)); // Directories first
+    }
+    entries.sort();
+
+    let title = format!("Index of {}", request_path);
+    let mut listing = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n<ul>\n", escape_html(&title), escape_html(&title));
+    if request_path != "/" {
+        listing.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (is_file, name) in entries {
+        let suffix = if is_file { "" } else { "/" };
+        listing.push_str(&format!("<li><a href=\"{}{}\">{}{}</a></li>\n", percent_encode(&name), suffix, escape_html(&name), suffix));
+    }
+    listing.push_str("</ul>\n</body>\n</html>\n");
+    Ok(listing)
+}
+
+async fn write_response_head(stream: &mut TcpStream, status: &str, content_type: &str, content_length: u64) -> io::Result<()> {
+    stream.write_all(format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, content_type, content_length).as_bytes()).await
+}
+
+async fn write_text_response(stream: &mut TcpStream, status: &str, content_type: &str, body: &str, include_body: bool) -> io::Result<()> {
+    write_response_head(stream, status, content_type, body.len() as u64).await?;
+    if include_body {
+        stream.write_all(body.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+// Handles a single request per connection, which keeps things simple at the cost of some speed, since browsers just open more connections
+async fn handle_connection(mut stream: TcpStream, root: &Path) -> io::Result<()> {
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(&mut stream);
+    reader.read_line(&mut request_line).await?;
+    // The headers aren't needed, but have to be read before responding
+    loop {
+        let mut header_line = String::new();
+        if reader.read_line(&mut header_line).await? == 0 || header_line.trim_end().is_empty() {
+            break
+        }
+    }
+
+    let mut request_line_parts = request_line.split_whitespace();
+    let (Some(method), Some(request_target)) = (request_line_parts.next(), request_line_parts.next()) else {
+        return write_text_response(&mut stream, "400 Bad Request", "text/plain", "Bad request\n", true).await
+    };
+    let include_body = match method {
+        "GET" => true,
+        "HEAD" => false,
+        _ => return write_text_response(&mut stream, "405 Method Not Allowed", "text/plain", "Only GET and HEAD requests are supported\n", true).await,
+    };
+    let Some(path) = resolve_request_path(root, request_target) else {
+        return write_text_response(&mut stream, "404 Not Found", "text/plain", "Not found\n", include_body).await
+    };
+
+    if path.is_dir() {
+        // Relative links in listings and index pages only resolve against directory URLs which end in a slash
+        let request_path = request_target.split(['?', '#']).next().unwrap_or("/");
+        if !request_path.ends_with('/') {
+            return stream.write_all(format!("HTTP/1.1 301 Moved Permanently\r\nLocation: {}/\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", request_path).as_bytes()).await
+        }
+        let index_path = path.join("index.html");
+        if !index_path.is_file() {
+            let listing = directory_listing(&path, &percent_decode(request_path).unwrap_or_default()).await?;
+            return write_text_response(&mut stream, "200 OK", "text/html; charset=utf-8", &listing, include_body).await
+        }
+        return serve_file(&mut stream, &index_path, include_body).await
+    }
+    serve_file(&mut stream, &path, include_body).await
+}
+
+// Streamed rather than read into memory, since downloaded media can be large
+async fn serve_file(stream: &mut TcpStream, path: &Path, include_body: bool) -> io::Result<()> {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(_) => return write_text_response(stream, "404 Not Found", "text/plain", "Not found\n", include_body).await,
+    };
+    write_response_head(stream, "200 OK", content_type(path), file.metadata().await?.len()).await?;
+    if include_body {
+        io::copy(&mut file, stream).await?;
+    }
+    Ok(())
+}
+
+//////////////
+//   Main   //
+//////////////
+
+/// Serves the static files in `directory` (e.g. an export's output directory) over HTTP on localhost, until interrupted with ctrl-c, so that html exports can be browsed with working media and links in browsers which restrict `file://` URLs. Directories without an `index.html` get a generated listing. Nothing outside `directory` is served, even via symlinks.
+pub async fn serve(directory: &Path, port: u16) -> anyhow::Result<()> {
+    let root = Arc::new(directory.canonicalize().map_err(|e| anyhow::anyhow!("Couldn't serve directory {}: {}", directory.display(), e))?);
+    if !root.is_dir() {
+        anyhow::bail!("Couldn't serve {}, since it isn't a directory.", directory.display());
+    }
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.map_err(|e| anyhow::anyhow!("Couldn't listen on port {}: {}", port, e))?;
+    println!("Serving {} at http://127.0.0.1:{}/ (press ctrl-c to stop).", root.display(), port);
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            accepted = listener.accept() => {
+                let (stream, _address) = accepted?;
+                let root = Arc::clone(&root);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, &root).await {
+                        eprintln!("Error serving request: {}", e);
+                    }
+                });
+            }
+        }
+    }
+}
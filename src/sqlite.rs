@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::export::{
+    anonymize_event_json,
+    exported_event_from_json,
+    reaction,
+    ExportedEncryptionStatus,
+    FormattingOptions,
+    RoomExportContext,
+};
+
+use chrono::{
+    SecondsFormat,
+    Utc,
+};
+use matrix_sdk::{
+    deserialized_responses::TimelineEvent,
+    ruma::UserId,
+};
+use rusqlite::{
+    params,
+    Connection,
+};
+
+///////////////////
+//   Constants   //
+///////////////////
+
+pub(crate) const SQLITE_EXPORT_FILENAME: &str = "Trace export.db";
+
+// Every statement is idempotent, so the schema is simply applied whenever the database is opened
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS rooms (
+    room_id TEXT PRIMARY KEY,
+    name TEXT,
+    canonical_alias TEXT,
+    last_exported TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS senders (
+    user_id TEXT PRIMARY KEY,
+    display_name TEXT
+);
+CREATE TABLE IF NOT EXISTS events (
+    event_id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL REFERENCES rooms (room_id),
+    sender TEXT NOT NULL REFERENCES senders (user_id),
+    event_type TEXT NOT NULL,
+    state_key TEXT,
+    origin_server_ts INTEGER NOT NULL,
+    redacted INTEGER NOT NULL,
+    encryption TEXT NOT NULL,
+    msgtype TEXT,
+    body TEXT,
+    formatted_body TEXT,
+    in_reply_to TEXT,
+    replaces TEXT,
+    thread_root TEXT,
+    content TEXT
+);
+CREATE INDEX IF NOT EXISTS events_by_room_and_time ON events (room_id, origin_server_ts);
+CREATE TABLE IF NOT EXISTS reactions (
+    event_id TEXT PRIMARY KEY REFERENCES events (event_id),
+    target_event_id TEXT NOT NULL,
+    sender TEXT NOT NULL REFERENCES senders (user_id),
+    key TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS reactions_by_target ON reactions (target_event_id);";
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn encryption_status_name(status: ExportedEncryptionStatus) -> &'static str {
+    match status {
+        ExportedEncryptionStatus::Unencrypted => "unencrypted",
+        ExportedEncryptionStatus::Decrypted => "decrypted",
+        ExportedEncryptionStatus::Undecrypted => "undecrypted",
+    }
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Every row is upserted by its natural key (room ID, user ID, or event ID), so re-exporting a room into the same database updates it in place rather than duplicating it. Rows for events since dropped from the export (e.g. by a narrower filter) are left alone.
+pub(crate) async fn write_sqlite_export(path: &Path, events: &[TimelineEvent], context: &RoomExportContext<'_>, formatting: &FormattingOptions) -> anyhow::Result<()> {
+    let room_info = context.room_info;
+
+    // Display names are looked up before the database is opened, so that the connection is never held across an await
+    let mut sender_display_names: HashMap<String, Option<String>> = HashMap::new();
+    for sender in events.iter().filter_map(|event| event.event.get_field::<String>("sender").ok().flatten()) {
+        if sender_display_names.contains_key(&sender) {
+            continue
+        }
+        let display_name = match (&formatting.sender_hasher, UserId::parse(&sender)) {
+            (None, Ok(user_id)) => room_info.room.get_member_no_sync(&user_id).await?.and_then(|member| member.display_name().map(String::from)),
+            _ => None,
+        };
+        sender_display_names.insert(sender, display_name);
+    }
+
+    let mut connection = Connection::open(path)?;
+    connection.execute_batch(SCHEMA)?;
+    let transaction = connection.transaction()?;
+
+    transaction.execute("INSERT INTO rooms (room_id, name, canonical_alias, last_exported) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT (room_id) DO UPDATE SET name = excluded.name, canonical_alias = excluded.canonical_alias, last_exported = excluded.last_exported", params![
+        room_info.id.as_str(),
+        room_info.name,
+        room_info.canonical_alias.as_ref().map(|alias| alias.as_str()),
+        Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+    ])?;
+
+    for (sender, display_name) in &sender_display_names {
+        let sender = match &formatting.sender_hasher {
+            Some(sender_hasher) => sender_hasher.hash(sender),
+            None => sender.clone(),
+        };
+        transaction.execute("INSERT INTO senders (user_id, display_name) VALUES (?1, ?2)
+            ON CONFLICT (user_id) DO UPDATE SET display_name = COALESCE(excluded.display_name, senders.display_name)", params![sender, display_name])?;
+    }
+
+    for event in events {
+        let Ok(mut event_json) = event.event.deserialize_as::<serde_json::Value>() else {
+            continue
+        };
+        if let Some(sender_hasher) = &formatting.sender_hasher {
+            anonymize_event_json(&mut event_json, sender_hasher);
+        }
+        let mut exported_event = exported_event_from_json(&event_json);
+        if event.encryption_info.is_some() {
+            exported_event.encryption = ExportedEncryptionStatus::Decrypted;
+        }
+        let message = exported_event.message.as_ref();
+
+        transaction.execute("INSERT INTO events (event_id, room_id, sender, event_type, state_key, origin_server_ts, redacted, encryption, msgtype, body, formatted_body, in_reply_to, replaces, thread_root, content) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
+            ON CONFLICT (event_id) DO UPDATE SET redacted = excluded.redacted, encryption = excluded.encryption, msgtype = excluded.msgtype, body = excluded.body, formatted_body = excluded.formatted_body, in_reply_to = excluded.in_reply_to, replaces = excluded.replaces, thread_root = excluded.thread_root, content = excluded.content", params![
+            exported_event.event_id,
+            room_info.id.as_str(),
+            exported_event.sender,
+            exported_event.event_type,
+            exported_event.state_key,
+            exported_event.origin_server_ts as i64,
+            exported_event.redacted,
+            encryption_status_name(exported_event.encryption),
+            message.map(|message| &message.msgtype),
+            message.map(|message| &message.body),
+            message.and_then(|message| message.formatted_body.as_ref()),
+            message.and_then(|message| message.relations.in_reply_to.as_ref()),
+            message.and_then(|message| message.relations.replaces.as_ref()),
+            message.and_then(|message| message.relations.thread_root.as_ref()),
+            exported_event.content.as_ref().map(|content| content.to_string()),
+        ])?;
+
+        // A reaction which has since been redacted no longer counts, even if it was recorded by an earlier export
+        if exported_event.redacted {
+            transaction.execute("DELETE FROM reactions WHERE event_id = ?1", params![exported_event.event_id])?;
+        } else if let Some((target_event_id, key)) = reaction(&event_json) {
+            transaction.execute("INSERT INTO reactions (event_id, target_event_id, sender, key) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (event_id) DO UPDATE SET key = excluded.key", params![exported_event.event_id, target_event_id, exported_event.sender, key])?;
+        }
+    }
+
+    transaction.commit()?;
+    Ok(())
+}